@@ -0,0 +1,268 @@
+//! Target reference voltage (Vtref) measurement on the ADC.
+//!
+//! The status task samples Vtref every period through [`AdcReader::poll`], which tracks whether
+//! the target is powered. A threshold crossing latches a notification the host polls with the
+//! `ID_VTREF` vendor command, so it can react to the target being powered on or off during a
+//! session.
+//!
+//! Probe variants wire Vtref to different ADC inputs through different dividers, the input is
+//! picked with [`VtrefPin`] and the conversion to millivolts is described by a [`VtrefScale`].
+//!
+//! Carrier boards with a shunt and a sense amplifier in the target supply can have the target
+//! current sampled on a second input, described by a [`CurrentSense`]. The HAL selects the input
+//! before every conversion, so both channels share the ADC.
+
+use crate::{setup, target_power};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embedded_hal::adc::OneShot;
+use rp_pico::hal::{
+    adc::Adc,
+    gpio::{
+        pin::bank0::{Gpio26, Gpio27, Gpio28, Gpio29},
+        Disabled, FloatingInput, Pin, PullDown,
+    },
+};
+
+/// Full scale reading of the 12 bit ADC.
+const ADC_FULL_SCALE: u32 = 4095;
+
+/// Hysteresis around the threshold, so a slowly ramping supply does not toggle the state.
+const HYSTERESIS_MV: u32 = 100;
+
+static VTREF_MV: AtomicU32 = AtomicU32::new(0);
+static POWERED: AtomicBool = AtomicBool::new(false);
+static CHANGED: AtomicBool = AtomicBool::new(false);
+static CURRENT_MA: AtomicU32 = AtomicU32::new(0);
+
+/// The last measured Vtref, in millivolts.
+pub fn vtref_mv() -> u32 {
+    VTREF_MV.load(Ordering::Relaxed)
+}
+
+/// The last measured target current, in milliamps, 0 without current sensing.
+pub fn current_ma() -> u32 {
+    CURRENT_MA.load(Ordering::Relaxed)
+}
+
+/// Whether Vtref is above the threshold.
+pub fn target_powered() -> bool {
+    POWERED.load(Ordering::Relaxed)
+}
+
+/// Whether SWD transfers are refused, as the target is unpowered and
+/// [`REJECT_WITHOUT_VTREF`](crate::setup::REJECT_WITHOUT_VTREF) is set or the probe switched
+/// the target power on and it did not come up yet.
+pub fn transfers_blocked() -> bool {
+    setup::VTREF_SENSE
+        && !target_powered()
+        && (setup::REJECT_WITHOUT_VTREF || target_power::is_on())
+}
+
+/// Returns and clears the notification latched by a threshold crossing.
+pub fn take_changed() -> bool {
+    // NOTE(no-CAS) the status task and the USB task run at the same priority
+    let changed = CHANGED.load(Ordering::Relaxed);
+    CHANGED.store(false, Ordering::Relaxed);
+    changed
+}
+
+/// ADC input of the Vtref measurement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum VtrefChannel {
+    /// GPIO26
+    Adc0,
+    /// GPIO27
+    Adc1,
+    /// GPIO28
+    Adc2,
+    /// GPIO29, VSYS / 3 on the Pico itself
+    Adc3,
+}
+
+/// The pin of the selected [`VtrefChannel`].
+pub enum VtrefPin {
+    Adc0(Pin<Gpio26, FloatingInput>),
+    Adc1(Pin<Gpio27, FloatingInput>),
+    Adc2(Pin<Gpio28, FloatingInput>),
+    Adc3(Pin<Gpio29, FloatingInput>),
+}
+
+/// The ADC capable pins, handed out by channel.
+pub struct AdcPins {
+    pub adc0: Option<Pin<Gpio26, Disabled<PullDown>>>,
+    pub adc1: Option<Pin<Gpio27, Disabled<PullDown>>>,
+    pub adc2: Option<Pin<Gpio28, Disabled<PullDown>>>,
+    pub adc3: Option<Pin<Gpio29, Disabled<PullDown>>>,
+}
+
+impl AdcPins {
+    /// Takes the pin of `channel`, `None` if it was taken before.
+    pub fn take(&mut self, channel: VtrefChannel) -> Option<VtrefPin> {
+        Some(match channel {
+            VtrefChannel::Adc0 => VtrefPin::Adc0(self.adc0.take()?.into_floating_input()),
+            VtrefChannel::Adc1 => VtrefPin::Adc1(self.adc1.take()?.into_floating_input()),
+            VtrefChannel::Adc2 => VtrefPin::Adc2(self.adc2.take()?.into_floating_input()),
+            VtrefChannel::Adc3 => VtrefPin::Adc3(self.adc3.take()?.into_floating_input()),
+        })
+    }
+}
+
+/// Conversion of an ADC reading to Vtref.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct VtrefScale {
+    /// ADC reference voltage, in millivolts.
+    pub reference_mv: u32,
+    /// Ratio of the resistor divider in front of the input, as numerator and denominator.
+    pub divider: (u32, u32),
+    /// Added to the scaled reading, in millivolts, to calibrate out the offset of the divider
+    /// and the ADC.
+    pub offset_mv: i32,
+}
+
+impl VtrefScale {
+    /// 3.3 V reference and a 1:2 divider, without calibration.
+    pub const DEFAULT: VtrefScale = VtrefScale {
+        reference_mv: 3300,
+        divider: (2, 1),
+        offset_mv: 0,
+    };
+
+    /// Vtref in millivolts for a `raw` reading.
+    pub const fn millivolts(&self, raw: u16) -> u32 {
+        let input_mv = raw as u32 * self.reference_mv / ADC_FULL_SCALE;
+        let mv = (input_mv * self.divider.0 / self.divider.1) as i32 + self.offset_mv;
+        if mv < 0 {
+            0
+        } else {
+            mv as u32
+        }
+    }
+}
+
+/// Target current sensing with a shunt and a sense amplifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct CurrentSense {
+    /// ADC input of the amplifier output.
+    pub channel: VtrefChannel,
+    /// ADC reference voltage, in millivolts.
+    pub reference_mv: u32,
+    /// Shunt resistance, in milliohms.
+    pub shunt_milliohm: u32,
+    /// Voltage gain of the amplifier.
+    pub gain: u32,
+}
+
+impl CurrentSense {
+    /// Target current in milliamps for a `raw` reading.
+    pub const fn milliamps(&self, raw: u16) -> u32 {
+        let input_mv = raw as u32 * self.reference_mv / ADC_FULL_SCALE;
+        input_mv * 1000 / (self.shunt_milliohm * self.gain)
+    }
+}
+
+pub struct AdcReader {
+    adc: Adc,
+    pin: VtrefPin,
+    scale: VtrefScale,
+    threshold_mv: u32,
+    current: Option<(VtrefPin, CurrentSense)>,
+}
+
+impl AdcReader {
+    /// Measures Vtref on `pin`, converting the readings with `scale`, and the target current if
+    /// `current` is given. The first sample is taken right away, without latching a notification.
+    pub fn new(
+        adc: Adc,
+        pin: VtrefPin,
+        scale: VtrefScale,
+        threshold_mv: u32,
+        current: Option<(VtrefPin, CurrentSense)>,
+    ) -> Self {
+        let mut reader = AdcReader {
+            adc,
+            pin,
+            scale,
+            threshold_mv,
+            current,
+        };
+
+        if let Some(mv) = reader.read_mv() {
+            VTREF_MV.store(mv, Ordering::Relaxed);
+            POWERED.store(mv >= threshold_mv, Ordering::Relaxed);
+        }
+
+        reader
+    }
+
+    /// Vtref in millivolts, `None` if the conversion failed.
+    pub fn read_mv(&mut self) -> Option<u32> {
+        let raw = read_raw(&mut self.adc, &mut self.pin)?;
+        Some(self.scale.millivolts(raw))
+    }
+
+    /// Target current in milliamps, `None` without current sensing or if the conversion failed.
+    pub fn read_ma(&mut self) -> Option<u32> {
+        let (pin, sense) = self.current.as_mut()?;
+        let raw = read_raw(&mut self.adc, pin)?;
+        Some(sense.milliamps(raw))
+    }
+
+    /// Samples Vtref and the target current, and latches a notification when Vtref crosses the
+    /// threshold.
+    pub fn poll(&mut self) {
+        if let Some(ma) = self.read_ma() {
+            CURRENT_MA.store(ma, Ordering::Relaxed);
+        }
+
+        let mv = match self.read_mv() {
+            Some(mv) => mv,
+            None => return,
+        };
+        VTREF_MV.store(mv, Ordering::Relaxed);
+        defmt::trace!("Vtref = {} mV, current = {} mA", mv, current_ma());
+
+        let powered = target_powered();
+        let now_powered = powered_at(powered, mv, self.threshold_mv);
+
+        if now_powered != powered {
+            defmt::info!("Target power {}, Vtref = {} mV", now_powered, mv);
+            POWERED.store(now_powered, Ordering::Relaxed);
+            CHANGED.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether the target counts as powered at `mv`, the [`HYSTERESIS_MV`] around `threshold_mv`
+/// depends on whether it was `powered` before.
+const fn powered_at(powered: bool, mv: u32, threshold_mv: u32) -> bool {
+    if powered {
+        mv + HYSTERESIS_MV >= threshold_mv
+    } else {
+        mv >= threshold_mv + HYSTERESIS_MV
+    }
+}
+
+/// One conversion on `pin`, the HAL selects the input first.
+fn read_raw(adc: &mut Adc, pin: &mut VtrefPin) -> Option<u16> {
+    match pin {
+        VtrefPin::Adc0(pin) => adc.read(pin).ok(),
+        VtrefPin::Adc1(pin) => adc.read(pin).ok(),
+        VtrefPin::Adc2(pin) => adc.read(pin).ok(),
+        VtrefPin::Adc3(pin) => adc.read(pin).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_crossings_have_hysteresis() {
+        // Powering up needs the threshold plus the hysteresis
+        assert!(!powered_at(false, 1599, 1500));
+        assert!(powered_at(false, 1600, 1500));
+        // Powering down needs the threshold minus the hysteresis
+        assert!(powered_at(true, 1400, 1500));
+        assert!(!powered_at(true, 1399, 1500));
+    }
+}
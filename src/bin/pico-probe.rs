@@ -7,8 +7,7 @@ use pico_probe as _;
 mod app {
     use core::mem::MaybeUninit;
     use defmt::*;
-    use embedded_hal::digital::v2::ToggleableOutputPin;
-    use pico_probe::setup::*;
+    use pico_probe::{read_stream::ReadStream, setup::*, vendor};
     use rp2040_monotonic::*;
     use rp_pico::hal::usb::UsbBus;
     use usb_device::class_prelude::*;
@@ -17,13 +16,17 @@ mod app {
     type Monotonic = Rp2040Monotonic;
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        dap_handler: DapHandler,
+        liveness: pico_probe::liveness::Liveness,
+    }
 
     #[local]
     struct Local {
         probe_usb: pico_probe::usb::ProbeUsb,
-        dap_handler: DapHandler,
-        led: LedPin,
+        status_timer: pico_probe::status_timer::StatusTimer,
+        vtref: Option<pico_probe::adc::AdcReader>,
+        led: pico_probe::status_led::StatusLed,
     }
 
     #[init(local = [
@@ -31,47 +34,111 @@ mod app {
         delay: MaybeUninit<pico_probe::systick_delay::Delay> = MaybeUninit::uninit(),
     ])]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
-        let (mono, led, probe_usb, dap_handler) =
+        let (mono, status_timer, vtref, liveness, led, probe_usb, dap_handler) =
             setup(cx.device, cx.core, cx.local.usb_bus, cx.local.delay);
 
-        led_blinker::spawn().ok();
+        #[cfg(feature = "rtt")]
+        rtt_poll::spawn().ok();
 
         (
-            Shared {},
+            Shared {
+                dap_handler,
+                liveness,
+            },
             Local {
                 probe_usb,
-                dap_handler,
+                status_timer,
+                vtref,
                 led,
             },
             init::Monotonics(mono),
         )
     }
 
-    #[task(local = [led])]
+    /// The only regular watchdog feed point, it only runs when no task is stuck.
+    #[idle(shared = [liveness])]
+    fn idle(mut cx: idle::Context) -> ! {
+        loop {
+            cx.shared.liveness.lock(|liveness| liveness.feed());
+            cortex_m::asm::wfi();
+        }
+    }
+
+    #[task(binds = TIMER_IRQ_1, local = [status_timer, vtref, led, tick: u32 = 0])]
     fn led_blinker(cx: led_blinker::Context) {
-        cx.local.led.toggle().ok();
-        led_blinker::spawn_after(500.millis()).ok();
+        cx.local.status_timer.on_interrupt();
+        *cx.local.tick = cx.local.tick.wrapping_add(1);
+        let state = pico_probe::dap::status_led_state(*cx.local.tick);
+        cx.local.led.set(state);
+
+        if let Some(vtref) = cx.local.vtref {
+            vtref.poll();
+        }
+        pico_probe::bus_park::poll();
+    }
+
+    #[task(binds = PWM_IRQ_WRAP)]
+    fn led_breathing(_: led_breathing::Context) {
+        pico_probe::status_led::on_wrap_interrupt();
+    }
+
+    #[cfg(feature = "swo")]
+    #[task(binds = UART1_IRQ)]
+    fn swo_uart(_: swo_uart::Context) {
+        pico_probe::swo::on_uart_interrupt();
+    }
+
+    #[cfg(feature = "swo")]
+    #[task(binds = PIO1_IRQ_0)]
+    fn swo_manchester(_: swo_manchester::Context) {
+        pico_probe::swo::on_pio_interrupt();
+    }
+
+    /// Reschedules itself, the USB task forwards what it read to the host.
+    #[cfg(feature = "rtt")]
+    #[task(shared = [dap_handler], local = [rtt: pico_probe::rtt::Rtt = pico_probe::rtt::Rtt::new()])]
+    fn rtt_poll(mut cx: rtt_poll::Context) {
+        let rtt = cx.local.rtt;
+        let next_ms = cx.shared.dap_handler.lock(|dap| rtt.poll(dap));
+
+        if !pico_probe::rtt::FROM_TARGET.is_empty() {
+            rtic::pend(rp_pico::hal::pac::Interrupt::USBCTRL_IRQ);
+        }
+        rtt_poll::spawn_after(fugit::MicrosDurationU64::millis(next_ms as u64)).ok();
+    }
+
+    #[cfg(feature = "cdc")]
+    #[task(binds = UART0_IRQ)]
+    fn uart_bridge(_: uart_bridge::Context) {
+        if pico_probe::uart_bridge::on_interrupt() {
+            // Let the USB task forward the data to the host
+            rtic::pend(rp_pico::hal::pac::Interrupt::USBCTRL_IRQ);
+        }
     }
 
-    #[task(binds = USBCTRL_IRQ, local = [probe_usb, dap_handler, resp_buf: [u8; 64] = [0; 64]])]
-    fn on_usb(ctx: on_usb::Context) {
+    #[task(binds = USBCTRL_IRQ, shared = [dap_handler], local = [
+        probe_usb,
+        resp_buf: [u8; 64] = [0; 64],
+        read_stream: ReadStream = ReadStream::new(),
+    ])]
+    fn on_usb(mut ctx: on_usb::Context) {
         let probe_usb = ctx.local.probe_usb;
-        let dap = ctx.local.dap_handler;
         let resp_buf = ctx.local.resp_buf;
+        let read_stream = ctx.local.read_stream;
 
         if let Some(request) = probe_usb.interrupt() {
             use dap_rs::{dap::DapVersion, usb::Request};
 
-            match request {
+            ctx.shared.dap_handler.lock(|dap| match request {
                 Request::DAP1Command((report, n)) => {
-                    let len = dap.process_command(&report[..n], resp_buf, DapVersion::V1);
+                    let len = vendor::process_command(dap, &report[..n], resp_buf, DapVersion::V1);
 
                     if len > 0 {
                         probe_usb.dap1_reply(&resp_buf[..len]);
                     }
                 }
                 Request::DAP2Command((report, n)) => {
-                    let len = dap.process_command(&report[..n], resp_buf, DapVersion::V2);
+                    let len = vendor::process_command(dap, &report[..n], resp_buf, DapVersion::V2);
 
                     if len > 0 {
                         probe_usb.dap2_reply(&resp_buf[..len]);
@@ -79,9 +146,56 @@ mod app {
                 }
                 Request::Suspend => {
                     info!("Got USB suspend command");
+                    #[cfg(feature = "diagnostics")]
+                    pico_probe::scope_loop::stop();
+                    pico_probe::read_stream::stop();
                     dap.suspend();
+                    pico_probe::dap::keep_released_until_connect();
                 }
+            });
+
+            #[cfg(feature = "diagnostics")]
+            if pico_probe::scope_loop::is_active() {
+                scope_loop::spawn().ok();
             }
+
+            if pico_probe::bootloader::requested() {
+                enter_bootloader::spawn_after(BOOTLOADER_DELAY).ok();
+            }
+        }
+
+        // Also runs on the bulk IN completions, which pace the stream
+        if read_stream.is_busy() {
+            ctx.shared
+                .dap_handler
+                .lock(|dap| read_stream.poll(dap, probe_usb));
+        }
+    }
+
+    /// Time for the host to read the response of the bootloader command before the reboot.
+    const BOOTLOADER_DELAY: fugit::MicrosDurationU64 = fugit::MicrosDurationU64::millis(10);
+
+    #[task]
+    fn enter_bootloader(_: enter_bootloader::Context) {
+        pico_probe::bootloader::enter();
+    }
+
+    /// Runs at the same priority as `on_usb`, so USB requests (such as the stop command) are
+    /// served in between iterations.
+    #[cfg(feature = "diagnostics")]
+    #[task(shared = [dap_handler, liveness], local = [resp_buf: [u8; 64] = [0; 64]])]
+    fn scope_loop(mut cx: scope_loop::Context) {
+        let resp_buf = cx.local.resp_buf;
+
+        // The loop keeps idle from running, feed the watchdog in between iterations instead
+        cx.shared.liveness.lock(|liveness| liveness.feed());
+
+        if cx
+            .shared
+            .dap_handler
+            .lock(|dap| pico_probe::scope_loop::step(dap, resp_buf))
+        {
+            scope_loop::spawn().ok();
         }
     }
 }
@@ -0,0 +1,76 @@
+//! CRC-32 of the last block read with `DAP_TransferBlock`, for the host to cross-check the data
+//! it received against what the probe read.
+//!
+//! Off by default. Enabled, the read data of every `DAP_TransferBlock` is run through the CRC
+//! after the whole block completed, chunked or not, so the transfers themselves are not slowed
+//! down. Only the CRC and the word count are kept, not the data. A mismatch on the host points
+//! at corruption between the probe and the host, while a matching CRC of wrong data points at the
+//! wiring to the target.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Reflected polynomial of the IEEE 802.3 CRC-32, as in zlib.
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CRC: AtomicU32 = AtomicU32::new(0);
+static WORDS: AtomicU32 = AtomicU32::new(0);
+
+/// The CRC-32 of `data`, as computed by `zlib.crc32`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Enable or disable the CRC, either forgets the last block.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    CRC.store(0, Ordering::Relaxed);
+    WORDS.store(0, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record the data of a completed block read, as sent to the host.
+pub fn record(data: &[u8]) {
+    if !enabled() {
+        return;
+    }
+    CRC.store(crc32(data), Ordering::Relaxed);
+    WORDS.store((data.len() / 4) as u32, Ordering::Relaxed);
+}
+
+/// The CRC-32 of the last block read and its length in words, `(0, 0)` before the first one.
+pub fn last() -> (u32, u32) {
+    (CRC.load(Ordering::Relaxed), WORDS.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values from Python's `zlib.crc32`
+    #[test]
+    fn matches_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(crc32(&bytes), 0x2905_8c73);
+    }
+}
@@ -0,0 +1,50 @@
+//! Entering the UF2 bootloader of the boot ROM.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::digital::v2::InputPin;
+use rp_pico::hal::rom_data;
+
+/// Number of consecutive samples the jumper must read as set.
+const JUMPER_SAMPLES: u32 = 8;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks for a reboot into the bootloader once the current USB reply went out.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the host asked for the bootloader.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Reboot into the UF2 mass-storage bootloader, this does not return.
+pub fn enter() -> ! {
+    defmt::info!("Rebooting into the UF2 bootloader");
+
+    // Use the LED (GPIO25) as the bootloader activity indicator, keep both interfaces
+    rom_data::reset_to_usb_boot(1 << 25, 0);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Samples an active low jumper, the pin is expected to be configured as pulled up input.
+///
+/// The jumper only counts as set if it reads low for all samples, spaced `cycles_per_ms` apart,
+/// so a floating or bouncing line does not send the probe into the bootloader.
+pub fn jumper_set<P: InputPin>(pin: &P, cycles_per_ms: u32) -> bool {
+    // Let the pull-up settle
+    cortex_m::asm::delay(cycles_per_ms);
+
+    for _ in 0..JUMPER_SAMPLES {
+        if !matches!(pin.is_low(), Ok(true)) {
+            return false;
+        }
+        cortex_m::asm::delay(cycles_per_ms);
+    }
+
+    true
+}
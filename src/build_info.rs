@@ -0,0 +1,8 @@
+//! Identification of the running firmware build, for support and bug reports.
+
+/// `git describe` of the tree the firmware was built from, with `-modified` for uncommitted
+/// changes. Also the firmware version of `DAP_Info`.
+pub const GIT_VERSION: &str = git_version::git_version!();
+
+/// Build time in UTC, `YYYY-MM-DDTHH:MM:SSZ`, from `SOURCE_DATE_EPOCH` if it is set.
+pub const BUILD_TIME: &str = env!("BUILD_TIME");
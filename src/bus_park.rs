@@ -0,0 +1,136 @@
+//! Parking of the SWD bus during long idle periods.
+//!
+//! When no transfer happened for the configured period the status task parks the bus: SWDIO and
+//! SWCLK stop being driven and are held by the pad pulls instead, SWDIO pulled up and SWCLK
+//! pulled down. The next transfer takes the bus back before it clocks anything.
+//!
+//! The pins are switched with raw SIO and pad register accesses, as the pin drivers are owned by
+//! the DAP handler. Parking only applies while SWD is connected.
+
+use crate::timer_delay;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use defmt::*;
+use rp_pico::hal::pac;
+
+/// GPIO numbers of SWDIO and SWCLK, `NO_PIN` while SWD is not connected.
+static SWDIO: AtomicU8 = AtomicU8::new(NO_PIN);
+static SWCLK: AtomicU8 = AtomicU8::new(NO_PIN);
+static PARKED: AtomicBool = AtomicBool::new(false);
+static LAST_ACTIVITY_US: AtomicU32 = AtomicU32::new(0);
+static PARK_AFTER_US: AtomicU32 = AtomicU32::new(0);
+
+const NO_PIN: u8 = 0xff;
+
+/// Idle period after which the bus is parked, `None` disables parking.
+pub fn set_park_after(us: Option<u32>) {
+    PARK_AFTER_US.store(us.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// SWD took the bus on these pins, call on `DAP_Connect`.
+pub fn connect(swdio: u8, swclk: u8) {
+    SWDIO.store(swdio, Ordering::Relaxed);
+    SWCLK.store(swclk, Ordering::Relaxed);
+    PARKED.store(false, Ordering::Relaxed);
+    LAST_ACTIVITY_US.store(timer_delay::now_us(), Ordering::Relaxed);
+}
+
+/// SWD released the bus, the pin drivers reconfigure the pins themselves.
+pub fn disconnect() {
+    SWDIO.store(NO_PIN, Ordering::Relaxed);
+    SWCLK.store(NO_PIN, Ordering::Relaxed);
+    PARKED.store(false, Ordering::Relaxed);
+}
+
+/// Whether SWD holds the bus.
+pub fn connected() -> bool {
+    SWDIO.load(Ordering::Relaxed) != NO_PIN
+}
+
+/// GPIO numbers of SWDIO and SWCLK while SWD holds the bus.
+pub fn pins() -> Option<(u8, u8)> {
+    if connected() {
+        Some((SWDIO.load(Ordering::Relaxed), SWCLK.load(Ordering::Relaxed)))
+    } else {
+        None
+    }
+}
+
+/// Records bus activity and takes the bus back if it is parked, call before every transfer.
+pub fn activity() {
+    LAST_ACTIVITY_US.store(timer_delay::now_us(), Ordering::Relaxed);
+
+    if PARKED.load(Ordering::Relaxed) {
+        PARKED.store(false, Ordering::Relaxed);
+        set_parked(false);
+        trace!("Bus unparked");
+    }
+}
+
+/// Parks the bus if it has been idle for long enough, call periodically from the status task.
+pub fn poll() {
+    // NOTE(no-CAS) the status task and the USB task run at the same priority
+    if PARKED.load(Ordering::Relaxed) || SWDIO.load(Ordering::Relaxed) == NO_PIN {
+        return;
+    }
+
+    if let Some(idle_us) = park_due(
+        PARK_AFTER_US.load(Ordering::Relaxed),
+        LAST_ACTIVITY_US.load(Ordering::Relaxed),
+        timer_delay::now_us(),
+    ) {
+        PARKED.store(true, Ordering::Relaxed);
+        set_parked(true);
+        debug!("Bus parked after {} us idle", idle_us);
+    }
+}
+
+/// The idle time if the bus has been idle for `park_after_us`, across the wrap of the
+/// microsecond counter. A zero period disables parking.
+fn park_due(park_after_us: u32, last_activity_us: u32, now_us: u32) -> Option<u32> {
+    let idle_us = now_us.wrapping_sub(last_activity_us);
+    (park_after_us != 0 && idle_us >= park_after_us).then_some(idle_us)
+}
+
+fn set_parked(parked: bool) {
+    let swdio = SWDIO.load(Ordering::Relaxed) as usize;
+    let swclk = SWCLK.load(Ordering::Relaxed) as usize;
+    let mask = (1 << swdio) | (1 << swclk);
+
+    // NOTE(unsafe) the SIO set/clear registers are atomic, the pads of the SWD pins are only
+    // modified from tasks at the USB priority
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+
+    if parked {
+        pads.gpio[swdio].modify(|_, w| w.pue().set_bit().pde().clear_bit());
+        pads.gpio[swclk].modify(|_, w| w.pue().clear_bit().pde().set_bit());
+        sio.gpio_oe_clr.write(|w| unsafe { w.bits(mask) });
+    } else {
+        sio.gpio_oe_set.write(|w| unsafe { w.bits(mask) });
+        pads.gpio[swdio].modify(|_, w| w.pue().clear_bit().pde().clear_bit());
+        pads.gpio[swclk].modify(|_, w| w.pue().clear_bit().pde().clear_bit());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parks_after_the_idle_period() {
+        assert_eq!(park_due(50_000, 1_000, 50_999), None);
+        assert_eq!(park_due(50_000, 1_000, 51_000), Some(50_000));
+    }
+
+    #[test]
+    fn parks_across_the_counter_wrap() {
+        assert_eq!(park_due(20, u32::MAX - 9, 9), None);
+        assert_eq!(park_due(20, u32::MAX - 9, 10), Some(20));
+    }
+
+    #[test]
+    fn zero_period_never_parks() {
+        assert_eq!(park_due(0, 0, 0), None);
+        assert_eq!(park_due(0, 0, u32::MAX), None);
+    }
+}
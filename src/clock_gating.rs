@@ -0,0 +1,102 @@
+//! Gating of the clocks of unused peripherals.
+//!
+//! Every peripheral clock branch can be turned off in the CLOCKS `WAKE_EN`/`SLEEP_EN` registers.
+//! The firmware never uses the SPIs, I2Cs, DMA or RTC, UART0 only for the target serial
+//! bridge, UART1 and PIO1 only for SWO capture, PIO0 only with
+//! [`PIO_SWD`](crate::setup::PIO_SWD) and the ADC only with
+//! [`VTREF_SENSE`](crate::setup::VTREF_SENSE), so their clocks are gated after init. This
+//! lowers the dynamic power of the die a little, which helps when the probe also sources target
+//! power or runs for long sessions; the exact saving has not been measured on this board.
+//!
+//! A peripheral with a gated clock can not even be accessed over the bus, when a feature starts
+//! using one it has to be removed from the masks below.
+
+use defmt::*;
+use rp_pico::hal::pac;
+
+// `WAKE_EN0`/`SLEEP_EN0` bits
+const CLK_ADC_ADC: u32 = 1 << 1;
+const CLK_SYS_ADC: u32 = 1 << 2;
+const CLK_SYS_DMA: u32 = 1 << 5;
+const CLK_SYS_I2C0: u32 = 1 << 6;
+const CLK_SYS_I2C1: u32 = 1 << 7;
+const CLK_SYS_PIO0: u32 = 1 << 12;
+const CLK_SYS_PIO1: u32 = 1 << 13;
+const CLK_RTC_RTC: u32 = 1 << 21;
+const CLK_SYS_RTC: u32 = 1 << 22;
+const CLK_PERI_SPI0: u32 = 1 << 24;
+const CLK_SYS_SPI0: u32 = 1 << 25;
+const CLK_PERI_SPI1: u32 = 1 << 26;
+const CLK_SYS_SPI1: u32 = 1 << 27;
+
+// `WAKE_EN1`/`SLEEP_EN1` bits
+const CLK_PERI_UART0: u32 = 1 << 6;
+const CLK_SYS_UART0: u32 = 1 << 7;
+const CLK_PERI_UART1: u32 = 1 << 8;
+const CLK_SYS_UART1: u32 = 1 << 9;
+
+/// Clocks in `EN0` gated whatever the configuration.
+const UNUSED_EN0: u32 = CLK_SYS_DMA
+    | CLK_SYS_I2C0
+    | CLK_SYS_I2C1
+    | CLK_RTC_RTC
+    | CLK_SYS_RTC
+    | CLK_PERI_SPI0
+    | CLK_SYS_SPI0
+    | CLK_PERI_SPI1
+    | CLK_SYS_SPI1;
+
+/// UART0 bridges the target serial port with the `cdc` feature.
+const CDC_EN1: u32 = if cfg!(feature = "cdc") {
+    0
+} else {
+    CLK_PERI_UART0 | CLK_SYS_UART0
+};
+
+/// UART1 and PIO1 receive SWO with the `swo` feature.
+const SWO_EN0: u32 = if cfg!(feature = "swo") {
+    0
+} else {
+    CLK_SYS_PIO1
+};
+const SWO_EN1: u32 = if cfg!(feature = "swo") {
+    0
+} else {
+    CLK_PERI_UART1 | CLK_SYS_UART1
+};
+
+/// The `EN0` and `EN1` masks of the clocks to gate, `adc_used` keeps the ADC clocks running and
+/// `pio0_used` the PIO0 clock.
+pub const fn unused_clocks(adc_used: bool, pio0_used: bool) -> (u32, u32) {
+    let adc = if adc_used {
+        0
+    } else {
+        CLK_ADC_ADC | CLK_SYS_ADC
+    };
+    let pio0 = if pio0_used { 0 } else { CLK_SYS_PIO0 };
+
+    (UNUSED_EN0 | SWO_EN0 | adc | pio0, CDC_EN1 | SWO_EN1)
+}
+
+/// Gates the clocks of the peripherals the configuration does not use, both while running and
+/// while the core sleeps in `wfi`.
+pub fn gate_unused(adc_used: bool, pio0_used: bool) {
+    let (en0, en1) = unused_clocks(adc_used, pio0_used);
+
+    // NOTE(unsafe) only called once from setup, the clocks themselves are configured by then
+    let clocks = unsafe { &*pac::CLOCKS::ptr() };
+    clocks
+        .wake_en0
+        .modify(|r, w| unsafe { w.bits(r.bits() & !en0) });
+    clocks
+        .wake_en1
+        .modify(|r, w| unsafe { w.bits(r.bits() & !en1) });
+    clocks
+        .sleep_en0
+        .modify(|r, w| unsafe { w.bits(r.bits() & !en0) });
+    clocks
+        .sleep_en1
+        .modify(|r, w| unsafe { w.bits(r.bits() & !en1) });
+
+    info!("Gated unused clocks, EN0: 0x{:x}, EN1: 0x{:x}", en0, en1);
+}
@@ -1,21 +1,889 @@
-use crate::systick_delay::Delay;
+#[cfg(feature = "diagnostics")]
+use crate::stats;
+use crate::{
+    adc, bus_park, pio_swd::PioSwd, status_led::LedState, systick_delay::Delay, timer_delay,
+};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
 use dap_rs::{swj::Swj, *};
 use defmt::*;
 use embedded_hal::{
     blocking::delay::DelayUs,
     digital::v2::{InputPin, OutputPin, PinState},
 };
-use rp_pico::hal::gpio::DynPin;
+use rp_pico::hal::{gpio::DynPin, pac};
+
+/// SWD timing parameters carried by a [`ConnectProfile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct SwdTiming {
+    /// Turnaround period in clock cycles (1-4).
+    pub turnaround: u8,
+    /// Idle cycles, with SWDIO driven low, after a completed transfer.
+    pub idle_cycles: u8,
+    /// Idle cycles after a non-OK ACK, before the next request.
+    pub trailing_idle: u8,
+    /// How many times a DP read is retried on a parity error.
+    pub parity_retries: u8,
+}
+
+/// Connect profiles, each carrying the SWD timing applied on `DAP_Connect`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectProfile {
+    /// A single SWD target as described by ADIv5.
+    Standard,
+    /// The RP2040 style multi-drop bus, where deselected DPs do not drive ACK.
+    Rp2040Multidrop,
+}
+
+impl ConnectProfile {
+    pub const fn timing(self) -> SwdTiming {
+        match self {
+            ConnectProfile::Standard => SwdTiming {
+                turnaround: 1,
+                idle_cycles: 8,
+                trailing_idle: 8,
+                parity_retries: 0,
+            },
+            ConnectProfile::Rp2040Multidrop => SwdTiming {
+                turnaround: 1,
+                idle_cycles: 8,
+                trailing_idle: 16,
+                parity_retries: 2,
+            },
+        }
+    }
+
+    /// The timing applied on `DAP_Connect`, with the turnaround the host configured or else the
+    /// one the pin set needs instead of the profile's.
+    fn timing_with(self, host_turnaround: Option<u8>, pin_set_turnaround: Option<u8>) -> SwdTiming {
+        let mut timing = self.timing();
+        if let Some(turnaround) = host_turnaround.or(pin_set_turnaround) {
+            timing.turnaround = turnaround;
+        }
+        timing
+    }
+
+    /// The connect sequence used unless another one is configured.
+    pub const fn sequence(self) -> ConnectSequence {
+        match self {
+            ConnectProfile::Standard => ConnectSequence::SwitchBeforeReset,
+            ConnectProfile::Rp2040Multidrop => ConnectSequence::DormantWakeup,
+        }
+    }
+}
+
+/// A step of the sequence sent on `DAP_Connect`, before the DPIDR read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectStep {
+    /// Line reset, at least 50 clocks with SWDIO high.
+    LineReset,
+    /// The 16 bit JTAG-to-SWD select code.
+    JtagToSwd,
+    /// Selection alert and SWD activation code, which leave the dormant state (ADIv6).
+    DormantToSwd,
+    /// The 16 bit SWD-to-dormant select code, only recognized after a line reset.
+    SwdToDormant,
+    /// 8 clocks with TMS high and the 31 bit JTAG-to-dormant select code.
+    JtagToDormant,
+    /// Idle cycles with SWDIO low.
+    Idle,
+}
+
+impl ConnectStep {
+    /// The SWDIO levels of the step, LSB first, and their number.
+    pub const fn bits(self) -> (&'static [u8], usize) {
+        match self {
+            ConnectStep::LineReset => (&[0xff; 7], 56),
+            ConnectStep::JtagToSwd => (&JTAG_TO_SWD, 16),
+            ConnectStep::DormantToSwd => (&DORMANT_TO_SWD, 148),
+            ConnectStep::SwdToDormant => (&SWD_TO_DORMANT, 16),
+            ConnectStep::JtagToDormant => (&JTAG_TO_DORMANT, 39),
+            ConnectStep::Idle => (&[0x00], 8),
+        }
+    }
+}
+
+/// Orderings of the connect steps, some parts need another order than ADIv5 as an erratum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectSequence {
+    /// Line reset, JTAG-to-SWD switch, line reset and idle, as in ADIv5.
+    SwitchBeforeReset,
+    /// Line reset and idle before the JTAG-to-SWD switch, without a line reset after it.
+    ResetBeforeSwitch,
+    /// Wake up from the dormant state before the line reset, as in ADIv6 and multi-drop SWD.
+    DormantWakeup,
+    /// Put the target into the dormant state from SWD and from JTAG, then wake it up into SWD.
+    /// Reaches a target whatever state it is in, including one that boots dormant. Each select
+    /// code is ignored by a target that is not in the state it switches from.
+    ViaDormant,
+}
+
+impl ConnectSequence {
+    pub const fn steps(self) -> &'static [ConnectStep] {
+        use ConnectStep::*;
+
+        match self {
+            ConnectSequence::SwitchBeforeReset => &[LineReset, JtagToSwd, LineReset, Idle],
+            ConnectSequence::ResetBeforeSwitch => &[LineReset, Idle, JtagToSwd, Idle],
+            ConnectSequence::DormantWakeup => &[LineReset, DormantToSwd, LineReset, Idle],
+            ConnectSequence::ViaDormant => &[
+                LineReset,
+                SwdToDormant,
+                JtagToDormant,
+                DormantToSwd,
+                LineReset,
+                Idle,
+            ],
+        }
+    }
+
+    /// Packs the SWDIO levels of all steps into `buffer`, LSB first, and returns their number.
+    fn pack(self, buffer: &mut [u8; MAX_CONNECT_BITS / 8]) -> usize {
+        let mut bits = 0;
+        for &step in self.steps() {
+            let (data, len) = step.bits();
+            for bit in 0..len {
+                if data[bit / 8] & (1 << (bit % 8)) != 0 {
+                    buffer[bits / 8] |= 1 << (bits % 8);
+                }
+                bits += 1;
+            }
+        }
+        bits
+    }
+}
+
+/// Detail about why the most recent SWD transfer failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum TransferError {
+    None = 0,
+    Wait = 1,
+    Fault = 2,
+    /// No target drove the ACK, or it was not a valid ACK.
+    NoAck = 3,
+    Parity = 4,
+    /// Not attempted as Vtref is below the threshold, see
+    /// [`REJECT_WITHOUT_VTREF`](crate::setup::REJECT_WITHOUT_VTREF).
+    Unpowered = 5,
+    /// The target kept answering WAIT for longer than the wait retries or
+    /// [`WAIT_TIMEOUT_MS`](crate::setup::WAIT_TIMEOUT_MS) allow.
+    WaitTimeout = 6,
+}
+
+impl TransferError {
+    /// The `RETRY_*` bit matching the error.
+    const fn retry_bit(self) -> u8 {
+        match self {
+            TransferError::None | TransferError::Unpowered | TransferError::WaitTimeout => 0,
+            TransferError::Wait => RETRY_WAIT,
+            TransferError::Fault => RETRY_FAULT,
+            TransferError::NoAck => RETRY_NO_ACK,
+            TransferError::Parity => RETRY_PARITY,
+        }
+    }
+
+    fn from_error(e: &swd::Error) -> Self {
+        match e {
+            swd::Error::AckWait => TransferError::Wait,
+            swd::Error::AckFault => TransferError::Fault,
+            swd::Error::AckProtocol | swd::Error::AckUnknown(_) => TransferError::NoAck,
+            swd::Error::BadParity => TransferError::Parity,
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => TransferError::Wait,
+            2 => TransferError::Fault,
+            3 => TransferError::NoAck,
+            4 => TransferError::Parity,
+            5 => TransferError::Unpowered,
+            6 => TransferError::WaitTimeout,
+            _ => TransferError::None,
+        }
+    }
+
+    /// The `DAP_Transfer` response status that most closely describes the error.
+    pub const fn status(self) -> u8 {
+        match self {
+            TransferError::None => 0x01,
+            TransferError::Wait => 0x02,
+            TransferError::Fault | TransferError::WaitTimeout => 0x04,
+            TransferError::NoAck | TransferError::Unpowered => 0x07,
+            // Protocol error, the ACK itself was OK
+            TransferError::Parity => 0x08 | 0x01,
+        }
+    }
+}
+
+static LAST_ERROR: AtomicU8 = AtomicU8::new(TransferError::None as u8);
+
+/// The reason the most recent failed transfer failed.
+pub fn last_transfer_error() -> TransferError {
+    TransferError::from_u8(LAST_ERROR.load(Ordering::Relaxed))
+}
+
+fn record_error<T>(r: swd::Result<T>) -> swd::Result<T> {
+    if let Err(e) = &r {
+        let error = TransferError::from_error(e);
+        LAST_ERROR.store(error as u8, Ordering::Relaxed);
+
+        #[cfg(feature = "diagnostics")]
+        match error {
+            TransferError::None | TransferError::Unpowered | TransferError::WaitTimeout => {}
+            TransferError::Wait => stats::WAIT.increment(),
+            TransferError::Fault => stats::FAULT.increment(),
+            TransferError::NoAck => stats::NO_ACK.increment(),
+            TransferError::Parity => stats::PARITY.increment(),
+        }
+    }
+    r
+}
+
+/// Fails a transfer without clocking the bus while the target has no power.
+fn reject_unpowered<T>() -> swd::Result<T> {
+    trace!("    target unpowered, Vtref = {} mV", adc::vtref_mv());
+    LAST_ERROR.store(TransferError::Unpowered as u8, Ordering::Relaxed);
+    Err(swd::Error::AckProtocol)
+}
+
+static AUTO_SWITCH: AtomicBool = AtomicBool::new(crate::setup::CONNECT_LINE_RESET);
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+
+/// Fails a transfer that kept getting WAIT with a FAULT, which ends the retries of the DAP
+/// handler as well.
+fn wait_timeout<T>() -> swd::Result<T> {
+    warn!("Target kept answering WAIT, failing the transfer");
+    LAST_ERROR.store(TransferError::WaitTimeout as u8, Ordering::Relaxed);
+    #[cfg(feature = "diagnostics")]
+    stats::WAIT.increment();
+    Err(swd::Error::AckFault)
+}
+
+static WAIT_RETRIES: AtomicU16 = AtomicU16::new(u16::MAX);
+
+/// Set the wait retry count of `DAP_TransferConfigure`, the most WAITs in a row a transfer may
+/// see counting the retries of the DAP handler and of the retry policy together. Until the host
+/// configures it only [`WAIT_TIMEOUT_MS`](crate::setup::WAIT_TIMEOUT_MS) applies.
+pub fn set_wait_retries(retries: u16) {
+    WAIT_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// The most WAITs in a row a transfer may see.
+pub fn wait_retries() -> u16 {
+    WAIT_RETRIES.load(Ordering::Relaxed)
+}
+
+static NEW_REQUEST: AtomicBool = AtomicBool::new(false);
+
+/// Start a new run of WAITs with the next transfer, call before a `DAP_Transfer` or
+/// `DAP_TransferBlock` is handed to the DAP handler. A request the handler gave up on after its
+/// own wait retries ends on a WAIT, its run would otherwise carry over into the next request.
+pub fn start_transfer_request() {
+    NEW_REQUEST.store(true, Ordering::Relaxed);
+}
+
+/// A run of WAITs in a row, counted across the retries of the DAP handler and the retry policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct WaitRun(Option<(u16, u32)>);
+
+impl WaitRun {
+    /// Ends the run, on any other result or a new request.
+    fn end(&mut self) {
+        self.0 = None;
+    }
+
+    /// Counts a WAIT at `now_us`. Returns `true` once the run is over `retries` WAITs or lasted
+    /// `timeout_us`, which ends it.
+    fn wait(&mut self, now_us: u32, retries: u16, timeout_us: u32) -> bool {
+        let (count, since) = self.0.get_or_insert((0, now_us));
+        *count = count.saturating_add(1);
+        let expired = *count > retries || now_us.wrapping_sub(*since) >= timeout_us;
+        if expired {
+            self.end();
+        }
+
+        expired
+    }
+}
+
+/// Enable or disable the automatic JTAG-to-SWD switch, line reset and DPIDR read on
+/// `DAP_Connect`. Enabled by default, hosts that do their own sequencing can turn it off.
+pub fn set_auto_switch(enabled: bool) {
+    AUTO_SWITCH.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the switch sequence is emitted on `DAP_Connect`.
+pub fn auto_switch() -> bool {
+    AUTO_SWITCH.load(Ordering::Relaxed)
+}
+
+/// Whether the DPIDR read on the last `DAP_Connect` got an OK ACK, always `true` without the
+/// switch sequence, as there was no read.
+pub fn link_up() -> bool {
+    LINK_UP.load(Ordering::Relaxed)
+}
+
+static FAULT_LEVEL_HIGH: AtomicBool = AtomicBool::new(false);
+
+/// Select the level SWDIO is left driven at after a FAULT (or any other non-OK ACK), so the
+/// line does not float until the next transfer. Low by default.
+pub fn set_fault_level(high: bool) {
+    FAULT_LEVEL_HIGH.store(high, Ordering::Relaxed);
+}
+
+/// The level SWDIO is left driven at after a FAULT.
+pub fn fault_level() -> PinState {
+    PinState::from(FAULT_LEVEL_HIGH.load(Ordering::Relaxed))
+}
+
+/// The level SWDIO is left driven at after a transfer ended with `result`. The idle cycles of a
+/// transfer that got an OK ACK leave it low, the others `fault_level`.
+fn swdio_level_after<T>(result: &swd::Result<T>, fault_level: PinState) -> PinState {
+    match result {
+        Err(swd::Error::BadParity) | Ok(_) => PinState::Low,
+        Err(_) => fault_level,
+    }
+}
+
+static SWCLK_IDLE_HIGH: AtomicBool = AtomicBool::new(true);
+
+/// Select the level SWCLK rests at between transfers. High by default, which the bit loops
+/// naturally end on.
+pub fn set_swclk_idle_level(high: bool) {
+    SWCLK_IDLE_HIGH.store(high, Ordering::Relaxed);
+}
+
+/// The level SWCLK rests at between transfers.
+pub fn swclk_idle_level() -> PinState {
+    PinState::from(SWCLK_IDLE_HIGH.load(Ordering::Relaxed))
+}
+
+static ACK_GAP: AtomicU8 = AtomicU8::new(0);
+
+/// Maximum number of extra cycles between the request and the ACK.
+pub const MAX_ACK_GAP: u8 = 32;
+
+/// Set the number of extra cycles clocked between the request and sampling the ACK, on top of
+/// the turnaround period, for targets that are slow to drive the ACK. 0 (spec behavior)
+/// by default.
+pub fn set_ack_gap(cycles: u8) {
+    ACK_GAP.store(core::cmp::min(cycles, MAX_ACK_GAP), Ordering::Relaxed);
+}
+
+/// The number of extra cycles between the request and sampling the ACK.
+pub fn ack_gap() -> u8 {
+    ACK_GAP.load(Ordering::Relaxed)
+}
+
+/// Keep the idle cycles of the connect profile.
+pub const PROFILE_IDLE_CYCLES: u8 = 0xff;
+
+/// Maximum number of idle cycles after a transfer.
+pub const MAX_IDLE_CYCLES: u8 = 64;
+
+static IDLE_CYCLES: AtomicU8 = AtomicU8::new(PROFILE_IDLE_CYCLES);
+
+/// Set the number of idle cycles, with SWDIO driven low, clocked after every completed
+/// transfer, at most [`MAX_IDLE_CYCLES`]. Some targets need more of them to commit a write.
+/// [`PROFILE_IDLE_CYCLES`] (the default) goes back to the cycles of the connect profile.
+pub fn set_idle_cycles(cycles: u8) {
+    let cycles = if cycles == PROFILE_IDLE_CYCLES {
+        cycles
+    } else {
+        core::cmp::min(cycles, MAX_IDLE_CYCLES)
+    };
+    IDLE_CYCLES.store(cycles, Ordering::Relaxed);
+}
+
+/// The configured idle cycles, [`PROFILE_IDLE_CYCLES`] if the profile decides.
+pub fn idle_cycles() -> u8 {
+    IDLE_CYCLES.load(Ordering::Relaxed)
+}
+
+/// The idle cycles clocked after a transfer, the `configured` ones unless they are
+/// [`PROFILE_IDLE_CYCLES`].
+fn transfer_idle_cycles(configured: u8, profile: u8) -> u8 {
+    match configured {
+        PROFILE_IDLE_CYCLES => profile,
+        cycles => cycles,
+    }
+}
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Keep the pins released after a USB suspend, call after `Dap::suspend`, which floats them
+/// through [`dap::DapContext::high_impedance_mode`]. `DAP_SWJ_Pins` and `DAP_SWJ_Sequence` then
+/// leave them alone too, until the next `DAP_Connect` takes the bus again, so a target on a
+/// shared bus runs freely while the host sleeps.
+pub fn keep_released_until_connect() {
+    SUSPENDED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the pins stay released until the next connect.
+fn bus_released() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+static WIRING_SWAPPED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a DPIDR read failing with `error` after the switch hints at swapped wiring. Only a
+/// line nobody answers on does, not a refused read or a target without power.
+fn hints_at_swapped_wiring(error: &swd::Error, last_error: TransferError) -> bool {
+    matches!(error, swd::Error::AckProtocol | swd::Error::AckUnknown(_))
+        && last_error != TransferError::Unpowered
+}
+
+/// Whether the target only answered the last `DAP_Connect` with SWDIO and SWCLK swapped.
+pub fn wiring_swapped() -> bool {
+    WIRING_SWAPPED.load(Ordering::Relaxed)
+}
+
+/// Retry on WAIT.
+pub const RETRY_WAIT: u8 = 1 << 0;
+/// Retry on FAULT, only useful if something else clears the sticky error in between.
+pub const RETRY_FAULT: u8 = 1 << 1;
+/// Retry without a valid ACK.
+pub const RETRY_NO_ACK: u8 = 1 << 2;
+/// Retry on a parity error, DP reads only. Without it the connect profile's retries apply.
+pub const RETRY_PARITY: u8 = 1 << 3;
+
+/// Maximum number of retries per transfer.
+pub const MAX_RETRIES: u8 = 64;
+
+static RETRY_MASK: AtomicU8 = AtomicU8::new(0);
+static RETRY_COUNT: AtomicU8 = AtomicU8::new(0);
+
+/// Set which failures a transfer is retried on, a combination of the `RETRY_*` bits, and how
+/// many times at most. Nothing is retried by default, apart from the connect profile's parity
+/// retries.
+pub fn set_retry_policy(mask: u8, count: u8) {
+    let policy = RetryPolicy::new(mask, count, 0);
+    RETRY_MASK.store(policy.mask, Ordering::Relaxed);
+    RETRY_COUNT.store(policy.count, Ordering::Relaxed);
+}
+
+/// The failures a transfer is retried on.
+pub fn retry_mask() -> u8 {
+    RETRY_MASK.load(Ordering::Relaxed)
+}
+
+/// How many times a transfer is retried at most.
+pub fn retry_count() -> u8 {
+    RETRY_COUNT.load(Ordering::Relaxed)
+}
+
+/// Which failures a transfer is retried on and how often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RetryPolicy {
+    /// The `RETRY_*` bits.
+    mask: u8,
+    /// Retries of the errors in `mask`.
+    count: u8,
+    /// Retries of a parity error without `RETRY_PARITY`, from the connect profile.
+    parity_retries: u8,
+}
+
+impl RetryPolicy {
+    fn new(mask: u8, count: u8, parity_retries: u8) -> Self {
+        RetryPolicy {
+            mask: mask & (RETRY_WAIT | RETRY_FAULT | RETRY_NO_ACK | RETRY_PARITY),
+            count: core::cmp::min(count, MAX_RETRIES),
+            parity_retries,
+        }
+    }
+
+    /// How many times a transfer failing with `error` may be retried.
+    fn retry_limit(self, error: TransferError, apndp: swd::APnDP, read: bool) -> u8 {
+        match (error, apndp, read) {
+            // Re-reading a DP register has no side effects, while AP reads are posted and
+            // retrying them after the data phase would skip a value.
+            (TransferError::Parity, swd::APnDP::AP, true) => 0,
+            _ if self.mask & error.retry_bit() != 0 => self.count,
+            (TransferError::Parity, _, _) => self.parity_retries,
+            _ => 0,
+        }
+    }
+}
+
+/// Runs `transfer` until it succeeds or fails with an error `policy` allows no more retries of.
+/// Returns `None` once `waited_too_long` ends a run of WAITs instead.
+fn retry_transfer<P, T>(
+    probe: &mut P,
+    policy: RetryPolicy,
+    apndp: swd::APnDP,
+    read: bool,
+    mut waited_too_long: impl FnMut(&mut P, &swd::Result<T>) -> bool,
+    mut transfer: impl FnMut(&mut P) -> swd::Result<T>,
+) -> Option<swd::Result<T>> {
+    let mut retries = 0;
+
+    loop {
+        let result = transfer(probe);
+        if waited_too_long(probe, &result) {
+            return None;
+        }
+
+        match result {
+            Err(e) if retries < policy.retry_limit(TransferError::from_error(&e), apndp, read) => {
+                trace!("    {}, retrying", e);
+                #[cfg(feature = "diagnostics")]
+                if matches!(e, swd::Error::BadParity) {
+                    stats::PARITY_RETRIES.increment();
+                }
+                retries += 1;
+            }
+            r => return Some(r),
+        }
+    }
+}
+
+/// Timing windows of connect-under-reset, in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct ResetWindows {
+    /// nRESET is asserted this long before the switch sequence is clocked.
+    pub assert_us: u32,
+    /// nRESET is kept asserted this long after the DPIDR read.
+    pub release_delay_us: u32,
+    /// Wait after releasing nRESET, before the first transfer.
+    pub settle_us: u32,
+}
+
+impl ResetWindows {
+    pub const DEFAULT: ResetWindows = ResetWindows {
+        assert_us: 10_000,
+        release_delay_us: 0,
+        settle_us: 10_000,
+    };
+
+    /// Longest accepted window, so a bad value can not stall the probe.
+    pub const MAX_US: u32 = 1_000_000;
+
+    /// The windows, each limited to [`MAX_US`](Self::MAX_US).
+    fn clamped(self) -> Self {
+        let clamp = |us| core::cmp::min(us, ResetWindows::MAX_US);
+        ResetWindows {
+            assert_us: clamp(self.assert_us),
+            release_delay_us: clamp(self.release_delay_us),
+            settle_us: clamp(self.settle_us),
+        }
+    }
+
+    /// The steps before the connect sequence is clocked.
+    fn before_connect(self) -> [ResetStep; 2] {
+        [ResetStep::Assert, ResetStep::Wait(self.assert_us)]
+    }
+
+    /// The steps after the DPIDR read.
+    fn after_connect(self) -> [ResetStep; 3] {
+        [
+            ResetStep::Wait(self.release_delay_us),
+            ResetStep::Release,
+            ResetStep::Wait(self.settle_us),
+        ]
+    }
+}
+
+/// A step of connect-under-reset around the connect sequence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResetStep {
+    Assert,
+    Release,
+    /// Wait this many microseconds.
+    Wait(u32),
+}
+
+static UNDER_RESET: AtomicBool = AtomicBool::new(crate::setup::CONNECT_UNDER_RESET);
+static RESET_ASSERT_US: AtomicU32 = AtomicU32::new(ResetWindows::DEFAULT.assert_us);
+static RESET_RELEASE_DELAY_US: AtomicU32 = AtomicU32::new(ResetWindows::DEFAULT.release_delay_us);
+static RESET_SETTLE_US: AtomicU32 = AtomicU32::new(ResetWindows::DEFAULT.settle_us);
+
+/// Enable or disable connect-under-reset on `DAP_Connect`, the default is
+/// [`CONNECT_UNDER_RESET`](crate::setup::CONNECT_UNDER_RESET).
+pub fn set_connect_under_reset(enabled: bool) {
+    UNDER_RESET.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the connect-under-reset windows, each limited to [`ResetWindows::MAX_US`].
+pub fn set_reset_windows(windows: ResetWindows) {
+    let windows = windows.clamped();
+    RESET_ASSERT_US.store(windows.assert_us, Ordering::Relaxed);
+    RESET_RELEASE_DELAY_US.store(windows.release_delay_us, Ordering::Relaxed);
+    RESET_SETTLE_US.store(windows.settle_us, Ordering::Relaxed);
+}
+
+/// The connect-under-reset windows, whether it is enabled or not.
+pub fn reset_windows() -> ResetWindows {
+    ResetWindows {
+        assert_us: RESET_ASSERT_US.load(Ordering::Relaxed),
+        release_delay_us: RESET_RELEASE_DELAY_US.load(Ordering::Relaxed),
+        settle_us: RESET_SETTLE_US.load(Ordering::Relaxed),
+    }
+}
+
+/// The connect-under-reset windows, `None` when it is disabled.
+pub fn connect_under_reset() -> Option<ResetWindows> {
+    if UNDER_RESET.load(Ordering::Relaxed) {
+        Some(reset_windows())
+    } else {
+        None
+    }
+}
+
+static EDGE_OVERHEAD: AtomicU32 = AtomicU32::new(0);
+
+/// SysTick ticks a half SWCLK period of the bit-banged driver takes beyond the ticks it waits
+/// for, as measured by [`measure_edge_overhead`] when the DAP handler was created.
+pub fn edge_overhead_ticks() -> u32 {
+    EDGE_OVERHEAD.load(Ordering::Relaxed)
+}
+
+/// Half periods [`measure_edge_overhead`] times.
+const OVERHEAD_RUNS: u32 = 1024;
+/// Ticks waited per half period while measuring, more than one iteration of the wait loop.
+const OVERHEAD_WAIT_TICKS: u32 = 16;
+
+/// Measures the edge overhead on SysTick, call with interrupts disabled.
+///
+/// The waits run from the SysTick value the previous wait returned, so the pin accesses of an
+/// edge are part of the next wait and cost nothing as long as they are shorter than it. What is
+/// lost is the overshoot of the wait loop, which only sees the deadline passed on its next
+/// iteration. This chains [`OVERHEAD_RUNS`] of the same waits the bit loops use without touching
+/// the pins and counts the ticks they took beyond what they waited for.
+fn measure_edge_overhead(delay: &Delay) -> u32 {
+    let start = delay.get_current();
+    let mut last = start;
+    for _ in 0..OVERHEAD_RUNS {
+        last = delay.delay_ticks_from_last(OVERHEAD_WAIT_TICKS, last);
+    }
+
+    // SysTick counts down
+    let elapsed = start.wrapping_sub(last) & 0xff_ffff;
+    edge_overhead(elapsed, OVERHEAD_RUNS, OVERHEAD_WAIT_TICKS)
+}
+
+/// The overhead per half period of `runs` waits of `wait_ticks` that took `elapsed` ticks,
+/// rounded down so the clock errs on the slow side.
+const fn edge_overhead(elapsed: u32, runs: u32, wait_ticks: u32) -> u32 {
+    (elapsed / runs).saturating_sub(wait_ticks)
+}
+
+/// SysTick ticks to wait per half SWCLK period for `frequency`, less the `overhead_ticks` of
+/// every half period, at least one.
+pub const fn half_period_ticks(cpu_frequency: u32, frequency: u32, overhead_ticks: u32) -> u32 {
+    let ticks = cpu_frequency / frequency / 2;
+    if ticks > overhead_ticks + 1 {
+        ticks - overhead_ticks
+    } else {
+        1
+    }
+}
+
+/// The SWCLK frequency a requested `frequency` quantizes to, including the `overhead_ticks` of
+/// every half period. At the highest rates the pin accesses take longer than the wait, the real
+/// clock is lower then.
+pub const fn effective_frequency(cpu_frequency: u32, frequency: u32, overhead_ticks: u32) -> u32 {
+    let half_period = half_period_ticks(cpu_frequency, frequency, overhead_ticks) + overhead_ticks;
+    cpu_frequency / (2 * half_period)
+}
+
+/// SWCLK frequency until the host sets one, capped by the configured maximum.
+pub const DEFAULT_FREQUENCY: u32 = 2_000_000;
+
+static ACHIEVED_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// The SWCLK frequency the last `DAP_SWJ_Clock` achieved, the requested one rounded down to a
+/// whole number of SysTick ticks per half period, or the PIO divider.
+pub fn achieved_frequency() -> u32 {
+    ACHIEVED_FREQUENCY.load(Ordering::Relaxed)
+}
+
+static SYSTEM_CLOCK: AtomicU32 = AtomicU32::new(0);
+
+/// Longest `DAP_SWJ_Pins` wait, 3 s as in the CMSIS-DAP specification.
+pub const MAX_PIN_WAIT_US: u32 = 3_000_000;
+
+/// Record the system clock the PLLs were configured for, before the DAP handler is created.
+pub fn set_system_clock(cpu_frequency: u32) {
+    SYSTEM_CLOCK.store(cpu_frequency, Ordering::Relaxed);
+}
+
+/// CPU cycles per millisecond, for the `cortex_m::asm::delay` based waits outside the handler.
+/// Assumes the default 125 MHz until the system clock is recorded.
+pub fn cycles_per_ms() -> u32 {
+    match SYSTEM_CLOCK.load(Ordering::Relaxed) {
+        0 => DEFAULT_CYCLES_PER_MS,
+        cpu_frequency => cpu_frequency / 1000,
+    }
+}
+
+const DEFAULT_CYCLES_PER_MS: u32 = 125_000;
+
+/// Preset SWCLK frequencies, `0` stands for the fastest the probe allows.
+pub const SPEED_PRESETS: [u32; 5] = [100_000, 500_000, 1_000_000, 2_000_000, 0];
+
+/// The highest SWCLK frequency of the active pin set and ceiling, kept by `set_clock`.
+static FREQUENCY_LIMIT: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// No preset is active, the host set the clock itself.
+pub const NO_SPEED_PRESET: u8 = 0xff;
+
+static SPEED_PRESET: AtomicU8 = AtomicU8::new(NO_SPEED_PRESET);
+
+/// The frequency requested for a preset.
+pub fn preset_frequency(index: usize) -> Option<u32> {
+    preset_frequency_at(SYSTEM_CLOCK.load(Ordering::Relaxed), index)
+}
+
+fn preset_frequency_at(cpu_frequency: u32, index: usize) -> Option<u32> {
+    match SPEED_PRESETS.get(index) {
+        Some(0) => Some(cpu_frequency / 2),
+        Some(&frequency) => Some(frequency),
+        None => None,
+    }
+}
+
+/// The frequency a preset quantizes to at the current system clock, within the limits of the
+/// pin set and [`MAX_SWCLK_FREQUENCY`](crate::setup::MAX_SWCLK_FREQUENCY) that
+/// `DAP_SWJ_Clock` applies. Not what the PIO engine runs at, it divides the clock differently.
+pub fn preset_effective_frequency(index: usize) -> Option<u32> {
+    let cpu_frequency = SYSTEM_CLOCK.load(Ordering::Relaxed);
+    let limit = FREQUENCY_LIMIT.load(Ordering::Relaxed);
+    preset_effective_frequency_at(cpu_frequency, limit, edge_overhead_ticks(), index)
+}
+
+fn preset_effective_frequency_at(
+    cpu_frequency: u32,
+    limit: u32,
+    overhead_ticks: u32,
+    index: usize,
+) -> Option<u32> {
+    preset_frequency_at(cpu_frequency, index)
+        .map(|frequency| effective_frequency(cpu_frequency, frequency.min(limit), overhead_ticks))
+}
+
+/// Record the active preset, [`NO_SPEED_PRESET`] when the host sets the clock directly.
+pub fn set_speed_preset(index: u8) {
+    SPEED_PRESET.store(index, Ordering::Relaxed);
+}
+
+/// The active preset, or [`NO_SPEED_PRESET`].
+pub fn speed_preset() -> u8 {
+    SPEED_PRESET.load(Ordering::Relaxed)
+}
+
+/// The pin sets SWD can run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PinSetId {
+    /// Pins wired directly to the target.
+    Direct = 0,
+    /// Pins behind a buffered or isolated front-end.
+    Buffered = 1,
+}
+
+/// Timing of a pin set, so slower front-ends can run with more margin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PinSetTiming {
+    /// Highest SWCLK frequency the front-end supports.
+    pub max_frequency: u32,
+    /// Turnaround cycles, overriding the connect profile when set.
+    pub turnaround: Option<u8>,
+    /// Pull SWDIO up while it is an input, instead of leaving it floating.
+    pub pull_up: bool,
+}
+
+impl PinSetTiming {
+    pub const DIRECT: PinSetTiming = PinSetTiming {
+        max_frequency: u32::MAX,
+        turnaround: None,
+        pull_up: false,
+    };
+}
+
+/// The inactive one of the two pin sets.
+pub struct PinSet {
+    pub swdio: DynPin,
+    pub swclk: DynPin,
+    pub nreset: DynPin,
+    pub timing: PinSetTiming,
+}
+
+/// TDI and TDO for JTAG, TCK and TMS are shared with SWCLK and SWDIO.
+pub struct JtagPins {
+    pub tdi: DynPin,
+    pub tdo: DynPin,
+}
+
+static PIN_SET: AtomicU8 = AtomicU8::new(PinSetId::Direct as u8);
+
+/// Select the pin set used from the next `DAP_Connect` on.
+pub fn select_pin_set(id: PinSetId) {
+    PIN_SET.store(id as u8, Ordering::Relaxed);
+}
+
+/// The pin set selected for the next `DAP_Connect`.
+pub fn selected_pin_set() -> PinSetId {
+    match PIN_SET.load(Ordering::Relaxed) {
+        1 => PinSetId::Buffered,
+        _ => PinSetId::Direct,
+    }
+}
+
+/// CTRL/STAT overrun detection enable.
+const ORUNDETECT: u32 = 1 << 0;
+
+/// JTAG-to-SWD select code 0xe79e, sent LSB first.
+const JTAG_TO_SWD: [u8; 2] = 0xe79eu16.to_le_bytes();
+
+/// SWD-to-dormant select code 0xe3bc, sent LSB first.
+const SWD_TO_DORMANT: [u8; 2] = 0xe3bcu16.to_le_bytes();
+
+/// 8 clocks with TMS high and the 31 bit JTAG-to-dormant select code 0x33bbbbba, sent LSB
+/// first.
+const JTAG_TO_DORMANT: [u8; 5] = [0xff, 0xba, 0xbb, 0xbb, 0x33];
+
+/// Room for the longest connect sequence, in bits.
+const MAX_CONNECT_BITS: usize = 384;
+
+/// At least 8 clocks with SWDIO high, the 128 bit selection alert, 4 clocks with SWDIO low and
+/// the SWD activation code 0x1a, sent LSB first.
+const DORMANT_TO_SWD: [u8; 19] = [
+    0xff, 0x92, 0xf3, 0x09, 0x62, 0x95, 0x2d, 0x85, 0x86, 0xe9, 0xaf, 0xdd, 0xe3, 0xa2, 0x0e, 0xbc,
+    0x19, 0xa0, 0x01,
+];
 
 pub struct Context {
+    /// SWCLK frequency as requested, the pin set and ceiling limits are applied on top of it.
     max_frequency: u32,
+    frequency: u32,
+    frequency_ceiling: u32,
     cpu_frequency: u32,
     cycles_per_us: u32,
     half_period_ticks: u32,
+    edge_overhead_ticks: u32,
+    profile: ConnectProfile,
+    timing: SwdTiming,
+    connect_sequence: ConnectSequence,
     delay: &'static Delay,
     swdio: DynPin,
     swclk: DynPin,
     nreset: DynPin,
+    pin_set: PinSetId,
+    pin_timing: PinSetTiming,
+    other_pin_set: Option<PinSet>,
+    reset_active: Option<DynPin>,
+    reset_asserted: bool,
+    jtag_pins: Option<JtagPins>,
+    pio: Option<PioSwd>,
+    host_turnaround: Option<u8>,
+    data_phase: bool,
+    /// The host enabled overrun detection in CTRL/STAT.
+    overrun_detect: bool,
+    waits: WaitRun,
+    /// Direction SWDIO was last set to, `Some(true)` for driven, `None` if unknown.
+    swdio_driven: Option<bool>,
+    bus_taken: bool,
 }
 
 impl defmt::Format for Context {
@@ -23,11 +891,16 @@ impl defmt::Format for Context {
         // format the bitfields of the register as struct fields
         defmt::write!(
            f,
-           "Context {{ max_frequency: {}, cpu_frequency: {}, cycles_per_us: {}, half_period_ticks: {} }}",
+           "Context {{ max_frequency: {}, cpu_frequency: {}, cycles_per_us: {}, half_period_ticks: {}, profile: {}, timing: {}, connect_sequence: {}, pin_set: {}, pin_timing: {} }}",
             self.max_frequency,
             self.cpu_frequency,
             self.cycles_per_us,
             self.half_period_ticks,
+            self.profile,
+            self.timing,
+            self.connect_sequence,
+            self.pin_set,
+            self.pin_timing,
         )
     }
 }
@@ -39,6 +912,11 @@ impl core::fmt::Debug for Context {
             .field("cpu_frequency", &self.cpu_frequency)
             .field("cycles_per_us", &self.cycles_per_us)
             .field("half_period_ticks", &self.half_period_ticks)
+            .field("profile", &self.profile)
+            .field("timing", &self.timing)
+            .field("connect_sequence", &self.connect_sequence)
+            .field("pin_set", &self.pin_set)
+            .field("pin_timing", &self.pin_timing)
             .finish()
     }
 }
@@ -46,8 +924,16 @@ impl core::fmt::Debug for Context {
 impl dap::DapContext for Context {
     fn high_impedance_mode(&mut self) {
         self.swdio.into_floating_disabled();
+        self.swdio_driven = None;
         self.swclk.into_floating_disabled();
         self.nreset.into_floating_disabled();
+        self.reset_asserted = false;
+        if let Some(reset_active) = &mut self.reset_active {
+            reset_active.set_low().ok();
+        }
+        if let Some(jtag_pins) = &mut self.jtag_pins {
+            jtag_pins.tdi.into_floating_disabled();
+        }
     }
 }
 
@@ -57,26 +943,277 @@ impl Context {
         swclk: DynPin,
         nreset: DynPin,
         cpu_frequency: u32,
+        profile: ConnectProfile,
         delay: &'static Delay,
     ) -> Self {
-        let max_frequency = 100_000;
-        let half_period_ticks = cpu_frequency / max_frequency / 2;
+        let max_frequency = DEFAULT_FREQUENCY;
+        let edge_overhead_ticks = measure_edge_overhead(delay);
+        debug!("SWCLK edge overhead: {} ticks", edge_overhead_ticks);
+        let half_period_ticks =
+            half_period_ticks(cpu_frequency, max_frequency, edge_overhead_ticks);
+        SYSTEM_CLOCK.store(cpu_frequency, Ordering::Relaxed);
+        EDGE_OVERHEAD.store(edge_overhead_ticks, Ordering::Relaxed);
         Context {
             max_frequency,
+            frequency: effective_frequency(cpu_frequency, max_frequency, edge_overhead_ticks),
+            frequency_ceiling: u32::MAX,
             cpu_frequency,
             cycles_per_us: cpu_frequency / 1_000_000,
             half_period_ticks,
+            edge_overhead_ticks,
+            profile,
+            timing: profile.timing(),
+            connect_sequence: profile.sequence(),
             delay,
             swdio,
             swclk,
             nreset,
+            pin_set: PinSetId::Direct,
+            pin_timing: PinSetTiming::DIRECT,
+            other_pin_set: None,
+            reset_active: None,
+            reset_asserted: false,
+            jtag_pins: None,
+            pio: None,
+            host_turnaround: None,
+            data_phase: false,
+            overrun_detect: false,
+            waits: WaitRun::default(),
+            swdio_driven: None,
+            bus_taken: true,
+        }
+    }
+
+    /// Add the buffered pin set, which can then be selected with [`select_pin_set`].
+    pub fn set_buffered_pins(&mut self, mut pins: PinSet) {
+        pins.swdio.into_floating_disabled();
+        pins.swclk.into_floating_disabled();
+        pins.nreset.into_floating_disabled();
+        self.other_pin_set = Some(pins);
+    }
+
+    /// Add TDI and TDO, which enables the JTAG transport.
+    pub fn set_jtag_pins(&mut self, mut pins: JtagPins) {
+        pins.tdi.into_floating_disabled();
+        pins.tdo.into_floating_disabled();
+        self.jtag_pins = Some(pins);
+    }
+
+    /// The SWCLK frequency the last `set_clock` achieved, before the loop overhead of the
+    /// bit-banged driver.
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    /// SWCLK frequency until the host sets one, instead of [`DEFAULT_FREQUENCY`].
+    pub fn set_default_frequency(&mut self, frequency: u32) {
+        self.set_clock(frequency);
+    }
+
+    /// Cap the SWCLK frequency, whatever the host requests.
+    pub fn set_max_frequency(&mut self, ceiling: u32) {
+        self.frequency_ceiling = ceiling;
+        self.set_clock(self.max_frequency);
+    }
+
+    /// Run SWD transfers on the PIO engine instead of bit-banging them.
+    pub fn set_pio(&mut self, mut pio: PioSwd) {
+        self.frequency = pio.set_frequency(self.clamped_frequency());
+        ACHIEVED_FREQUENCY.store(self.frequency, Ordering::Relaxed);
+        self.pio = Some(pio);
+    }
+
+    /// Switch to the selected pin set, if it differs from the active one and exists.
+    fn apply_pin_set(&mut self) {
+        let id = selected_pin_set();
+        if id == self.pin_set {
+            return;
+        }
+
+        if let Some(other) = &mut self.other_pin_set {
+            self.swdio.into_floating_disabled();
+            self.swdio_driven = None;
+            self.swclk.into_floating_disabled();
+            self.nreset.into_floating_disabled();
+            self.reset_asserted = false;
+
+            core::mem::swap(&mut self.swdio, &mut other.swdio);
+            core::mem::swap(&mut self.swclk, &mut other.swclk);
+            core::mem::swap(&mut self.nreset, &mut other.nreset);
+            core::mem::swap(&mut self.pin_timing, &mut other.timing);
+            self.pin_set = id;
+
+            // Re-apply the clock within the limit of the new front-end
+            self.set_clock(self.max_frequency);
+            info!("Switched to pin set {}, timing: {}", id, self.pin_timing);
+        } else {
+            warn!("Pin set {} is not available", id);
+        }
+    }
+
+    /// Switch SWDIO to an input, pulled up if the front-end wants it.
+    fn swdio_input(&mut self) {
+        if self.swdio_driven == Some(false) {
+            return;
+        }
+
+        if self.pin_timing.pull_up {
+            self.swdio.into_pull_up_input();
+        } else {
+            self.swdio.into_floating_input();
+        }
+        self.swdio_driven = Some(false);
+    }
+
+    /// Whether the tracked SWDIO direction matches the output enable of the pin.
+    fn swdio_direction_tracked(&self) -> bool {
+        // NOTE(unsafe) read only access of the output enables
+        let oe = unsafe { (*pac::SIO::ptr()).gpio_oe.read().bits() };
+        let driven = (oe >> self.swdio.id().num) & 1 != 0;
+        self.swdio_driven.map_or(true, |tracked| tracked == driven)
+    }
+
+    /// Drive SWDIO, unless it already is an output. Skipping the pin reconfiguration keeps the
+    /// gaps between the phases of back to back transfers short.
+    fn drive_swdio(&mut self) {
+        if self.swdio_driven != Some(true) {
+            self.swdio.into_push_pull_output();
+            self.swdio_driven = Some(true);
+        }
+    }
+
+    /// Keep the pins high-impedance, ignoring `DAP_SWJ_Pins` and `DAP_SWJ_Sequence`, until the
+    /// first `DAP_Connect` takes the bus.
+    pub fn hold_bus_until_connect(&mut self) {
+        dap::DapContext::high_impedance_mode(self);
+        self.bus_taken = false;
+    }
+
+    /// Optional output that is driven high while nRESET is asserted.
+    pub fn set_reset_output(&mut self, mut pin: Option<DynPin>) {
+        if let Some(pin) = &mut pin {
+            pin.into_push_pull_output();
+            pin.set_low().ok();
+        }
+        self.reset_active = pin;
+    }
+
+    /// Assert or release nRESET, released it is left open drain.
+    fn drive_reset(&mut self, asserted: bool) {
+        self.reset_asserted = asserted;
+        if asserted {
+            // Clear the output latch first, so enabling the output never drives the line high,
+            // not even for a moment
+            // NOTE(unsafe) the SIO clear register is atomic and nRESET is owned by the context
+            let sio = unsafe { &*pac::SIO::ptr() };
+            sio.gpio_out_clr
+                .write(|w| unsafe { w.bits(1 << self.nreset.id().num) });
+            self.nreset.into_push_pull_output();
+            self.nreset.set_low().ok();
+        } else {
+            // "open drain", with the input enabled to read back who holds the line
+            self.nreset.into_floating_input();
+        }
+
+        if let Some(reset_active) = &mut self.reset_active {
+            reset_active.set_state(PinState::from(asserted)).ok();
+        }
+    }
+
+    fn delay_us(&self, us: u32) {
+        timer_delay::delay_us(us);
+    }
+
+    fn run_reset_steps(&mut self, steps: &[ResetStep]) {
+        for &step in steps {
+            match step {
+                ResetStep::Assert => self.drive_reset(true),
+                ResetStep::Release => self.drive_reset(false),
+                ResetStep::Wait(us) => self.delay_us(us),
+            }
+        }
+    }
+
+    /// Polls `done` until it holds or `ticks` SysTick ticks passed, returns whether it held.
+    fn wait_until(&mut self, ticks: u32, mut done: impl FnMut(&mut Self) -> bool) -> bool {
+        let delay = self.delay;
+        let mut remaining = ticks;
+        let mut last = delay.get_current();
+
+        loop {
+            if done(self) {
+                return true;
+            }
+            if remaining == 0 {
+                return false;
+            }
+
+            let now = delay.get_current();
+            remaining = remaining.saturating_sub(last.wrapping_sub(now) & 0xff_ffff);
+            last = now;
+        }
+    }
+
+    /// Select a connect profile, its timing is applied on the next connect.
+    pub fn set_profile(&mut self, profile: ConnectProfile) {
+        self.profile = profile;
+        self.connect_sequence = profile.sequence();
+    }
+
+    /// Override the connect sequence of the profile.
+    pub fn set_connect_sequence(&mut self, sequence: ConnectSequence) {
+        self.connect_sequence = sequence;
+    }
+
+    /// Apply the timing defaults of the selected connect profile.
+    fn apply_profile(&mut self) {
+        self.timing = self
+            .profile
+            .timing_with(self.host_turnaround, self.pin_timing.turnaround);
+        trace!("  profile = {}, timing = {}", self.profile, self.timing);
+    }
+
+    /// Clocks SWCLK `cycles` times without touching SWDIO, leaving SWCLK high-impedance.
+    fn clock_only(&mut self, cycles: usize) {
+        self.swclk.into_push_pull_output();
+
+        let half_period_ticks = self.half_period_ticks;
+        let mut last = self.delay.get_current();
+        last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        for _ in 0..cycles {
+            self.swclk.set_low().ok();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.swclk.set_high().ok();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
         }
+
+        self.swclk.into_floating_input();
+    }
+
+    /// Send the steps of the configured connect sequence, in order. They go out as one
+    /// sequence, so SWCLK and SWDIO stay driven in between: a select code is only recognized
+    /// when it directly follows the line reset or alert before it.
+    fn send_connect_sequence(&mut self) {
+        trace!("  connect sequence = {}", self.connect_sequence);
+        let mut buffer = [0; MAX_CONNECT_BITS / 8];
+        let bits = self.connect_sequence.pack(&mut buffer);
+        self.sequence(&buffer, bits);
     }
 }
 
 impl swj::Swj for Context {
+    /// Drives the selected pins, then waits up to `wait_us` (at most [`MAX_PIN_WAIT_US`]) for a
+    /// selected nRESET to read back at the requested level, as the target or a supervisor may
+    /// hold it low after the probe releases it. An asserted nRESET stays asserted until a later
+    /// request releases it, it is never driven high as it emulates an open drain output.
+    /// SWCLK and SWDIO are released before the levels are read.
     fn pins(&mut self, output: swj::Pins, mask: swj::Pins, wait_us: u32) -> swj::Pins {
         trace!("Running SWJ_pins");
+        if !self.bus_taken || bus_released() {
+            warn!("SWJ_pins before DAP_Connect, pins kept high-impedance");
+            return swj::Pins::empty();
+        }
+        bus_park::activity();
         if mask.contains(swj::Pins::SWCLK) {
             self.swclk.into_push_pull_output();
             self.swclk
@@ -89,7 +1226,7 @@ impl swj::Swj for Context {
         }
 
         if mask.contains(swj::Pins::SWDIO) {
-            self.swdio.into_push_pull_output();
+            self.drive_swdio();
             self.swdio
                 .set_state(if output.contains(swj::Pins::SWDIO) {
                     PinState::High
@@ -100,20 +1237,25 @@ impl swj::Swj for Context {
         }
 
         if mask.contains(swj::Pins::NRESET) {
-            if output.contains(swj::Pins::NRESET) {
-                // "open drain"
-                self.nreset.into_floating_disabled();
-            } else {
-                self.nreset.into_push_pull_output();
-                self.nreset.set_low().ok();
+            let released = output.contains(swj::Pins::NRESET);
+            self.drive_reset(!released);
+
+            let ticks = self.cycles_per_us * wait_us.min(MAX_PIN_WAIT_US);
+            let reached = self.wait_until(
+                ticks,
+                |context| matches!(context.nreset.is_high(), Ok(high) if high == released),
+            );
+            if !reached && wait_us > 0 {
+                warn!("nRESET did not follow within {} us", wait_us);
             }
         }
 
-        self.delay.delay_ticks(self.cycles_per_us * wait_us);
-
         self.swclk.into_floating_input();
         self.swdio.into_floating_input();
-        self.nreset.into_floating_input();
+        self.swdio_driven = None;
+        if !self.reset_asserted {
+            self.nreset.into_floating_input();
+        }
 
         let mut ret = swj::Pins::empty();
         ret.set(swj::Pins::SWCLK, matches!(self.swclk.is_high(), Ok(true)));
@@ -123,140 +1265,621 @@ impl swj::Swj for Context {
         ret
     }
 
-    fn sequence(&mut self, data: &[u8], mut bits: usize) {
+    /// Clocks `bits` bits of `data` out on SWDIO, LSB first, at most as many as `data` holds.
+    /// Without data only SWCLK is clocked and SWDIO is left as it is. Both pins are
+    /// high-impedance afterwards, which hosts rely on after the JTAG-to-SWD switch.
+    fn sequence(&mut self, data: &[u8], bits: usize) {
         trace!("Running SWJ sequence");
-        self.swdio.into_push_pull_output();
+        if !self.bus_taken || bus_released() {
+            warn!("SWJ sequence before DAP_Connect, pins kept high-impedance");
+            return;
+        }
+        if bits == 0 {
+            return;
+        }
+        bus_park::activity();
+
+        if data.is_empty() {
+            self.clock_only(bits);
+            return;
+        }
+
+        let clamped = sequence_bits(bits, data.len());
+        if clamped < bits {
+            warn!(
+                "SWJ sequence of {} bits with {} bytes, clamped",
+                bits,
+                data.len()
+            );
+        }
+
+        self.drive_swdio();
         self.swclk.into_push_pull_output();
 
         let half_period_ticks = self.half_period_ticks;
         let mut last = self.delay.get_current();
         last = self.delay.delay_ticks_from_last(half_period_ticks, last);
 
-        for byte in data {
-            let mut byte = *byte;
-            let frame_bits = core::cmp::min(bits, 8);
-            for _ in 0..frame_bits {
-                let bit = byte & 1;
-                byte >>= 1;
-                if bit != 0 {
-                    self.swdio.set_high().ok();
-                } else {
-                    self.swdio.set_low().ok();
-                }
-                self.swclk.set_low().ok();
-                last = self.delay.delay_ticks_from_last(half_period_ticks, last);
-                self.swclk.set_high().ok();
-                last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+        for high in sequence_levels(data, clamped) {
+            if high {
+                self.swdio.set_high().ok();
+            } else {
+                self.swdio.set_low().ok();
             }
-            bits -= frame_bits;
+            self.swclk.set_low().ok();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
+            self.swclk.set_high().ok();
+            last = self.delay.delay_ticks_from_last(half_period_ticks, last);
         }
 
         self.swclk.into_floating_input();
         self.swdio.into_floating_input();
+        self.swdio_driven = None;
     }
 
+    /// Any non-zero frequency is accepted, anything above the fastest half period of one
+    /// SysTick tick runs at that rate. The rate achieved is kept in [`Context::frequency`] and
+    /// [`achieved_frequency`].
     fn set_clock(&mut self, max_frequency: u32) -> bool {
         trace!("Running SWJ clock");
-        if max_frequency < self.cpu_frequency {
-            self.max_frequency = max_frequency;
-            self.half_period_ticks = self.cpu_frequency / self.max_frequency / 2;
-            trace!("  freq = {}", max_frequency);
-            trace!("  half_period_ticks = {}", self.half_period_ticks);
-            true
+        if max_frequency == 0 {
+            return false;
+        }
+
+        // Kept unclamped, so a later pin set or ceiling change gets back to the requested rate
+        self.max_frequency = max_frequency;
+        let max_frequency = self.clamped_frequency();
+        FREQUENCY_LIMIT.store(self.frequency_limit(), Ordering::Relaxed);
+        self.half_period_ticks =
+            half_period_ticks(self.cpu_frequency, max_frequency, self.edge_overhead_ticks);
+        self.frequency = match &mut self.pio {
+            Some(pio) => pio.set_frequency(max_frequency),
+            None => {
+                effective_frequency(self.cpu_frequency, max_frequency, self.edge_overhead_ticks)
+            }
+        };
+        ACHIEVED_FREQUENCY.store(self.frequency, Ordering::Relaxed);
+        if self.frequency != max_frequency {
+            debug!(
+                "SWCLK requested: {}, achieved: {}",
+                max_frequency, self.frequency
+            );
+        }
+        trace!("  half_period_ticks = {}", self.half_period_ticks);
+        true
+    }
+
+    /// The requested SWCLK frequency within the limits of the pin set and the ceiling.
+    fn clamped_frequency(&self) -> u32 {
+        self.max_frequency.min(self.frequency_limit())
+    }
+
+    /// The highest SWCLK frequency the pin set and the ceiling allow.
+    fn frequency_limit(&self) -> u32 {
+        self.pin_timing.max_frequency.min(self.frequency_ceiling)
+    }
+}
+
+/// The bits of an SWJ sequence of `bits` bits clocked with `len` bytes of data, a malformed
+/// request may claim more bits than it carries.
+fn sequence_bits(bits: usize, len: usize) -> usize {
+    bits.min(len.saturating_mul(8))
+}
+
+/// The SWDIO level of each clock of an SWJ sequence, LSB first.
+fn sequence_levels(data: &[u8], bits: usize) -> impl Iterator<Item = bool> + '_ {
+    (0..sequence_bits(bits, data.len())).map(move |bit| data[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+static HOST_CONNECTED: AtomicBool = AtomicBool::new(false);
+static HOST_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the host reported a connected debugger with `DAP_HostStatus`.
+pub fn host_connected() -> bool {
+    HOST_CONNECTED.load(Ordering::Relaxed)
+}
+
+/// State of the on-board LED at the status task tick `tick`. The LED itself belongs to the
+/// status task, [`Leds`] only records the host status: a heartbeat while no host is connected,
+/// on while connected and breathing while the host reports running. A short flash every fourth
+/// tick, whatever the host status, shows that the sensed Vtref is below the threshold.
+pub fn status_led_state(tick: u32) -> LedState {
+    let on = if crate::setup::VTREF_SENSE && !adc::target_powered() {
+        tick % 4 == 0
+    } else if !HOST_CONNECTED.load(Ordering::Relaxed) {
+        tick % 2 == 0
+    } else if HOST_RUNNING.load(Ordering::Relaxed) {
+        return LedState::Breathing;
+    } else {
+        true
+    };
+
+    if on {
+        LedState::On
+    } else {
+        LedState::Off
+    }
+}
+
+pub struct Leds {
+    connected: Option<DynPin>,
+}
+
+impl Leds {
+    /// `connected` is an optional output that follows the host's connected status.
+    pub fn new(mut connected: Option<DynPin>) -> Self {
+        if let Some(pin) = &mut connected {
+            pin.into_push_pull_output();
+            pin.set_low().ok();
+        }
+        Leds { connected }
+    }
+}
+
+impl defmt::Format for Leds {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Leds {{ connected: {} }}", self.connected.is_some())
+    }
+}
+
+impl core::fmt::Debug for Leds {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Leds")
+            .field("connected", &self.connected.is_some())
+            .finish()
+    }
+}
+
+impl dap::DapLeds for Leds {
+    fn react_to_host_status(&mut self, host_status: dap::HostStatus) {
+        trace!("Running LEDs react to host status");
+        match host_status {
+            dap::HostStatus::Connected(connected) => {
+                HOST_CONNECTED.store(connected, Ordering::Relaxed);
+                if !connected {
+                    HOST_RUNNING.store(false, Ordering::Relaxed);
+                }
+                if let Some(pin) = &mut self.connected {
+                    pin.set_state(PinState::from(connected)).ok();
+                }
+            }
+            dap::HostStatus::Running(running) => {
+                HOST_RUNNING.store(running, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub struct Jtag(Context);
+
+impl Jtag {
+    /// Clock one `DAP_JTAG_Sequence` entry, TMS held at `tms` and TDI taken LSB first from
+    /// `tdi`. TDO is sampled before each rising edge of TCK into `tdo` if given.
+    fn sequence(&mut self, tms: bool, tdi: &[u8], mut tdo: Option<&mut [u8]>, bits: usize) {
+        let context = &mut self.0;
+        let pins = match &mut context.jtag_pins {
+            Some(pins) => pins,
+            None => return,
+        };
+
+        context.swdio.set_state(PinState::from(tms)).ok();
+
+        let half_period_ticks = context.half_period_ticks;
+        let mut last = context.delay.get_current();
+
+        for bit in 0..bits {
+            let (byte, mask) = (bit / 8, 1 << (bit % 8));
+            pins.tdi
+                .set_state(PinState::from(tdi[byte] & mask != 0))
+                .ok();
+            context.swclk.set_low().ok();
+            last = context.delay.delay_ticks_from_last(half_period_ticks, last);
+
+            if let Some(tdo) = &mut tdo {
+                if mask == 1 {
+                    tdo[byte] = 0;
+                }
+                if matches!(pins.tdo.is_high(), Ok(true)) {
+                    tdo[byte] |= mask;
+                }
+            }
+            context.swclk.set_high().ok();
+            last = context.delay.delay_ticks_from_last(half_period_ticks, last);
+        }
+    }
+}
+
+impl jtag::Jtag<Context> for Jtag {
+    const AVAILABLE: bool = cfg!(feature = "jtag");
+
+    fn new(mut context: Context) -> Self {
+        trace!("Creating JTAG");
+        context.bus_taken = true;
+        SUSPENDED.store(false, Ordering::Relaxed);
+        context.apply_pin_set();
+
+        if let Some(pins) = &mut context.jtag_pins {
+            pins.tdi.into_push_pull_output();
+            pins.tdi.set_high().ok();
+            pins.tdo.into_pull_up_input();
         } else {
-            false
+            warn!("JTAG selected without TDI and TDO pins");
         }
+
+        // TCK idles high, TMS high keeps the TAP in Test-Logic-Reset
+        context.swclk.into_push_pull_output();
+        context.swclk.set_high().ok();
+        context.drive_swdio();
+        context.swdio.set_high().ok();
+
+        Jtag(context)
+    }
+
+    fn release(mut self) -> Context {
+        trace!("Releasing JTAG");
+        self.0.swclk.into_floating_input();
+        self.0.swdio.into_floating_input();
+        self.0.swdio_driven = None;
+        if let Some(pins) = &mut self.0.jtag_pins {
+            pins.tdi.into_floating_input();
+            pins.tdo.into_floating_input();
+        }
+        self.0
+    }
+
+    /// `data` is the `DAP_JTAG_Sequence` request after the command ID: the sequence count, then
+    /// per sequence the info byte and the TDI data. Returns the number of TDO bytes in `rxbuf`.
+    fn sequences(&mut self, data: &[u8], rxbuf: &mut [u8]) -> u32 {
+        trace!("Running JTAG sequences");
+        let (&count, mut data) = match data.split_first() {
+            Some(split) => split,
+            None => return 0,
+        };
+
+        let mut rx_len = 0;
+        for _ in 0..count {
+            let (&info, rest) = match data.split_first() {
+                Some(split) => split,
+                None => break,
+            };
+
+            // Info: TCK cycles in bits 5:0 with 0 meaning 64, TMS in bit 6, capture TDO in bit 7
+            let bits = match info & 0x3f {
+                0 => 64,
+                n => n as usize,
+            };
+            let tms = info & 0x40 != 0;
+            let capture = info & 0x80 != 0;
+            let bytes = (bits + 7) / 8;
+            if rest.len() < bytes || (capture && rxbuf.len() < rx_len + bytes) {
+                warn!("JTAG sequence truncated");
+                break;
+            }
+
+            let tdo = if capture {
+                Some(&mut rxbuf[rx_len..rx_len + bytes])
+            } else {
+                None
+            };
+            self.sequence(tms, &rest[..bytes], tdo, bits);
+
+            if capture {
+                rx_len += bytes;
+            }
+            data = &rest[bytes..];
+        }
+
+        rx_len as u32
+    }
+
+    fn set_clock(&mut self, max_frequency: u32) -> bool {
+        self.0.set_clock(max_frequency)
     }
 }
 
 #[derive(Debug, defmt::Format)]
-pub struct Leds {}
+pub struct Swd(Context);
+
+/// Clock cycles of a `DAP_SWD_Configure` turnaround period.
+fn turnaround_cycles(period: swd::TurnaroundPeriod) -> u8 {
+    match period {
+        swd::TurnaroundPeriod::Cycles1 => 1,
+        swd::TurnaroundPeriod::Cycles2 => 2,
+        swd::TurnaroundPeriod::Cycles3 => 3,
+        swd::TurnaroundPeriod::Cycles4 => 4,
+    }
+}
+
+impl swd::Swd<Context> for Swd {
+    const AVAILABLE: bool = true;
+
+    fn new(mut context: Context) -> Self {
+        trace!("Creating SWD");
+        #[cfg(feature = "diagnostics")]
+        stats::on_connect();
+        WIRING_SWAPPED.store(false, Ordering::Relaxed);
+        context.bus_taken = true;
+        context.overrun_detect = false;
+        SUSPENDED.store(false, Ordering::Relaxed);
+        context.apply_pin_set();
+        context.apply_profile();
+
+        // A reset the host asserted with `DAP_SWJ_Pins` is its connect under reset, it stays
+        // asserted until the host releases it
+        let under_reset = connect_under_reset().filter(|_| !context.reset_asserted);
+        if let Some(windows) = under_reset {
+            trace!("  connect under reset, windows = {}", windows);
+            context.run_reset_steps(&windows.before_connect());
+        } else if context.reset_asserted {
+            trace!("  connect with nRESET held by the host");
+        }
+
+        // An unpowered target is not clocked at all, not even by the connect sequence
+        let blocked = adc::transfers_blocked();
+        if blocked {
+            warn!(
+                "Target unpowered, connecting without the connect sequence, Vtref = {} mV",
+                adc::vtref_mv()
+            );
+        }
+        let line_reset = auto_switch() && !blocked;
+
+        if line_reset {
+            context.send_connect_sequence();
+        }
+
+        context.drive_swdio();
+        context.swclk.into_push_pull_output();
+        bus_park::connect(context.swdio.id().num, context.swclk.id().num);
+
+        let mut probe = Self(context);
+
+        let mut link_up = !blocked;
+        if line_reset {
+            // The DPIDR read is required after the line reset to leave the reset state
+            match swd::Swd::read_inner(&mut probe, swd::APnDP::DP, swd::DPRegister::DPIDR) {
+                Ok(dpidr) => info!("Connected, DPIDR: 0x{:x}", dpidr),
+                Err(e) => {
+                    warn!("DPIDR read after switch failed: {}", e);
+                    link_up = false;
+                    if hints_at_swapped_wiring(&e, last_transfer_error()) {
+                        probe.check_swapped_wiring();
+                    }
+                }
+            }
+        }
+        LINK_UP.store(link_up, Ordering::Relaxed);
+
+        if let Some(windows) = under_reset {
+            probe.0.run_reset_steps(&windows.after_connect());
+        }
+
+        probe
+    }
+
+    fn release(mut self) -> Context {
+        trace!("Releasing SWD");
+        bus_park::disconnect();
+        self.0.swclk.into_floating_input();
+        self.0.swdio_input();
+
+        self.0
+    }
+
+    fn configure(&mut self, period: swd::TurnaroundPeriod, data_phase: swd::DataPhase) -> bool {
+        trace!("SWD configure");
+        self.0.data_phase = data_phase != swd::DataPhase::NoDataPhase;
+
+        // Kept over reconnects, and over the turnaround of the profile and the pin set
+        let turnaround = turnaround_cycles(period);
+        self.0.host_turnaround = Some(turnaround);
+        self.0.timing.turnaround = turnaround;
+        trace!(
+            "  turnaround = {}, data phase = {}",
+            turnaround,
+            self.0.data_phase
+        );
+
+        true
+    }
+
+    fn read_inner(&mut self, apndp: swd::APnDP, a: swd::DPRegister) -> swd::Result<u32> {
+        trace!("SWD read, apndp: {}, addr: {}", apndp, a,);
+        if adc::transfers_blocked() {
+            return reject_unpowered();
+        }
+        bus_park::activity();
+
+        self.with_retries(apndp, true, |probe| probe.read_once(apndp, a))
+    }
+
+    fn write_inner(&mut self, apndp: swd::APnDP, a: swd::DPRegister, data: u32) -> swd::Result<()> {
+        trace!(
+            "SWD write, apndp: {}, addr: {}, data: 0x{:x}",
+            apndp,
+            a,
+            data
+        );
+        if adc::transfers_blocked() {
+            return reject_unpowered();
+        }
+        bus_park::activity();
+
+        if matches!(apndp, swd::APnDP::DP) && matches!(a, swd::DPRegister::RDBUFF) {
+            self.write_targetsel(data);
+            self.idle_clock();
+            return Ok(());
+        }
+        if matches!(apndp, swd::APnDP::DP) && matches!(a, swd::DPRegister::CTRLSTAT) {
+            self.0.overrun_detect = data & ORUNDETECT != 0;
+        }
+
+        self.with_retries(apndp, false, |probe| probe.write_once(apndp, a, data))
+    }
 
-impl dap::DapLeds for Leds {
-    fn react_to_host_status(&mut self, _host_status: dap::HostStatus) {
-        trace!("Running LEDs react to host status");
+    fn set_clock(&mut self, max_frequency: u32) -> bool {
+        trace!("SWD set clock: freq = {}", max_frequency);
+        self.0.set_clock(max_frequency)
     }
 }
 
-pub struct Jtag(Context);
+// Every phase sets the SWDIO direction it needs instead of relying on the previous transfer, so
+// reads and writes can be mixed freely within one `DAP_Transfer`. The posted AP reads and the
+// RDBUFF read that ends them are handled by the DAP handler.
+impl Swd {
+    /// Runs a transfer, retrying it on the non-OK ACKs and parity errors the retry policy asks
+    /// for, then records the final result.
+    fn with_retries<T>(
+        &mut self,
+        apndp: swd::APnDP,
+        read: bool,
+        transfer: impl FnMut(&mut Self) -> swd::Result<T>,
+    ) -> swd::Result<T> {
+        let policy = RetryPolicy::new(retry_mask(), retry_count(), self.0.timing.parity_retries);
+        let result = retry_transfer(self, policy, apndp, read, Self::waited_too_long, transfer);
+        self.idle_clock();
+        debug_assert!(self.0.swdio_direction_tracked());
+
+        match result {
+            Some(r) => record_error(r),
+            None => wait_timeout(),
+        }
+    }
 
-impl jtag::Jtag<Context> for Jtag {
-    const AVAILABLE: bool = false;
+    /// Tracks the run of WAITs the transfers of one request see, as the DAP handler retries
+    /// WAITs with the same request. Returns `true` once the run is over the wait retry count or
+    /// [`WAIT_TIMEOUT_MS`](crate::setup::WAIT_TIMEOUT_MS), which starts a new run.
+    fn waited_too_long<T>(&mut self, result: &swd::Result<T>) -> bool {
+        if NEW_REQUEST.load(Ordering::Relaxed) {
+            NEW_REQUEST.store(false, Ordering::Relaxed);
+            self.0.waits.end();
+        }
+        if !matches!(result, Err(swd::Error::AckWait)) {
+            self.0.waits.end();
+            return false;
+        }
 
-    fn new(context: Context) -> Self {
-        Jtag(context)
+        self.0.waits.wait(
+            timer_delay::now_us(),
+            wait_retries(),
+            crate::setup::WAIT_TIMEOUT_MS * 1000,
+        )
     }
 
-    fn release(self) -> Context {
-        self.0
+    /// Retries the connect with SWDIO and SWCLK swapped, the most common wiring mistake, after a
+    /// DPIDR read got no valid ACK. The lines are swapped back afterwards either way, a swap is
+    /// only reported to the host.
+    fn check_swapped_wiring(&mut self) {
+        core::mem::swap(&mut self.0.swdio, &mut self.0.swclk);
+        self.0.swdio_driven = None;
+        self.0.send_connect_sequence();
+        self.0.drive_swdio();
+        self.0.swclk.into_push_pull_output();
+
+        let swapped = self
+            .read_once(swd::APnDP::DP, swd::DPRegister::DPIDR)
+            .is_ok();
+        self.idle_clock();
+
+        core::mem::swap(&mut self.0.swdio, &mut self.0.swclk);
+        self.0.swdio_driven = None;
+        self.0.drive_swdio();
+        self.0.swclk.into_push_pull_output();
+
+        if swapped {
+            error!("Target answers with SWDIO and SWCLK swapped, check the wiring");
+        }
+        WIRING_SWAPPED.store(swapped, Ordering::Relaxed);
     }
 
-    fn sequences(&mut self, _data: &[u8], _rxbuf: &mut [u8]) -> u32 {
-        0
-    }
+    fn write_once(&mut self, apndp: swd::APnDP, a: swd::DPRegister, data: u32) -> swd::Result<()> {
+        // Send request
+        let req = swd::make_request(apndp, swd::RnW::W, a);
+        if let Some(result) = self.pio_transfer(req, Some(data)) {
+            return match result {
+                Err(e) if self.sticky_overrun(&e) => Ok(()),
+                r => r.map(|_| ()),
+            };
+        }
+        self.tx8(req);
 
-    fn set_clock(&mut self, max_frequency: u32) -> bool {
-        self.0.set_clock(max_frequency)
-    }
-}
+        // Read ack, turnaround and 3 bits for ACK
+        let ack = self.read_ack();
+        match swd::Ack::try_ok(ack) {
+            Ok(_) => trace!("    ack ok"),
+            // The data phase follows anyway, the target flags the failed write in CTRL/STAT
+            Err(e) if self.sticky_overrun(&e) => trace!("    ack err: {}, sticky overrun", e),
+            Err(e) => {
+                trace!("    ack err: {}", e);
+                self.recover(false, &e);
+                return Err(e);
+            }
+        }
 
-#[derive(Debug, defmt::Format)]
-pub struct Swd(Context);
+        // Turnaround before taking over the bus
+        let mut last = self.0.delay.get_current();
+        self.turnaround(&mut last);
 
-impl swd::Swd<Context> for Swd {
-    const AVAILABLE: bool = true;
+        // Send data and parity
+        let parity = data.count_ones() & 1 == 1;
+        self.send_data(data, parity);
 
-    fn new(mut context: Context) -> Self {
-        trace!("Creating SWD");
-        context.swdio.into_push_pull_output();
-        context.swclk.into_push_pull_output();
+        // Send trailing idle
+        self.idle_low(self.idle_cycles());
 
-        Self(context)
+        Ok(())
     }
 
-    fn release(mut self) -> Context {
-        trace!("Releasing SWD");
-        self.0.swclk.into_floating_input();
-        self.0.swdio.into_floating_input();
+    /// Writes the SWDv2 TARGETSEL register, which shares address 0xC with the read-only
+    /// RDBUFF. While the targets on a multi-drop bus are not selected none of them drives the
+    /// ACK, so the ACK cycles are clocked with SWDIO released and the data is sent regardless.
+    /// A host selects a target with a DP write to 0xC after the dormant wakeup and line reset.
+    fn write_targetsel(&mut self, data: u32) {
+        trace!("    TARGETSEL = 0x{:x}", data);
+        let req = swd::make_request(swd::APnDP::DP, swd::RnW::W, swd::DPRegister::RDBUFF);
+        self.tx8(req);
 
-        self.0
-    }
+        // Turnaround and the undriven ACK
+        self.read_ack();
 
-    fn configure(&mut self, period: swd::TurnaroundPeriod, data_phase: swd::DataPhase) -> bool {
-        trace!("SWD configure");
-        period == swd::TurnaroundPeriod::Cycles1 && data_phase == swd::DataPhase::NoDataPhase
+        // Turnaround before taking over the bus
+        let mut last = self.0.delay.get_current();
+        self.turnaround(&mut last);
+
+        let parity = data.count_ones() & 1 == 1;
+        self.send_data(data, parity);
+        self.idle_low(self.idle_cycles());
     }
 
-    fn read_inner(&mut self, apndp: swd::APnDP, a: swd::DPRegister) -> swd::Result<u32> {
-        trace!("SWD read, apndp: {}, addr: {}", apndp, a,);
+    fn read_once(&mut self, apndp: swd::APnDP, a: swd::DPRegister) -> swd::Result<u32> {
         // Send request
         let req = swd::make_request(apndp, swd::RnW::R, a);
+        if let Some(result) = self.pio_transfer(req, None) {
+            return result;
+        }
         self.tx8(req);
 
-        // Read ack, 1 clock for turnaround and 3 for ACK
-        let ack = self.rx4() >> 1;
+        // Read ack, turnaround and 3 bits for ACK
+        let ack = self.read_ack();
 
-        match swd::Ack::try_ok(ack as u8) {
+        match swd::Ack::try_ok(ack) {
             Ok(_) => trace!("    ack ok"),
             Err(e) => {
                 trace!("    ack error: {}", e);
-                // On non-OK ACK, target has released the bus but
-                // is still expecting a turnaround clock before
-                // the next request, and we need to take over the bus.
-                self.tx8(0);
+                self.recover(true, &e);
                 return Err(e);
             }
         }
 
         // Read data and parity
-        let (data, parity) = self.read_data();
+        let (data, parity_ok) = self.read_data();
 
         // Turnaround + trailing
         let mut last = self.0.delay.get_current();
-        self.read_bit(&mut last);
-        self.tx8(0); // Drive the SWDIO line to 0 to not float
+        self.turnaround(&mut last);
+        self.idle_low(self.idle_cycles()); // Drive the SWDIO line to 0 to not float
 
-        if parity as u8 == (data.count_ones() as u8 & 1) {
+        if parity_ok {
             trace!("    data: 0x{:x}", data);
             Ok(data)
         } else {
@@ -264,51 +1887,82 @@ impl swd::Swd<Context> for Swd {
         }
     }
 
-    fn write_inner(&mut self, apndp: swd::APnDP, a: swd::DPRegister, data: u32) -> swd::Result<()> {
-        trace!(
-            "SWD write, apndp: {}, addr: {}, data: 0x{:x}",
-            apndp,
-            a,
-            data
+    /// Runs the whole transfer on the PIO engine if there is one, then leaves SWDIO driven like
+    /// the bit-banged transfer does. The ACK latency is not measured on this path.
+    fn pio_transfer(&mut self, req: u8, data: Option<u32>) -> Option<swd::Result<u32>> {
+        let timing = SwdTiming {
+            idle_cycles: self.idle_cycles(),
+            ..self.0.timing
+        };
+        let (swdio, swclk) = (self.0.swdio.id().num, self.0.swclk.id().num);
+        let result = self.0.pio.as_mut()?.transfer(
+            swdio,
+            swclk,
+            req,
+            data,
+            &timing,
+            ack_gap(),
+            self.0.data_phase,
         );
 
-        // Send request
-        let req = swd::make_request(apndp, swd::RnW::W, a);
-        self.tx8(req);
+        // The PIO took the pin over, the pin driver has to set it up again
+        self.0.swdio_driven = None;
+        self.0.drive_swdio();
+        let level = swdio_level_after(&result, fault_level());
+        self.0.swdio.set_state(level).ok();
 
-        // Read ack, 1 clock for turnaround and 3 for ACK and 1 for turnaround
-        let ack = (self.rx5() >> 1) & 0b111;
-        match swd::Ack::try_ok(ack as u8) {
-            Ok(_) => trace!("    ack ok"),
-            Err(e) => {
-                trace!("    ack err: {}", e);
-                // On non-OK ACK, target has released the bus but
-                // is still expecting a turnaround clock before
-                // the next request, and we need to take over the bus.
-                self.tx8(0);
-                return Err(e);
-            }
+        Some(result)
+    }
+
+    /// On non-OK ACK, target has released the bus but is still expecting a
+    /// turnaround clock before the next request, and we need to take over the bus.
+    ///
+    /// With the data phase configured the 33 data and parity cycles still follow a WAIT or
+    /// FAULT: with SWDIO released on a read, driven low on a write.
+    fn recover(&mut self, read: bool, error: &swd::Error) {
+        let data_phase =
+            self.0.data_phase && matches!(error, swd::Error::AckWait | swd::Error::AckFault);
+
+        if data_phase && read {
+            self.read_data();
         }
 
-        // Send data and parity
-        let parity = data.count_ones() & 1 == 1;
-        self.send_data(data, parity);
+        let mut last = self.0.delay.get_current();
+        self.turnaround(&mut last);
 
-        // Send trailing idle
-        self.tx8(0);
+        if data_phase && !read {
+            self.send_data(0, false);
+        }
+        self.idle_low(self.0.timing.trailing_idle);
 
-        Ok(())
+        // Keep driving SWDIO so it does not pick up noise before the next transfer
+        self.0.swdio.set_state(fault_level()).ok();
     }
 
-    fn set_clock(&mut self, max_frequency: u32) -> bool {
-        trace!("SWD set clock: freq = {}", max_frequency);
-        self.0.set_clock(max_frequency)
+    /// With overrun detection enabled in CTRL/STAT and the data phase configured with
+    /// `DAP_SWD_Configure`, a write answered with WAIT or FAULT sets the target's STICKYORUN
+    /// and every following transfer is ignored until the host clears it with ABORT. Writes are
+    /// then pipelined without looking at the ACK, the host checks CTRL/STAT after a batch
+    /// instead of the probe stopping and retrying each write. Reads still report their ACK,
+    /// their data is not valid without an OK.
+    fn sticky_overrun(&self, error: &swd::Error) -> bool {
+        self.0.data_phase
+            && self.0.overrun_detect
+            && matches!(error, swd::Error::AckWait | swd::Error::AckFault)
+    }
+
+    /// Idle cycles after a completed transfer, the profile's unless configured otherwise.
+    fn idle_cycles(&self) -> u8 {
+        transfer_idle_cycles(idle_cycles(), self.0.timing.idle_cycles)
+    }
+
+    /// Park SWCLK at the configured idle level between transfers.
+    fn idle_clock(&mut self) {
+        self.0.swclk.set_state(swclk_idle_level()).ok();
     }
-}
 
-impl Swd {
     fn tx8(&mut self, mut data: u8) {
-        self.0.swdio.into_push_pull_output();
+        self.0.drive_swdio();
 
         let mut last = self.0.delay.get_current();
 
@@ -318,35 +1972,58 @@ impl Swd {
         }
     }
 
-    fn rx4(&mut self) -> u8 {
-        self.0.swdio.into_floating_input();
+    fn read_ack(&mut self) -> u8 {
+        self.0.swdio_input();
 
-        let mut data = 0;
         let mut last = self.0.delay.get_current();
+        #[cfg(feature = "diagnostics")]
+        let request_end = last;
+        self.turnaround(&mut last);
 
-        for i in 0..4 {
-            data |= (self.read_bit(&mut last) & 1) << i;
+        for _ in 0..ack_gap() {
+            self.read_bit(&mut last);
         }
 
-        data
-    }
+        #[cfg(feature = "diagnostics")]
+        let ack_start = last;
+        let mut ack = 0;
 
-    fn rx5(&mut self) -> u8 {
-        self.0.swdio.into_floating_input();
+        for i in 0..3 {
+            ack |= (self.read_bit(&mut last) & 1) << i;
+        }
 
-        let mut last = self.0.delay.get_current();
+        #[cfg(feature = "diagnostics")]
+        if matches!(ack, 0b001 | 0b010 | 0b100) {
+            // Measured with SysTick as the M0+ has no DWT cycle counter. It counts down, and the
+            // first ACK bit is sampled half a period after `ack_start`
+            let ticks = request_end.wrapping_sub(ack_start) & 0xff_ffff;
+            let ticks = (ticks + self.0.half_period_ticks + self.0.edge_overhead_ticks) as u64;
+            // In u64, at low SWCLK rates the half period alone overflows u32 in nanoseconds
+            let ns = ticks * 1000 / self.0.cycles_per_us as u64;
+            stats::record_ack_latency(ns.min(u32::MAX as u64) as u32);
+        }
 
-        let mut data = 0;
+        ack
+    }
 
-        for i in 0..5 {
-            data |= (self.read_bit(&mut last) & 1) << i;
+    fn turnaround(&mut self, last: &mut u32) {
+        for _ in 0..self.0.timing.turnaround {
+            self.read_bit(last);
         }
+    }
+
+    fn idle_low(&mut self, cycles: u8) {
+        self.0.drive_swdio();
+
+        let mut last = self.0.delay.get_current();
 
-        data
+        for _ in 0..cycles {
+            self.write_bit(0, &mut last);
+        }
     }
 
     fn send_data(&mut self, mut data: u32, parity: bool) {
-        self.0.swdio.into_push_pull_output();
+        self.0.drive_swdio();
 
         let mut last = self.0.delay.get_current();
 
@@ -358,20 +2035,25 @@ impl Swd {
         self.write_bit(parity as u8, &mut last);
     }
 
+    /// Reads the data and parity, returns the data and whether the parity matched. The parity
+    /// is accumulated while sampling, so the word is not walked a second time.
     fn read_data(&mut self) -> (u32, bool) {
-        self.0.swdio.into_floating_input();
+        self.0.swdio_input();
 
         let mut data = 0;
+        let mut parity = 0;
 
         let mut last = self.0.delay.get_current();
 
         for i in 0..32 {
-            data |= (self.read_bit(&mut last) as u32 & 1) << i;
+            let bit = self.read_bit(&mut last) & 1;
+            parity ^= bit;
+            data |= (bit as u32) << i;
         }
 
-        let parity = self.read_bit(&mut last) != 0;
+        parity ^= self.read_bit(&mut last) & 1;
 
-        (data, parity)
+        (data, parity == 0)
     }
 
     #[inline(always)]
@@ -404,9 +2086,15 @@ impl Swd {
     }
 }
 
+#[cfg(feature = "swo")]
+pub use crate::swo::Swo;
+
+/// Without the `swo` feature there is no capture, the handler gets `None`.
+#[cfg(not(feature = "swo"))]
 #[derive(Debug, defmt::Format)]
 pub struct Swo {}
 
+#[cfg(not(feature = "swo"))]
 impl swo::Swo for Swo {
     fn set_transport(&mut self, _transport: swo::SwoTransport) {}
 
@@ -453,19 +2141,23 @@ impl swo::Swo for Swo {
     }
 }
 
+/// The waits the DAP handler runs for the host, such as `DAP_Delay`, timed by the TIMER through
+/// [`timer_delay`](crate::timer_delay).
 pub struct Wait {
-    delay: &'static Delay,
+    _delay: &'static Delay,
 }
 
 impl Wait {
+    /// The SysTick delay is not used for these waits, it is only taken to keep the constructor
+    /// unchanged.
     pub fn new(delay: &'static Delay) -> Self {
-        Wait { delay }
+        Wait { _delay: delay }
     }
 }
 
 impl DelayUs<u32> for Wait {
     fn delay_us(&mut self, us: u32) {
-        self.delay.delay_us(us);
+        timer_delay::delay_us(us);
     }
 }
 
@@ -476,14 +2168,675 @@ pub fn create_dap(
     swclk: DynPin,
     nreset: DynPin,
     cpu_frequency: u32,
+    default_frequency: u32,
+    max_frequency: u32,
+    buffered_pins: Option<PinSet>,
+    jtag_pins: Option<JtagPins>,
+    pio: Option<PioSwd>,
+    reset_active: Option<DynPin>,
+    leds: Leds,
+    swo: Option<Swo>,
+    profile: ConnectProfile,
+    sequence: Option<ConnectSequence>,
+    high_z_until_connect: bool,
     delay: &'static Delay,
 ) -> dap::Dap<'static, Context, Leds, Wait, Jtag, Swd, Swo> {
-    let context = Context::from_pins(swdio, swclk, nreset, cpu_frequency, delay);
-    let leds = Leds {};
+    let mut context = Context::from_pins(swdio, swclk, nreset, cpu_frequency, profile, delay);
+    context.set_default_frequency(default_frequency);
+    context.set_max_frequency(max_frequency);
+    context.set_reset_output(reset_active);
+    if let Some(sequence) = sequence {
+        context.set_connect_sequence(sequence);
+    }
+    if high_z_until_connect {
+        context.hold_bus_until_connect();
+    }
+    if let Some(pins) = buffered_pins {
+        context.set_buffered_pins(pins);
+    }
+    if let Some(pins) = jtag_pins {
+        context.set_jtag_pins(pins);
+    }
+    if let Some(pio) = pio {
+        context.set_pio(pio);
+    }
     let wait = Wait::new(delay);
-    let swo = None;
 
     defmt::info!("Making dap interface with context: {}", context);
 
     dap::Dap::from_parts(context, leds, wait, swo, version_string)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCES: [ConnectSequence; 4] = [
+        ConnectSequence::SwitchBeforeReset,
+        ConnectSequence::ResetBeforeSwitch,
+        ConnectSequence::DormantWakeup,
+        ConnectSequence::ViaDormant,
+    ];
+
+    #[test]
+    fn connect_sequences_start_with_a_line_reset_and_end_idle() {
+        for sequence in SEQUENCES {
+            let steps = sequence.steps();
+            assert_eq!(
+                steps.first(),
+                Some(&ConnectStep::LineReset),
+                "{:?}",
+                sequence
+            );
+            assert_eq!(steps.last(), Some(&ConnectStep::Idle), "{:?}", sequence);
+        }
+    }
+
+    #[test]
+    fn swd_to_dormant_follows_a_line_reset() {
+        for sequence in SEQUENCES {
+            for pair in sequence.steps().windows(2) {
+                if pair[1] == ConnectStep::SwdToDormant {
+                    assert_eq!(pair[0], ConnectStep::LineReset, "{:?}", sequence);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn connect_sequences_fit_the_buffer() {
+        for sequence in SEQUENCES {
+            let mut total = 0;
+            for step in sequence.steps() {
+                let (data, bits) = step.bits();
+                assert_eq!(data.len(), (bits + 7) / 8, "{:?}", step);
+                total += bits;
+            }
+            assert!(total <= MAX_CONNECT_BITS, "{:?}", sequence);
+        }
+    }
+
+    /// Reads `len` bits from `data` at `start`, LSB first.
+    fn field(data: &[u8], start: usize, len: usize) -> u128 {
+        (0..len).fold(0, |value, n| {
+            let bit = start + n;
+            value | ((((data[bit / 8] >> (bit % 8)) & 1) as u128) << n)
+        })
+    }
+
+    #[test]
+    fn select_codes() {
+        let (data, bits) = ConnectStep::JtagToSwd.bits();
+        assert_eq!((field(data, 0, bits), bits), (0xe79e, 16));
+
+        let (data, bits) = ConnectStep::SwdToDormant.bits();
+        assert_eq!((field(data, 0, bits), bits), (0xe3bc, 16));
+
+        // 8 clocks with TMS high, then the 31 bit code
+        let (data, bits) = ConnectStep::JtagToDormant.bits();
+        assert_eq!(field(data, 0, 8), 0xff);
+        assert_eq!((field(data, 8, bits - 8), bits), (0x33bb_bbba, 39));
+    }
+
+    #[test]
+    fn dormant_to_swd() {
+        let (data, bits) = ConnectStep::DormantToSwd.bits();
+        assert_eq!(bits, 8 + 128 + 4 + 8);
+        assert_eq!(field(data, 0, 8), 0xff);
+        // Selection alert
+        assert_eq!(
+            field(data, 8, 128),
+            0x19bc_0ea2_e3dd_afe9_8685_2d95_6209_f392
+        );
+        assert_eq!(field(data, 136, 4), 0);
+        // SWD activation code
+        assert_eq!(field(data, 140, 8), 0x1a);
+    }
+
+    #[test]
+    fn default_connect_switches_jtag_to_swd() {
+        let sequence = ConnectProfile::Standard.sequence();
+        let mut buffer = [0; MAX_CONNECT_BITS / 8];
+        let bits = sequence.pack(&mut buffer);
+
+        // Line reset, JTAG-to-SWD select code, line reset and idle cycles before the DPIDR read
+        assert_eq!(bits, 56 + 16 + 56 + 8);
+        assert_eq!(field(&buffer, 0, 56), (1 << 56) - 1);
+        assert_eq!(field(&buffer, 56, 16), 0xe79e);
+        assert_eq!(field(&buffer, 72, 56), (1 << 56) - 1);
+        assert_eq!(field(&buffer, 128, 8), 0);
+    }
+
+    #[test]
+    fn sequence_bits_within_the_data() {
+        // The 51 clock line reset in 7 bytes
+        assert_eq!(sequence_bits(51, 7), 51);
+        assert_eq!(sequence_bits(16, 2), 16);
+        assert_eq!(sequence_bits(0, 0), 0);
+    }
+
+    #[test]
+    fn sequence_bits_beyond_the_data() {
+        assert_eq!(sequence_bits(17, 2), 16);
+        assert_eq!(sequence_bits(64, 1), 8);
+        // The longest `DAP_SWJ_Sequence`, with a truncated request
+        assert_eq!(sequence_bits(256, 3), 24);
+        assert_eq!(sequence_bits(256, 0), 0);
+    }
+
+    #[test]
+    fn line_reset_then_jtag_to_swd() {
+        // The 51 clock line reset, then the 16 bit select code, LSB first
+        let mut levels = sequence_levels(&[0xff; 7], 51).collect::<Vec<_>>();
+        levels.extend(sequence_levels(&JTAG_TO_SWD, 16));
+        assert_eq!(levels.len(), 67);
+        assert!(levels[..51].iter().all(|&high| high));
+        let code = levels[51..]
+            .iter()
+            .enumerate()
+            .fold(0u16, |code, (n, &high)| code | (u16::from(high) << n));
+        assert_eq!(code, 0xe79e);
+    }
+
+    #[test]
+    fn sequence_levels_stop_at_the_data() {
+        assert_eq!(
+            sequence_levels(&[0b101], 3).collect::<Vec<_>>(),
+            [true, false, true]
+        );
+        assert_eq!(sequence_levels(&[0xff], 12).count(), 8);
+        // Without data only SWCLK is clocked
+        assert_eq!(sequence_levels(&[], 51).count(), 0);
+    }
+
+    const TRANSFER_ERRORS: [TransferError; 7] = [
+        TransferError::None,
+        TransferError::Wait,
+        TransferError::Fault,
+        TransferError::NoAck,
+        TransferError::Parity,
+        TransferError::Unpowered,
+        TransferError::WaitTimeout,
+    ];
+
+    #[test]
+    fn transfer_error_survives_the_atomic() {
+        for error in TRANSFER_ERRORS {
+            assert_eq!(TransferError::from_u8(error as u8), error);
+        }
+        assert_eq!(TransferError::from_u8(0xff), TransferError::None);
+    }
+
+    #[test]
+    fn transfer_error_status() {
+        let status: [u8; 7] = TRANSFER_ERRORS.map(TransferError::status);
+        assert_eq!(status, [0x01, 0x02, 0x04, 0x07, 0x09, 0x07, 0x04]);
+    }
+
+    #[test]
+    fn connect_under_reset_honors_the_windows() {
+        let windows = ResetWindows {
+            assert_us: 5_000,
+            release_delay_us: 200,
+            settle_us: 20_000,
+        };
+        assert_eq!(
+            windows.before_connect(),
+            [ResetStep::Assert, ResetStep::Wait(5_000)]
+        );
+        // Still in reset when the DPIDR read is done
+        assert_eq!(
+            windows.after_connect(),
+            [
+                ResetStep::Wait(200),
+                ResetStep::Release,
+                ResetStep::Wait(20_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_windows_are_clamped() {
+        let windows = ResetWindows {
+            assert_us: u32::MAX,
+            release_delay_us: ResetWindows::MAX_US,
+            settle_us: 0,
+        };
+        assert_eq!(
+            windows.clamped(),
+            ResetWindows {
+                assert_us: ResetWindows::MAX_US,
+                release_delay_us: ResetWindows::MAX_US,
+                settle_us: 0,
+            }
+        );
+        assert_eq!(ResetWindows::DEFAULT.clamped(), ResetWindows::DEFAULT);
+    }
+
+    #[test]
+    fn profiles_carry_their_timing() {
+        for profile in [ConnectProfile::Standard, ConnectProfile::Rp2040Multidrop] {
+            assert_eq!(profile.timing_with(None, None), profile.timing());
+        }
+        // Deselected multi-drop targets take longer to release the bus, and their parity
+        // errors on DP reads are retried
+        let multidrop = ConnectProfile::Rp2040Multidrop.timing_with(None, None);
+        assert_eq!((multidrop.trailing_idle, multidrop.parity_retries), (16, 2));
+    }
+
+    #[test]
+    fn host_turnaround_wins_over_the_pin_set() {
+        let profile = ConnectProfile::Rp2040Multidrop;
+        let turnaround = |host, pin_set| profile.timing_with(host, pin_set).turnaround;
+        assert_eq!(turnaround(None, Some(2)), 2);
+        assert_eq!(turnaround(Some(4), Some(2)), 4);
+        assert_eq!(turnaround(Some(3), None), 3);
+
+        // Only the turnaround is overridden
+        let timing = profile.timing_with(Some(4), None);
+        assert_eq!(
+            SwdTiming {
+                turnaround: 1,
+                ..timing
+            },
+            profile.timing()
+        );
+    }
+
+    #[test]
+    fn swdio_is_driven_at_the_fault_level_after_a_fault() {
+        for fault_level in [PinState::Low, PinState::High] {
+            let level = |result: swd::Result<u32>| swdio_level_after(&result, fault_level);
+            assert_eq!(level(Err(swd::Error::AckFault)), fault_level);
+            assert_eq!(level(Err(swd::Error::AckWait)), fault_level);
+            assert_eq!(level(Err(swd::Error::AckProtocol)), fault_level);
+
+            // The ACK was OK, the idle cycles drove SWDIO low
+            assert_eq!(level(Ok(0)), PinState::Low);
+            assert_eq!(level(Err(swd::Error::BadParity)), PinState::Low);
+        }
+    }
+
+    #[test]
+    fn configured_idle_cycles_replace_the_profile() {
+        let profile = ConnectProfile::Standard.timing().idle_cycles;
+        assert_eq!(transfer_idle_cycles(PROFILE_IDLE_CYCLES, profile), profile);
+        for cycles in [0, 1, 8, MAX_IDLE_CYCLES] {
+            assert_eq!(transfer_idle_cycles(cycles, profile), cycles);
+        }
+    }
+
+    #[test]
+    fn every_turnaround_period_is_accepted() {
+        let periods = [
+            swd::TurnaroundPeriod::Cycles1,
+            swd::TurnaroundPeriod::Cycles2,
+            swd::TurnaroundPeriod::Cycles3,
+            swd::TurnaroundPeriod::Cycles4,
+        ];
+        assert_eq!(periods.map(turnaround_cycles), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn only_a_missing_ack_hints_at_swapped_wiring() {
+        let no_ack = TransferError::NoAck;
+        assert!(hints_at_swapped_wiring(&swd::Error::AckProtocol, no_ack));
+        assert!(hints_at_swapped_wiring(
+            &swd::Error::AckUnknown(0b101),
+            no_ack
+        ));
+
+        // The target answered, the wiring is fine
+        assert!(!hints_at_swapped_wiring(
+            &swd::Error::AckWait,
+            TransferError::Wait
+        ));
+        assert!(!hints_at_swapped_wiring(
+            &swd::Error::AckFault,
+            TransferError::Fault
+        ));
+        assert!(!hints_at_swapped_wiring(
+            &swd::Error::BadParity,
+            TransferError::Parity
+        ));
+
+        // Refused without clocking the bus
+        let unpowered = TransferError::Unpowered;
+        assert!(!hints_at_swapped_wiring(
+            &swd::Error::AckProtocol,
+            unpowered
+        ));
+    }
+
+    /// An edge overhead for the tests, what the wait loop takes at `opt-level = 's'` is about
+    /// this much.
+    const OVERHEAD_TICKS: u32 = 4;
+
+    #[test]
+    fn edge_overhead_is_rounded_down() {
+        assert_eq!(edge_overhead(1024 * 16, 1024, 16), 0);
+        // 4.5 ticks per half period
+        assert_eq!(edge_overhead(1024 * 41 / 2, 1024, 16), 4);
+        assert_eq!(edge_overhead(1024 * 21 - 1, 1024, 16), 4);
+        assert_eq!(edge_overhead(1024 * 21, 1024, 16), 5);
+        // Can't be faster than the waits, but stays sane if SysTick reads inconsistently
+        assert_eq!(edge_overhead(0, 1024, 16), 0);
+    }
+
+    #[test]
+    fn half_period_leaves_out_the_edge_overhead() {
+        assert_eq!(
+            half_period_ticks(125_000_000, 100_000, OVERHEAD_TICKS),
+            625 - OVERHEAD_TICKS
+        );
+        assert_eq!(
+            effective_frequency(125_000_000, 100_000, OVERHEAD_TICKS),
+            100_000
+        );
+        // 62.5 ticks per half period, rounded down
+        assert_eq!(
+            half_period_ticks(125_000_000, 1_000_000, OVERHEAD_TICKS),
+            62 - OVERHEAD_TICKS
+        );
+        assert_eq!(
+            effective_frequency(125_000_000, 1_000_000, OVERHEAD_TICKS),
+            1_008_064
+        );
+        // Without an overhead only the rounding is left
+        assert_eq!(half_period_ticks(125_000_000, 1_000_000, 0), 62);
+        assert_eq!(effective_frequency(125_000_000, 1_000_000, 0), 1_008_064);
+    }
+
+    #[test]
+    fn half_period_is_at_least_one_tick() {
+        assert_eq!(
+            half_period_ticks(125_000_000, 62_500_000, OVERHEAD_TICKS),
+            1
+        );
+        assert_eq!(half_period_ticks(125_000_000, u32::MAX, OVERHEAD_TICKS), 1);
+        assert_eq!(
+            effective_frequency(125_000_000, u32::MAX, OVERHEAD_TICKS),
+            12_500_000
+        );
+    }
+
+    #[test]
+    fn lowest_frequency() {
+        assert_eq!(
+            half_period_ticks(125_000_000, 1, OVERHEAD_TICKS),
+            62_500_000 - OVERHEAD_TICKS
+        );
+        assert_eq!(effective_frequency(125_000_000, 1, OVERHEAD_TICKS), 1);
+    }
+
+    const WAIT_TIMEOUT_US: u32 = crate::setup::WAIT_TIMEOUT_MS * 1000;
+
+    #[test]
+    fn continuous_wait_fails_after_the_retries() {
+        let mut run = WaitRun::default();
+        // 3 WAITs in a row are allowed, the fourth fails the transfer
+        for now in 0..3 {
+            assert!(!run.wait(now * 10, 3, WAIT_TIMEOUT_US));
+        }
+        assert!(run.wait(30, 3, WAIT_TIMEOUT_US));
+
+        // Which starts a new run
+        assert!(!run.wait(40, 3, WAIT_TIMEOUT_US));
+    }
+
+    #[test]
+    fn continuous_wait_fails_after_the_timeout() {
+        // Until the host configures the wait retries only the timeout applies, the count
+        // saturates instead of wrapping around
+        let mut run = WaitRun::default();
+        for _ in 0..70_000 {
+            assert!(!run.wait(0, u16::MAX, WAIT_TIMEOUT_US));
+        }
+        assert!(!run.wait(WAIT_TIMEOUT_US - 1, u16::MAX, WAIT_TIMEOUT_US));
+        assert!(run.wait(WAIT_TIMEOUT_US, u16::MAX, WAIT_TIMEOUT_US));
+    }
+
+    #[test]
+    fn continuous_wait_across_the_timer_wrap() {
+        let mut run = WaitRun::default();
+        assert!(!run.wait(u32::MAX - 10, u16::MAX, WAIT_TIMEOUT_US));
+        assert!(!run.wait(10, u16::MAX, WAIT_TIMEOUT_US));
+        assert!(run.wait(WAIT_TIMEOUT_US - 11, u16::MAX, WAIT_TIMEOUT_US));
+    }
+
+    #[test]
+    fn a_new_request_starts_a_new_run() {
+        // The DAP handler gave up after exactly the wait retries, the run ended on a WAIT
+        let mut run = WaitRun::default();
+        for _ in 0..3 {
+            assert!(!run.wait(0, 3, WAIT_TIMEOUT_US));
+        }
+
+        // Carried over, the first WAIT of a request a minute later would fail it at once
+        let mut stale = run;
+        assert!(stale.wait(60_000_000, 3, WAIT_TIMEOUT_US));
+
+        run.end();
+        assert!(!run.wait(60_000_000, 3, WAIT_TIMEOUT_US));
+    }
+
+    #[test]
+    fn retry_policy_selects_the_errors() {
+        for mask in 0..0x10 {
+            let policy = RetryPolicy::new(mask, 3, 0);
+            for error in TRANSFER_ERRORS {
+                let expected = match error {
+                    TransferError::Wait => mask & RETRY_WAIT != 0,
+                    TransferError::Fault => mask & RETRY_FAULT != 0,
+                    TransferError::NoAck => mask & RETRY_NO_ACK != 0,
+                    TransferError::Parity => mask & RETRY_PARITY != 0,
+                    _ => false,
+                };
+                let limit = policy.retry_limit(error, swd::APnDP::DP, false);
+                assert_eq!(
+                    limit,
+                    if expected { 3 } else { 0 },
+                    "{:?} with mask 0x{:x}",
+                    error,
+                    mask
+                );
+            }
+        }
+
+        let policy = RetryPolicy::new(0xff, 0xff, 0);
+        assert_eq!((policy.mask, policy.count), (0x0f, MAX_RETRIES));
+    }
+
+    /// A target answering the attempts of a transfer from a script, the last answer repeats.
+    struct Scripted {
+        answers: Vec<TransferError>,
+        attempts: usize,
+        waits: WaitRun,
+    }
+
+    impl Scripted {
+        fn new(answers: &[TransferError]) -> Self {
+            Scripted {
+                answers: answers.to_vec(),
+                attempts: 0,
+                waits: WaitRun::default(),
+            }
+        }
+
+        fn attempt(&mut self) -> swd::Result<u32> {
+            let answer = self.answers[self.attempts.min(self.answers.len() - 1)];
+            self.attempts += 1;
+
+            match answer {
+                TransferError::None => Ok(0),
+                TransferError::Wait => Err(swd::Error::AckWait),
+                TransferError::Fault => Err(swd::Error::AckFault),
+                TransferError::NoAck => Err(swd::Error::AckProtocol),
+                TransferError::Parity => Err(swd::Error::BadParity),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Runs a transfer against `answers`, returns how it ended and after how many attempts.
+    fn retried(
+        policy: RetryPolicy,
+        apndp: swd::APnDP,
+        read: bool,
+        answers: &[TransferError],
+    ) -> (TransferError, usize) {
+        let mut target = Scripted::new(answers);
+        let result = retry_transfer(
+            &mut target,
+            policy,
+            apndp,
+            read,
+            |_, _| false,
+            Scripted::attempt,
+        )
+        .unwrap();
+        let error = result
+            .err()
+            .map_or(TransferError::None, |e| TransferError::from_error(&e));
+        (error, target.attempts)
+    }
+
+    #[test]
+    fn a_parity_error_on_an_ap_read_is_never_retried() {
+        use TransferError::{None, Parity};
+
+        let policy = RetryPolicy::new(RETRY_PARITY, MAX_RETRIES, 3);
+        let answers = [Parity, None];
+        assert_eq!(retried(policy, swd::APnDP::AP, true, &answers), (Parity, 1));
+        assert_eq!(retried(policy, swd::APnDP::AP, false, &answers), (None, 2));
+        assert_eq!(retried(policy, swd::APnDP::DP, true, &answers), (None, 2));
+    }
+
+    #[test]
+    fn profile_parity_retries_apply_without_retry_parity() {
+        use TransferError::{None, Parity};
+
+        let policy = RetryPolicy::new(RETRY_WAIT, 5, 2);
+        let read = |answers: &[TransferError]| retried(policy, swd::APnDP::DP, true, answers);
+        assert_eq!(read(&[Parity]), (Parity, 3));
+        assert_eq!(read(&[Parity, Parity, None]), (None, 3));
+
+        // With RETRY_PARITY the retry count applies instead
+        let policy = RetryPolicy::new(RETRY_WAIT | RETRY_PARITY, 5, 2);
+        assert_eq!(
+            retried(policy, swd::APnDP::DP, true, &[Parity]),
+            (Parity, 6)
+        );
+    }
+
+    #[test]
+    fn retry_count_is_respected() {
+        let all = RETRY_WAIT | RETRY_FAULT | RETRY_NO_ACK | RETRY_PARITY;
+
+        for count in [0, 1, 3, MAX_RETRIES] {
+            let policy = RetryPolicy::new(all, count, 0);
+            for error in [
+                TransferError::Wait,
+                TransferError::Fault,
+                TransferError::NoAck,
+                TransferError::Parity,
+            ] {
+                let attempts = usize::from(count) + 1;
+                assert_eq!(
+                    retried(policy, swd::APnDP::DP, false, &[error]),
+                    (error, attempts),
+                    "{:?} with count {}",
+                    error,
+                    count
+                );
+
+                // Succeeding on the last retry
+                let mut answers = vec![error; usize::from(count)];
+                answers.push(TransferError::None);
+                assert_eq!(
+                    retried(policy, swd::APnDP::DP, false, &answers),
+                    (TransferError::None, attempts),
+                    "{:?} with count {}",
+                    error,
+                    count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn each_mask_retries_only_its_errors() {
+        let policy = |mask| RetryPolicy::new(mask, 2, 0);
+
+        for mask in 0..0x10 {
+            for error in [
+                TransferError::Wait,
+                TransferError::Fault,
+                TransferError::NoAck,
+                TransferError::Parity,
+            ] {
+                let attempts = if mask & error.retry_bit() != 0 { 3 } else { 1 };
+                assert_eq!(
+                    retried(policy(mask), swd::APnDP::AP, false, &[error]),
+                    (error, attempts),
+                    "{:?} with mask 0x{:x}",
+                    error,
+                    mask
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_wait_run_ends_the_retries() {
+        let policy = RetryPolicy::new(RETRY_WAIT, MAX_RETRIES, 0);
+        let mut target = Scripted::new(&[TransferError::Wait]);
+        let result = retry_transfer(
+            &mut target,
+            policy,
+            swd::APnDP::AP,
+            true,
+            |target, result| {
+                matches!(result, Err(swd::Error::AckWait))
+                    && target
+                        .waits
+                        .wait(100 * target.attempts as u32, 10, WAIT_TIMEOUT_US)
+            },
+            Scripted::attempt,
+        );
+        assert!(result.is_none());
+        assert_eq!(target.attempts, 11);
+    }
+
+    fn presets(limit: u32) -> Vec<u32> {
+        (0..SPEED_PRESETS.len())
+            .map(|index| {
+                preset_effective_frequency_at(125_000_000, limit, OVERHEAD_TICKS, index).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn speed_presets() {
+        // The fastest preset is capped like a `DAP_SWJ_Clock` request
+        let limit = crate::setup::MAX_SWCLK_FREQUENCY;
+        assert_eq!(
+            presets(limit),
+            [100_000, 500_000, 1_008_064, 2_016_129, 4_166_666]
+        );
+        assert_eq!(preset_frequency_at(125_000_000, 4), Some(62_500_000));
+        assert_eq!(preset_frequency_at(125_000_000, SPEED_PRESETS.len()), None);
+        assert_eq!(
+            preset_effective_frequency_at(125_000_000, limit, OVERHEAD_TICKS, SPEED_PRESETS.len()),
+            None
+        );
+    }
+
+    #[test]
+    fn speed_presets_within_the_pin_set_limit() {
+        assert_eq!(
+            presets(1_000_000),
+            [100_000, 500_000, 1_008_064, 1_008_064, 1_008_064]
+        );
+    }
+}
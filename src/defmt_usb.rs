@@ -0,0 +1,78 @@
+//! defmt global logger writing the probe's own log to a CDC-ACM port, with the `defmt-usb`
+//! feature, instead of RTT.
+//!
+//! The encoded frames go into [`LOG`], which the USB task writes to the port. Decode it on the
+//! host with `defmt-print -e <elf> < /dev/ttyACMx`. Until the host reads them the frames are
+//! kept, once [`LOG`] is full new frames are dropped, so the log of the boot survives. A frame
+//! that only partly fits is cut, the decoder resynchronizes on the next frame.
+
+use crate::ring_buffer::RingBuffer;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::{
+    interrupt,
+    peripheral::NVIC,
+    register::{self, primask::Primask},
+};
+use rp_pico::hal::pac::Interrupt;
+
+/// Size of the log buffer in bytes.
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Encoded log frames waiting for the host.
+pub static LOG: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+
+#[defmt::global_logger]
+struct Logger;
+
+/// A frame is being written, logging from inside the logger is a bug.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+/// The buffer was empty when the frame started, the USB task is woken up at its end.
+static WAKE: AtomicBool = AtomicBool::new(false);
+static mut PRIMASK: Primask = Primask::Inactive;
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+// NOTE(unsafe) frames are written with interrupts disabled, so only one context is in the
+// logger and it is the only producer of `LOG`
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let primask = register::primask::read();
+        interrupt::disable();
+
+        if TAKEN.load(Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly")
+        }
+        TAKEN.store(true, Ordering::Relaxed);
+        WAKE.store(LOG.is_empty(), Ordering::Relaxed);
+
+        unsafe {
+            PRIMASK = primask;
+            ENCODER.start_frame(do_write);
+        }
+    }
+
+    unsafe fn flush() {
+        // The USB task sends the frames, waiting here could dead-lock it
+    }
+
+    unsafe fn release() {
+        ENCODER.end_frame(do_write);
+        TAKEN.store(false, Ordering::Relaxed);
+
+        // Logging from the USB task itself only pends it once more
+        if WAKE.load(Ordering::Relaxed) {
+            NVIC::pend(Interrupt::USBCTRL_IRQ);
+        }
+
+        if PRIMASK.is_active() {
+            interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        ENCODER.write(bytes, do_write);
+    }
+}
+
+fn do_write(bytes: &[u8]) {
+    LOG.push_slice(bytes);
+}
@@ -0,0 +1,153 @@
+//! Probe settings persisted in the last flash sector.
+//!
+//! The sector is kept out of the image by `memory.x` and holds a single record, a magic word
+//! followed by the settings. An erased or foreign sector reads as no record, the compiled-in
+//! defaults apply then. Settings are only read at boot, a changed record takes effect on the
+//! next reset.
+//!
+//! Writing erases and reprograms the sector from RAM with interrupts disabled, which stalls the
+//! firmware for the erase time (tens of milliseconds). Core 1 must not be running from flash
+//! meanwhile, the firmware does not start it.
+
+use cortex_m::interrupt;
+use defmt::*;
+use rp_pico::hal::rom_data;
+
+const XIP_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2048 * 1024;
+const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: usize = 256;
+
+/// Offset of the config sector in flash.
+const OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+
+/// 4 KiB sector erase command of the flash.
+const SECTOR_ERASE_CMD: u8 = 0x20;
+
+const MAGIC: [u8; 4] = *b"PPCF";
+
+/// Record: `[magic[4], swdio, swclk, nreset, reserved, frequency[4]]`, unset fields are erased
+/// (`0xff`).
+const RECORD_LEN: usize = 12;
+
+const UNSET: u8 = 0xff;
+
+/// GPIOs of SWDIO, SWCLK and nRESET.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PinAssignment {
+    pub swdio: u8,
+    pub swclk: u8,
+    pub nreset: u8,
+}
+
+impl PinAssignment {
+    /// The wiring of the README.
+    pub const DEFAULT: PinAssignment = PinAssignment {
+        swdio: 14,
+        swclk: 15,
+        nreset: 13,
+    };
+
+    /// The GPIOs no optional feature claims, the debug pins can be moved to any of them.
+    pub const ASSIGNABLE: [u8; 10] = [6, 7, 8, 9, 12, 13, 14, 15, 20, 21];
+
+    /// Every pin assignable and no pin used twice.
+    pub fn is_valid(&self) -> bool {
+        [self.swdio, self.swclk, self.nreset]
+            .iter()
+            .all(|pin| Self::ASSIGNABLE.contains(pin))
+            && self.swdio != self.swclk
+            && self.swdio != self.nreset
+            && self.swclk != self.nreset
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct FlashConfig {
+    /// Debug pins, `None` for the compiled-in ones.
+    pub pins: Option<PinAssignment>,
+    /// SWCLK frequency until the host sets one, `None` for
+    /// [`DEFAULT_FREQUENCY`](crate::dap::DEFAULT_FREQUENCY).
+    pub frequency: Option<u32>,
+}
+
+impl FlashConfig {
+    pub const EMPTY: FlashConfig = FlashConfig {
+        pins: None,
+        frequency: None,
+    };
+
+    fn decode(record: &[u8]) -> FlashConfig {
+        if record[..4] != MAGIC {
+            return FlashConfig::EMPTY;
+        }
+
+        let pins = PinAssignment {
+            swdio: record[4],
+            swclk: record[5],
+            nreset: record[6],
+        };
+
+        let frequency = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+
+        FlashConfig {
+            pins: Some(pins).filter(PinAssignment::is_valid),
+            frequency: Some(frequency).filter(|&f| f != 0 && f != u32::MAX),
+        }
+    }
+
+    fn encode(&self, record: &mut [u8]) {
+        record[..RECORD_LEN].fill(UNSET);
+        record[..4].copy_from_slice(&MAGIC);
+        if let Some(pins) = self.pins {
+            record[4..7].copy_from_slice(&[pins.swdio, pins.swclk, pins.nreset]);
+        }
+        if let Some(frequency) = self.frequency {
+            record[8..12].copy_from_slice(&frequency.to_le_bytes());
+        }
+    }
+}
+
+/// The stored config, [`FlashConfig::EMPTY`] without a valid record.
+pub fn read() -> FlashConfig {
+    // NOTE(unsafe) the sector is mapped by XIP and never part of the image
+    let record =
+        unsafe { core::slice::from_raw_parts((XIP_BASE + OFFSET) as *const u8, RECORD_LEN) };
+    FlashConfig::decode(record)
+}
+
+/// Replaces the stored config.
+pub fn write(config: &FlashConfig) {
+    let mut page = [UNSET; PAGE_SIZE];
+    config.encode(&mut page);
+
+    interrupt::free(|_| unsafe { program(&page) });
+    info!("Stored config: {}", config);
+}
+
+#[link_section = ".data.ram_func"]
+#[inline(never)]
+unsafe fn program(page: &[u8; PAGE_SIZE]) {
+    // Load important addresses to the stack
+    let connect_internal_flash = rom_data::connect_internal_flash;
+    let flash_exit_xip = rom_data::flash_exit_xip;
+    let flash_range_erase = rom_data::flash_range_erase;
+    let flash_range_program = rom_data::flash_range_program;
+    let flash_flush_cache = rom_data::flash_flush_cache;
+
+    // boot2 restores the fast XIP mode afterwards
+    let mut boot2: core::mem::MaybeUninit<[u8; 256]> = core::mem::MaybeUninit::uninit();
+    rom_data::memcpy(boot2.as_mut_ptr() as _, XIP_BASE as _, 256);
+
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+
+    connect_internal_flash();
+    flash_exit_xip();
+    flash_range_erase(OFFSET, SECTOR_SIZE as usize, SECTOR_SIZE, SECTOR_ERASE_CMD);
+    flash_range_program(OFFSET, page.as_ptr(), PAGE_SIZE);
+    flash_flush_cache();
+
+    let ptr = (boot2.as_mut_ptr() as *const u8).add(1) as *const ();
+    let start: extern "C" fn() = core::mem::transmute(ptr);
+    start();
+}
@@ -0,0 +1,57 @@
+//! `DAP_Info` answers the probe fills in itself.
+//!
+//! The vendor and product names and the serial number are the ones of the USB identity, so the
+//! host sees the same probe on both. Commands are processed one at a time and both interfaces
+//! use 64 byte packets, which is what hosts size their transfer batches by. The firmware
+//! version and the capabilities are left to the DAP handler, which derives JTAG from the `jtag`
+//! feature and the SWO modes from `Swo::support`.
+
+use crate::{
+    device_signature,
+    setup::{self, DapHandler},
+};
+use dap_rs::dap::DapVersion;
+
+pub const ID_DAP_INFO: u8 = 0x00;
+
+const INFO_VENDOR_NAME: u8 = 0x01;
+const INFO_PRODUCT_NAME: u8 = 0x02;
+const INFO_SERIAL_NUMBER: u8 = 0x03;
+const INFO_PACKET_COUNT: u8 = 0xfe;
+const INFO_PACKET_SIZE: u8 = 0xff;
+
+/// Commands the host may have in flight.
+pub const PACKET_COUNT: u8 = 1;
+
+/// Size of the command and response packets, on both interfaces.
+pub const PACKET_SIZE: u16 = 64;
+
+pub fn process(dap: &mut DapHandler, report: &[u8], resp: &mut [u8], version: DapVersion) -> usize {
+    // Response: [id, len, info[len]]
+    let len = match report.get(1) {
+        Some(&INFO_VENDOR_NAME) => string(&mut resp[2..], setup::USB_IDENTITY.manufacturer),
+        Some(&INFO_PRODUCT_NAME) => string(&mut resp[2..], setup::USB_IDENTITY.product),
+        Some(&INFO_SERIAL_NUMBER) => string(&mut resp[2..], device_signature::device_id_hex()),
+        Some(&INFO_PACKET_COUNT) => {
+            resp[2] = PACKET_COUNT;
+            1
+        }
+        Some(&INFO_PACKET_SIZE) => {
+            resp[2..4].copy_from_slice(&PACKET_SIZE.to_le_bytes());
+            2
+        }
+        _ => return dap.process_command(report, resp, version),
+    };
+
+    resp[0] = ID_DAP_INFO;
+    resp[1] = len as u8;
+    2 + len
+}
+
+/// Writes `s` NUL terminated, as `DAP_Info` strings are, cut to fit.
+pub fn string(out: &mut [u8], s: &str) -> usize {
+    let n = s.len().min(out.len() - 1);
+    out[..n].copy_from_slice(&s.as_bytes()[..n]);
+    out[n] = 0;
+    n + 1
+}
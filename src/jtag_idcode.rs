@@ -0,0 +1,182 @@
+//! `DAP_JTAG_IDCODE`, answered with a scan of the chain after a TAP reset.
+//!
+//! Test-Logic-Reset loads every TAP's IDCODE into its data register, or selects BYPASS on TAPs
+//! without one, so the IDCODEs are shifted out of Shift-DR without loading an instruction. An
+//! IDCODE always has bit 0 set while BYPASS captures a single 0, which tells the two apart when
+//! walking the chain from TDO. The index counts the TAPs from the one connected to TDO.
+//!
+//! The scan is issued as `DAP_JTAG_Sequence` commands to the DAP handler, so it only succeeds
+//! while the handler is in JTAG mode.
+
+use crate::setup::DapHandler;
+use dap_rs::dap::DapVersion;
+use defmt::*;
+
+pub const ID_DAP_JTAG_IDCODE: u8 = 0x16;
+
+const ID_DAP_JTAG_SEQUENCE: u8 = 0x14;
+
+/// Largest number of TAPs scanned for an IDCODE.
+pub const MAX_TAPS: usize = 8;
+
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xff;
+
+/// `DAP_JTAG_Sequence` info byte, TMS in bit 6, capture TDO in bit 7.
+const TMS: u8 = 1 << 6;
+const CAPTURE: u8 = 1 << 7;
+
+/// Bits taken by the TAP at the start of `chain`, `Some(idcode)` or `None` for BYPASS.
+fn next_tap(chain: &[u8], bit: usize) -> Option<(usize, Option<u32>)> {
+    let get = |n: usize| chain.get(n / 8).map(|byte| (byte >> (n % 8)) & 1 != 0);
+
+    if !get(bit)? {
+        return Some((1, None));
+    }
+
+    let mut idcode = 0;
+    for n in 0..32 {
+        if get(bit + n)? {
+            idcode |= 1 << n;
+        }
+    }
+    Some((32, Some(idcode)))
+}
+
+/// Decodes the IDCODE of the TAP at `index` from the DR chain captured after a TAP reset, LSB
+/// first as it came out of TDO. `None` if the TAP is in BYPASS or the chain is too short.
+pub fn decode(chain: &[u8], index: usize) -> Option<u32> {
+    let mut bit = 0;
+    for _ in 0..index {
+        bit += next_tap(chain, bit)?.0;
+    }
+    next_tap(chain, bit)?.1
+}
+
+/// Request: `[0x16, index]`, response: `[0x16, status, idcode[4]]`.
+pub fn process(dap: &mut DapHandler, report: &[u8], resp: &mut [u8]) -> usize {
+    resp[0] = ID_DAP_JTAG_IDCODE;
+    resp[1] = DAP_ERROR;
+    resp[2..6].fill(0);
+
+    let index = match report.get(1) {
+        Some(&index) if (index as usize) < MAX_TAPS => index as usize,
+        _ => {
+            warn!("JTAG IDCODE index out of range");
+            return 6;
+        }
+    };
+
+    match scan(dap, index) {
+        Some(idcode) => {
+            trace!("JTAG IDCODE of TAP {}: 0x{:08x}", index, idcode);
+            resp[1] = DAP_OK;
+            resp[2..6].copy_from_slice(&idcode.to_le_bytes());
+        }
+        None => warn!("No IDCODE for TAP {}", index),
+    }
+    6
+}
+
+/// Resets the TAPs, shifts out enough of the DR chain to reach `index` and returns to
+/// Run-Test/Idle.
+fn scan(dap: &mut DapHandler, index: usize) -> Option<u32> {
+    // Test-Logic-Reset, then Run-Test/Idle, Select-DR-Scan, Capture-DR and Shift-DR
+    sequence(dap, TMS | 6, None)?;
+    sequence(dap, 1, None)?;
+    sequence(dap, TMS | 1, None)?;
+    sequence(dap, 2, None)?;
+
+    // Every TAP up to `index` takes at most 32 bits, TDI is held high while shifting
+    let mut chain = [0; MAX_TAPS * 4];
+    let bits = (index + 1) * 32;
+    for (n, chunk) in chain[..bits / 8].chunks_mut(8).enumerate() {
+        // The last bit leaves Shift-DR for Exit1-DR
+        let last = (n + 1) * 64 >= bits;
+        let len = chunk.len() * 8 - last as usize;
+        sequence(dap, CAPTURE | (len % 64) as u8, Some(&mut chunk[..]))?;
+        if last {
+            let mut tail = [0];
+            sequence(dap, CAPTURE | TMS | 1, Some(&mut tail))?;
+            chunk[chunk.len() - 1] |= tail[0] << 7;
+        }
+    }
+
+    // Update-DR, Run-Test/Idle
+    sequence(dap, TMS | 1, None)?;
+    sequence(dap, 1, None)?;
+
+    decode(&chain[..bits / 8], index)
+}
+
+/// Runs a single `DAP_JTAG_Sequence` with TDI high, the captured TDO is copied to `tdo`.
+fn sequence(dap: &mut DapHandler, info: u8, tdo: Option<&mut [u8]>) -> Option<()> {
+    let bits = match info & 0x3f {
+        0 => 64,
+        n => n as usize,
+    };
+    let bytes = (bits + 7) / 8;
+
+    // Request: [id, count, info, tdi...], response: [id, status, tdo...]
+    let mut command = [0xff; 3 + 8];
+    command[0] = ID_DAP_JTAG_SEQUENCE;
+    command[1] = 1;
+    command[2] = info;
+    let mut scratch = [0; 64];
+    dap.process_command(&command[..3 + bytes], &mut scratch, DapVersion::V2);
+    if scratch[1] != DAP_OK {
+        return None;
+    }
+
+    if let Some(tdo) = tdo {
+        let len = tdo.len().min(bytes);
+        tdo[..len].copy_from_slice(&scratch[2..2 + len]);
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDCODE_A: u32 = 0x4ba0_0477;
+    const IDCODE_B: u32 = 0x0bb1_1477;
+
+    /// Packs `(value, bits)` fields LSB first, as they come out of TDO.
+    fn chain(fields: &[(u32, usize)]) -> Vec<u8> {
+        let mut chain = Vec::new();
+        let mut bit = 0;
+        for &(value, len) in fields {
+            for n in 0..len {
+                if bit / 8 == chain.len() {
+                    chain.push(0);
+                }
+                chain[bit / 8] |= (((value >> n) & 1) as u8) << (bit % 8);
+                bit += 1;
+            }
+        }
+        chain
+    }
+
+    #[test]
+    fn two_taps() {
+        let chain = chain(&[(IDCODE_A, 32), (IDCODE_B, 32)]);
+        assert_eq!(decode(&chain, 0), Some(IDCODE_A));
+        assert_eq!(decode(&chain, 1), Some(IDCODE_B));
+        assert_eq!(decode(&chain, 2), None);
+    }
+
+    #[test]
+    fn bypass_takes_a_single_bit() {
+        let chain = chain(&[(IDCODE_A, 32), (0, 1), (IDCODE_B, 32)]);
+        assert_eq!(decode(&chain, 0), Some(IDCODE_A));
+        assert_eq!(decode(&chain, 1), None);
+        assert_eq!(decode(&chain, 2), Some(IDCODE_B));
+    }
+
+    #[test]
+    fn truncated_idcode() {
+        let chain = chain(&[(IDCODE_A, 32), (IDCODE_B, 24)]);
+        assert_eq!(decode(&chain, 1), None);
+    }
+}
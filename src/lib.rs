@@ -1,15 +1,55 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(feature = "defmt-usb"))]
 use defmt_rtt as _;
-use panic_probe as _;
 
+pub mod adc;
+pub mod block_crc;
+pub mod bootloader;
+pub mod build_info;
+pub mod bus_park;
+pub mod clock_gating;
 pub mod dap;
+#[cfg(feature = "defmt-usb")]
+pub mod defmt_usb;
 pub mod device_signature;
+pub mod flash_config;
+pub mod info;
+#[cfg(feature = "jtag")]
+pub mod jtag_idcode;
+pub mod liveness;
+#[cfg(not(test))]
+pub mod panic;
 pub mod pio;
+pub mod pio_swd;
+pub mod read_stream;
+pub mod ring_buffer;
+#[cfg(feature = "rtt")]
+pub mod rtt;
+#[cfg(feature = "diagnostics")]
+pub mod scope_loop;
+pub mod self_check;
 pub mod setup;
+#[cfg(feature = "diagnostics")]
+pub mod stats;
+pub mod status_led;
+pub mod status_timer;
+pub mod swd_sequence;
+#[cfg(feature = "swo")]
+pub mod swo;
 pub mod systick_delay;
+pub mod target;
+pub mod target_power;
+pub mod timer_delay;
+pub mod transfer_block;
+pub mod uart;
+#[cfg(feature = "cdc")]
+pub mod uart_bridge;
 pub mod usb;
+pub mod vendor;
+#[cfg(feature = "webusb")]
+pub mod webusb;
 
 defmt::timestamp! {"{=u64}", {
     static COUNT: AtomicUsize = AtomicUsize::new(0);
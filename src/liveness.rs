@@ -0,0 +1,62 @@
+//! Watchdog feeding and the liveness output.
+//!
+//! The watchdog is only fed from the idle loop, and between iterations of the long running scope
+//! loop, never from an interrupt that fires on its own. Every feed toggles the liveness output,
+//! so an external monitor sees it stop when the firmware hangs, even while the status LED keeps
+//! blinking from its timer.
+
+use embedded_hal::{
+    digital::v2::OutputPin,
+    watchdog::{Watchdog as _, WatchdogEnable},
+};
+use embedded_time::duration::Microseconds;
+use rp_pico::hal::{gpio::DynPin, watchdog::Watchdog};
+
+pub struct Liveness {
+    watchdog: Watchdog,
+    enabled: bool,
+    output: Option<DynPin>,
+    level: bool,
+}
+
+impl Liveness {
+    /// Starts the watchdog with `timeout_us`, if any, the watchdog is left disabled otherwise.
+    pub fn new(
+        mut watchdog: Watchdog,
+        timeout_us: Option<u32>,
+        mut output: Option<DynPin>,
+    ) -> Self {
+        if let Some(timeout_us) = timeout_us {
+            watchdog.pause_on_debug(true);
+            watchdog.start(Microseconds(timeout_us));
+        }
+
+        if let Some(pin) = &mut output {
+            pin.into_push_pull_output();
+            pin.set_low().ok();
+        }
+
+        Liveness {
+            watchdog,
+            enabled: timeout_us.is_some(),
+            output,
+            level: false,
+        }
+    }
+
+    /// The watchdog feed point, also the only place the liveness output changes.
+    pub fn feed(&mut self) {
+        if self.enabled {
+            self.watchdog.feed();
+        }
+
+        if let Some(pin) = &mut self.output {
+            self.level = !self.level;
+            if self.level {
+                pin.set_high().ok();
+            } else {
+                pin.set_low().ok();
+            }
+        }
+    }
+}
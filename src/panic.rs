@@ -0,0 +1,128 @@
+//! Panic and HardFault handlers.
+//!
+//! On a panic or a HardFault the debug pins are released so a connected target is not stranded
+//! with a driven bus or held in reset, then the LED blinks SOS. Optionally the probe reboots into
+//! the UF2 bootloader afterwards so it can be reflashed without touching the board.
+//!
+//! Nothing here depends on RTIC or the HAL being alive, only raw register accesses are used.
+
+use crate::{
+    dap,
+    flash_config::PinAssignment,
+    setup::{
+        BUFFERED_GPIOS, BUFFERED_PINS, JTAG_GPIOS, PANIC_REBOOT_TO_BOOTLOADER, PANIC_SOS_ROUNDS,
+        STATUS_OUTPUTS, STATUS_OUTPUT_GPIOS,
+    },
+};
+use core::panic::PanicInfo;
+use cortex_m_rt::{exception, ExceptionFrame};
+use rp_pico::hal::{pac, rom_data};
+
+/// The debug pins: every GPIO nRESET, SWDIO and SWCLK can be assigned to, and the buffered pin
+/// set, the JTAG pins and the status outputs when they are in use.
+const DEBUG_PINS: u32 = mask(true, &PinAssignment::ASSIGNABLE)
+    | mask(BUFFERED_PINS, &BUFFERED_GPIOS)
+    | mask(cfg!(feature = "jtag"), &JTAG_GPIOS)
+    | mask(STATUS_OUTPUTS, &STATUS_OUTPUT_GPIOS);
+
+/// The mask of `gpios`, empty if they are not `used`.
+const fn mask(used: bool, gpios: &[u8]) -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while used && i < gpios.len() {
+        mask |= 1 << gpios[i];
+        i += 1;
+    }
+    mask
+}
+
+const LED_PIN: usize = 25;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+    halt()
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!(
+        "HardFault at PC 0x{:08x}, LR 0x{:08x}",
+        frame.pc(),
+        frame.lr()
+    );
+    halt()
+}
+
+/// Releases the debug pins, blinks SOS and optionally reboots into the bootloader.
+fn halt() -> ! {
+    safe_pins();
+
+    for _ in 0..PANIC_SOS_ROUNDS {
+        blink_sos();
+    }
+
+    if PANIC_REBOOT_TO_BOOTLOADER {
+        // Use the LED as the bootloader activity indicator, keep both interfaces
+        rom_data::reset_to_usb_boot(1 << LED_PIN, 0);
+    }
+
+    loop {
+        blink_sos();
+    }
+}
+
+/// Puts all debug pins in high-impedance, whatever function they are currently used by.
+pub fn safe_pins() {
+    // NOTE(unsafe) we are panicking, nobody else accesses the registers anymore
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+
+    sio.gpio_oe_clr.write(|w| unsafe { w.bits(DEBUG_PINS) });
+
+    // Output disable on the pad overrides any peripheral (PIO etc)
+    for pin in 0..30 {
+        if DEBUG_PINS & (1 << pin) != 0 {
+            pads.gpio[pin].modify(|_, w| w.od().set_bit());
+        }
+    }
+}
+
+fn blink_sos() {
+    const DOT: u32 = 150;
+    const DASH: u32 = 3 * DOT;
+
+    for &on in &[DOT, DOT, DOT, DASH, DASH, DASH, DOT, DOT, DOT] {
+        led(true);
+        delay_ms(on);
+        led(false);
+        delay_ms(DOT);
+    }
+
+    // Word gap
+    delay_ms(7 * DOT);
+}
+
+fn led(on: bool) {
+    // NOTE(unsafe) we are panicking, nobody else accesses the registers anymore
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+
+    // Take the LED back from whatever owned it (PWM etc) and make it a SIO (function 5) output
+    io.gpio[LED_PIN]
+        .gpio_ctrl
+        .write(|w| unsafe { w.funcsel().bits(5) });
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << LED_PIN) });
+
+    if on {
+        sio.gpio_out_set.write(|w| unsafe { w.bits(1 << LED_PIN) });
+    } else {
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << LED_PIN) });
+    }
+}
+
+fn delay_ms(ms: u32) {
+    cortex_m::asm::delay(ms * dap::cycles_per_ms());
+}
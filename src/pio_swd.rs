@@ -0,0 +1,499 @@
+//! SWD transfers on a PIO state machine.
+//!
+//! PIO0 state machine 0 runs a small engine which clocks a given number of bits out of or into
+//! SWDIO, with SWCLK as its side-set pin. The CPU only feeds it one command per transfer phase
+//! (request, turnaround, ACK, data, parity, idle) and reads back what was sampled, so the bit
+//! timing no longer depends on the CPU and SWCLK runs at up to a quarter of the system clock.
+//!
+//! The pins are only switched to the PIO function for the duration of a transfer, everything
+//! else (sequences, pin control, bus parking) keeps using the SIO pin drivers. The state machine
+//! is driven through its registers like the SWO decoder on PIO1, the HAL driver can neither
+//! change its pins nor its divisor once started.
+
+use crate::dap::SwdTiming;
+use dap_rs::swd;
+use rp_pico::hal::pac;
+
+// PIO `CTRL` and `FSTAT` bits of state machine 0
+const SM0_ENABLE: u32 = 1 << 0;
+const SM0_RXEMPTY: u32 = 1 << 8;
+const SM0_TXFULL: u32 = 1 << 16;
+
+// PIO `SM0_EXECCTRL`: optional side-set
+const SIDE_EN: u32 = 1 << 30;
+
+// PIO `SM0_SHIFTCTRL`: shift both ways LSB first, no autopull or autopush
+const OUT_SHIFTDIR_RIGHT: u32 = 1 << 19;
+const IN_SHIFTDIR_RIGHT: u32 = 1 << 18;
+
+// PIO `SM0_PINCTRL`: side-set with the enable bit, one SET and OUT pin each
+const SIDESET_COUNT_2: u32 = 2 << 29;
+const SET_COUNT_1: u32 = 1 << 26;
+const OUT_COUNT_1: u32 = 1 << 20;
+
+/// `set pindirs, 1` and `set pins, 1`, run on SWCLK through the SET mapping.
+const SET_PINDIRS_1: u32 = 0xe081;
+const SET_PINS_1: u32 = 0xe001;
+
+// IO_BANK0 `GPIO_CTRL` function select
+const FUNCSEL_MASK: u32 = 0x1f;
+const FUNCSEL_SIO: u32 = 5;
+const FUNCSEL_PIO0: u32 = 6;
+
+/// PIO cycles per SWCLK period.
+const CYCLES_PER_BIT: u32 = 4;
+
+/// Command word: bit count - 1 in bits 7:0, SWDIO driven in bit 8, the phase in bits 13:9.
+const DRIVE: u32 = 1 << 8;
+const PHASE_SHIFT: u32 = 9;
+
+pub struct PioSwd {
+    _pio: pac::PIO0,
+    system_frequency: u32,
+    write_bits: u32,
+    read_bits: u32,
+}
+
+impl PioSwd {
+    /// Takes PIO0 out of reset, loads the engine and starts it waiting for the first command.
+    pub fn new(pio: pac::PIO0, resets: &mut pac::RESETS, system_frequency: u32) -> Self {
+        resets.reset.modify(|_, w| w.pio0().clear_bit());
+        while resets.reset_done.read().pio0().bit_is_clear() {}
+
+        let program = pio_proc::pio!(
+            32,
+            "
+; SWD bit engine
+;
+; - side 1 is SWCLK, which idles high
+; - SWDIO changes while SWCLK is low, the target samples it on the rising edge
+; - SWDIO is sampled right at the rising edge, which the input synchronizer delays
+; - reads push their bits once done, left aligned as they shift in from the top
+
+.side_set 1 opt
+
+public next_command:
+    pull                        side 1
+    out x, 8                            ; bit count - 1
+    out pindirs, 1                      ; drive SWDIO
+    out pc, 5                           ; run the phase
+public write_bits:
+    pull                                ; the data word
+write_loop:
+    out pins, 1             [1] side 0
+    jmp x-- write_loop      [1] side 1
+    jmp next_command
+public read_bits:
+read_loop:
+    nop                     [1] side 0
+    in pins, 1                  side 1
+    jmp x-- read_loop           side 1
+    push
+            "
+        );
+
+        // NOTE(unsafe) PIO0 is only used here, the program is loaded at 0 as assembled
+        for (slot, instruction) in pio.instr_mem.iter().zip(program.program.code.iter()) {
+            slot.write(|w| unsafe { w.bits(*instruction as u32) });
+        }
+        let sm = &pio.sm[0];
+        sm.sm_execctrl.write(|w| unsafe {
+            w.bits(
+                SIDE_EN
+                    | (program.program.wrap.source as u32) << 12
+                    | (program.program.wrap.target as u32) << 7,
+            )
+        });
+        sm.sm_shiftctrl
+            .write(|w| unsafe { w.bits(OUT_SHIFTDIR_RIGHT | IN_SHIFTDIR_RIGHT) });
+        // `jmp next_command`
+        sm.sm_instr
+            .write(|w| unsafe { w.bits(program.public_defines.next_command as u32) });
+        pio.ctrl.write(|w| unsafe { w.bits(SM0_ENABLE) });
+
+        let mut engine = PioSwd {
+            _pio: pio,
+            system_frequency,
+            write_bits: program.public_defines.write_bits as u32,
+            read_bits: program.public_defines.read_bits as u32,
+        };
+        engine.set_frequency(100_000);
+        engine
+    }
+
+    /// Sets the SWCLK frequency, returning the one actually achieved.
+    pub fn set_frequency(&mut self, frequency: u32) -> u32 {
+        let divisor = divisor(self.system_frequency, frequency);
+        // NOTE(unsafe) the divisor is in range
+        pio().sm[0]
+            .sm_clkdiv
+            .write(|w| unsafe { w.bits(divisor << 8) });
+
+        (256 * self.system_frequency as u64 / (CYCLES_PER_BIT * divisor) as u64) as u32
+    }
+
+    /// One SWD transfer, a write if `data` is given. Returns the read data, or 0 for a write.
+    /// `data_phase` clocks the data phase after a WAIT or FAULT as well.
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer(
+        &mut self,
+        swdio: u8,
+        swclk: u8,
+        request: u8,
+        data: Option<u32>,
+        timing: &SwdTiming,
+        ack_gap: u8,
+        data_phase: bool,
+    ) -> swd::Result<u32> {
+        self.attach(swdio as u32, swclk as u32);
+        let result = phases(self, request, data, timing, ack_gap, data_phase);
+        set_function(swdio as u32, FUNCSEL_SIO);
+        set_function(swclk as u32, FUNCSEL_SIO);
+
+        result
+    }
+
+    /// Maps the engine onto the pins, SWCLK is driven high before the PIO takes it over.
+    fn attach(&mut self, swdio: u32, swclk: u32) {
+        let sm = &pio().sm[0];
+        // NOTE(unsafe) the engine is stalled on its `pull`, the pins can be remapped
+        sm.sm_pinctrl.write(|w| unsafe {
+            w.bits(
+                SIDESET_COUNT_2
+                    | SET_COUNT_1
+                    | OUT_COUNT_1
+                    | swdio << 15
+                    | swclk << 10
+                    | swclk << 5
+                    | swdio,
+            )
+        });
+        sm.sm_instr.write(|w| unsafe { w.bits(SET_PINS_1) });
+        sm.sm_instr.write(|w| unsafe { w.bits(SET_PINDIRS_1) });
+
+        set_function(swclk, FUNCSEL_PIO0);
+        set_function(swdio, FUNCSEL_PIO0);
+    }
+
+    fn command(&mut self, word: u32) {
+        let pio = pio();
+        while pio.fstat.read().bits() & SM0_TXFULL != 0 {}
+        // NOTE(unsafe) any value is a valid FIFO entry
+        pio.txf[0].write(|w| unsafe { w.bits(word) });
+    }
+}
+
+/// The bit level operations a transfer is made of. The PIO engine, or a stand-in in tests.
+trait Engine {
+    /// Clocks out the low `bits` bits of `data`, at most 32.
+    fn write(&mut self, bits: u8, data: u32);
+
+    /// Clocks in `bits` bits, at most 32, returned in the low bits.
+    fn read(&mut self, bits: u8) -> u32;
+
+    /// Clocks with SWDIO released, discarding what is read.
+    fn skip(&mut self, mut bits: u8) {
+        while bits > 0 {
+            let chunk = bits.min(32);
+            self.read(chunk);
+            bits -= chunk;
+        }
+    }
+}
+
+impl Engine for PioSwd {
+    fn write(&mut self, bits: u8, data: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.command((bits as u32 - 1) | DRIVE | self.write_bits << PHASE_SHIFT);
+        self.command(data);
+    }
+
+    fn read(&mut self, bits: u8) -> u32 {
+        if bits == 0 {
+            return 0;
+        }
+        self.command((bits as u32 - 1) | self.read_bits << PHASE_SHIFT);
+
+        let pio = pio();
+        while pio.fstat.read().bits() & SM0_RXEMPTY != 0 {}
+        pio.rxf[0].read().bits() >> (32 - bits as u32)
+    }
+}
+
+/// Clocks the phases of one transfer, a write if `data` is given.
+fn phases(
+    engine: &mut impl Engine,
+    request: u8,
+    data: Option<u32>,
+    timing: &SwdTiming,
+    ack_gap: u8,
+    data_phase: bool,
+) -> swd::Result<u32> {
+    engine.write(8, request as u32);
+    engine.skip(timing.turnaround + ack_gap);
+
+    if let Err(e) = swd::Ack::try_ok(engine.read(3) as u8) {
+        let data_phase = data_phase && matches!(e, swd::Error::AckWait | swd::Error::AckFault);
+        if data_phase && data.is_none() {
+            engine.skip(33);
+        }
+        engine.skip(timing.turnaround);
+        if data_phase && data.is_some() {
+            engine.write(32, 0);
+            engine.write(1, 0);
+        }
+        engine.write(timing.trailing_idle, 0);
+        return Err(e);
+    }
+
+    let result = match data {
+        Some(data) => {
+            engine.skip(timing.turnaround);
+            engine.write(32, data);
+            engine.write(1, data.count_ones() & 1);
+            Ok(0)
+        }
+        None => {
+            let data = engine.read(32);
+            let parity = engine.read(1);
+            engine.skip(timing.turnaround);
+
+            if parity == data.count_ones() & 1 {
+                Ok(data)
+            } else {
+                Err(swd::Error::BadParity)
+            }
+        }
+    };
+    engine.write(timing.idle_cycles, 0);
+
+    result
+}
+
+/// The 16.8 fixed point clock divisor for `frequency`, clamped to at least 1.
+pub fn divisor(system_frequency: u32, frequency: u32) -> u32 {
+    let div = 256 * system_frequency as u64 / (CYCLES_PER_BIT as u64 * frequency.max(1) as u64);
+    div.clamp(0x100, 0xffff_ff) as u32
+}
+
+fn set_function(pin: u32, function: u32) {
+    // NOTE(unsafe) only the function select of the SWD pins is changed, from the USB priority
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    io.gpio[pin as usize]
+        .gpio_ctrl
+        .modify(|r, w| unsafe { w.bits(r.bits() & !FUNCSEL_MASK | function) });
+}
+
+#[inline(always)]
+fn pio() -> &'static pac::pio0::RegisterBlock {
+    // NOTE(unsafe) owned by `PioSwd`
+    unsafe { &*pac::PIO0::ptr() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACK_OK: u32 = 0b001;
+    const ACK_WAIT: u32 = 0b010;
+    const ACK_FAULT: u32 = 0b100;
+    const NO_ACK: u32 = 0b111;
+
+    const REQUEST_DPIDR: u8 = 0xa5;
+    const REQUEST_SELECT: u8 = 0xb1;
+
+    /// Clocks of a transfer phase.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Clocks {
+        /// Bits clocked out, with their value.
+        Write(u8, u32),
+        /// Bits clocked in.
+        Read(u8),
+        /// Bits clocked with SWDIO released, ignored.
+        Skip(u8),
+    }
+
+    /// A target answering every request with `ack`, reads return `data` with a good parity.
+    struct Target {
+        ack: u32,
+        data: u32,
+        clocks: Vec<Clocks>,
+    }
+
+    impl Engine for Target {
+        fn write(&mut self, bits: u8, data: u32) {
+            if bits > 0 {
+                self.clocks.push(Clocks::Write(bits, data));
+            }
+        }
+
+        fn read(&mut self, bits: u8) -> u32 {
+            self.clocks.push(Clocks::Read(bits));
+            match bits {
+                3 => self.ack,
+                32 => self.data,
+                1 => self.data.count_ones() & 1,
+                _ => unreachable!(),
+            }
+        }
+
+        fn skip(&mut self, bits: u8) {
+            if bits > 0 {
+                self.clocks.push(Clocks::Skip(bits));
+            }
+        }
+    }
+
+    const TIMING: SwdTiming = SwdTiming {
+        turnaround: 1,
+        idle_cycles: 2,
+        trailing_idle: 4,
+        parity_retries: 0,
+    };
+
+    /// Runs a transfer, a write if `data` is given, and returns its result and clocks.
+    fn transfer(
+        ack: u32,
+        request: u8,
+        data: Option<u32>,
+        timing: SwdTiming,
+        ack_gap: u8,
+        data_phase: bool,
+    ) -> (swd::Result<u32>, Vec<Clocks>) {
+        let mut target = Target {
+            ack,
+            data: 0x0bc1_2477,
+            clocks: Vec::new(),
+        };
+        let result = phases(&mut target, request, data, &timing, ack_gap, data_phase);
+        (result, target.clocks)
+    }
+
+    #[test]
+    fn turnaround_cycles() {
+        for turnaround in 1..=4 {
+            let timing = SwdTiming {
+                turnaround,
+                ..TIMING
+            };
+
+            let (result, clocks) = transfer(ACK_OK, REQUEST_DPIDR, None, timing, 0, false);
+            assert_eq!(result.ok(), Some(0x0bc1_2477));
+            assert_eq!(
+                clocks,
+                [
+                    Clocks::Write(8, REQUEST_DPIDR as u32),
+                    Clocks::Skip(turnaround),
+                    Clocks::Read(3),
+                    Clocks::Read(32),
+                    Clocks::Read(1),
+                    Clocks::Skip(turnaround),
+                    Clocks::Write(2, 0),
+                ]
+            );
+
+            let (result, clocks) = transfer(ACK_OK, REQUEST_SELECT, Some(0xf0), timing, 0, false);
+            assert_eq!(result.ok(), Some(0));
+            assert_eq!(
+                clocks,
+                [
+                    Clocks::Write(8, REQUEST_SELECT as u32),
+                    Clocks::Skip(turnaround),
+                    Clocks::Read(3),
+                    Clocks::Skip(turnaround),
+                    Clocks::Write(32, 0xf0),
+                    Clocks::Write(1, 0),
+                    Clocks::Write(2, 0),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn ack_gap_clocks() {
+        for ack_gap in [0, 1, 5, crate::dap::MAX_ACK_GAP] {
+            let (_, clocks) = transfer(ACK_OK, REQUEST_DPIDR, None, TIMING, ack_gap, false);
+            // The gap follows the turnaround, right before the ACK is sampled
+            assert_eq!(
+                clocks[..3],
+                [
+                    Clocks::Write(8, REQUEST_DPIDR as u32),
+                    Clocks::Skip(TIMING.turnaround + ack_gap),
+                    Clocks::Read(3),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn idle_cycles_follow_a_completed_transfer() {
+        for idle_cycles in [0, 8, crate::dap::MAX_IDLE_CYCLES] {
+            let timing = SwdTiming {
+                idle_cycles,
+                ..TIMING
+            };
+            // Nothing is clocked without idle cycles
+            let idle: Vec<_> = (idle_cycles > 0)
+                .then_some(Clocks::Write(idle_cycles, 0))
+                .into_iter()
+                .collect();
+
+            // Both end their data phase after six phases
+            let (_, clocks) = transfer(ACK_OK, REQUEST_DPIDR, None, timing, 0, false);
+            assert_eq!(clocks[6..], idle);
+            let (_, clocks) = transfer(ACK_OK, REQUEST_SELECT, Some(0xf0), timing, 0, false);
+            assert_eq!(clocks[6..], idle);
+        }
+    }
+
+    #[test]
+    fn wait_with_data_phase() {
+        // The target still drives the data phase of a read, it is clocked in and dropped
+        let (result, clocks) = transfer(ACK_WAIT, REQUEST_DPIDR, None, TIMING, 0, true);
+        assert!(matches!(result, Err(swd::Error::AckWait)));
+        assert_eq!(
+            clocks,
+            [
+                Clocks::Write(8, REQUEST_DPIDR as u32),
+                Clocks::Skip(1),
+                Clocks::Read(3),
+                Clocks::Skip(33),
+                Clocks::Skip(1),
+                Clocks::Write(4, 0),
+            ]
+        );
+
+        // The probe drives it on a write, with zeros
+        let (result, clocks) = transfer(ACK_WAIT, REQUEST_SELECT, Some(0xf0), TIMING, 0, true);
+        assert!(matches!(result, Err(swd::Error::AckWait)));
+        assert_eq!(
+            clocks,
+            [
+                Clocks::Write(8, REQUEST_SELECT as u32),
+                Clocks::Skip(1),
+                Clocks::Read(3),
+                Clocks::Skip(1),
+                Clocks::Write(32, 0),
+                Clocks::Write(1, 0),
+                Clocks::Write(4, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_data_phase_after_a_wait_unless_configured() {
+        let (_, clocks) = transfer(ACK_WAIT, REQUEST_DPIDR, None, TIMING, 0, false);
+        assert_eq!(
+            clocks[3..],
+            [Clocks::Skip(1), Clocks::Write(TIMING.trailing_idle, 0)]
+        );
+
+        // FAULT has a data phase too, an invalid ACK does not
+        let (_, with_fault) = transfer(ACK_FAULT, REQUEST_DPIDR, None, TIMING, 0, true);
+        assert_eq!(with_fault[3], Clocks::Skip(33));
+        let (_, without_ack) = transfer(NO_ACK, REQUEST_DPIDR, None, TIMING, 0, true);
+        assert_eq!(without_ack[3..], clocks[3..]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Streaming of memory reads on the DAPv2 bulk endpoint.
+//!
+//! Once started by the vendor command the probe keeps reading words through the current MEM-AP
+//! and pushes them to the host as they complete, one frame per packet, without waiting for a
+//! request per block. The host has to configure CSW for 32 bit accesses with auto-increment
+//! beforehand, and must not send other commands than the stop command while streaming.
+//!
+//! Frame: `[ID_READ_STREAM, seq, n, status, data[4] * n]` where `seq` counts the frames from 0,
+//! `status` is the `DAP_Transfer` response status with [`LAST_FRAME`] set on the final frame.
+//! A frame never crosses a 1 KiB boundary, the smallest TAR auto-increment range.
+
+use crate::{dap, setup::DapHandler, usb::ProbeUsb};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use dap_rs::dap::DapVersion;
+use defmt::*;
+
+pub const ID_READ_STREAM: u8 = 0x8e;
+
+/// Set in the status of the final frame.
+pub const LAST_FRAME: u8 = 0x80;
+
+/// Words per frame, limited by the 64 byte packet.
+pub const FRAME_WORDS: usize = 15;
+
+const FRAME_HEADER: usize = 4;
+
+const ID_DAP_TRANSFER: u8 = 0x05;
+const REQUEST_AP_WRITE_TAR: u8 = 0x05;
+const REQUEST_AP_READ_DRW: u8 = 0x0f;
+const STATUS_OK: u8 = 0x01;
+
+/// TAR auto-increment is only guaranteed within 1 KiB.
+const AUTO_INCREMENT_RANGE: u32 = 0x400;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static ADDRESS: AtomicU32 = AtomicU32::new(0);
+static REMAINING: AtomicU32 = AtomicU32::new(0);
+static SEQ: AtomicU8 = AtomicU8::new(0);
+
+/// Starts streaming `count` words from the word aligned `address`.
+pub fn start(address: u32, count: u32) -> bool {
+    if count == 0 || address & 3 != 0 {
+        warn!(
+            "Refusing read stream, address: 0x{:x}, count: {}",
+            address, count
+        );
+        return false;
+    }
+
+    ADDRESS.store(address, Ordering::Relaxed);
+    REMAINING.store(count, Ordering::Relaxed);
+    SEQ.store(0, Ordering::Relaxed);
+    ACTIVE.store(true, Ordering::Release);
+
+    info!(
+        "Read stream started, address: 0x{:x}, count: {}",
+        address, count
+    );
+    true
+}
+
+pub fn stop() {
+    // NOTE(no-CAS) only called from the `on_usb` task
+    if ACTIVE.load(Ordering::Acquire) {
+        ACTIVE.store(false, Ordering::Release);
+        info!("Read stream stopped");
+    }
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// A frame waiting for the bulk endpoint, the endpoint being busy is the flow control.
+pub struct ReadStream {
+    frame: [u8; 64],
+    len: usize,
+}
+
+impl ReadStream {
+    pub const fn new() -> Self {
+        ReadStream {
+            frame: [0; 64],
+            len: 0,
+        }
+    }
+
+    /// Whether the stream is running or its final frame is still waiting for the endpoint.
+    pub fn is_busy(&self) -> bool {
+        self.len > 0 || is_active()
+    }
+
+    /// Pushes frames until the endpoint is busy or the stream ends, call from the USB interrupt.
+    pub fn poll(&mut self, dap: &mut DapHandler, usb: &mut ProbeUsb) {
+        loop {
+            if self.len == 0 {
+                if !is_active() {
+                    return;
+                }
+                self.len = next_frame(dap, &mut self.frame);
+            }
+
+            if !usb.try_dap2_reply(&self.frame[..self.len]) {
+                return;
+            }
+            self.len = 0;
+        }
+    }
+}
+
+/// Reads the next frame, ending the stream on the last one or on an error.
+fn next_frame(dap: &mut DapHandler, frame: &mut [u8; 64]) -> usize {
+    let address = ADDRESS.load(Ordering::Relaxed);
+    let remaining = REMAINING.load(Ordering::Relaxed);
+    let to_boundary = (AUTO_INCREMENT_RANGE - (address & (AUTO_INCREMENT_RANGE - 1))) / 4;
+    let n = remaining.min(to_boundary).min(FRAME_WORDS as u32) as usize;
+
+    // DAP_Transfer with the TAR write followed by the DRW reads
+    let mut command = [REQUEST_AP_READ_DRW; 8 + FRAME_WORDS];
+    let tar = address.to_le_bytes();
+    command[..8].copy_from_slice(&[
+        ID_DAP_TRANSFER,
+        0,
+        1 + n as u8,
+        REQUEST_AP_WRITE_TAR,
+        tar[0],
+        tar[1],
+        tar[2],
+        tar[3],
+    ]);
+    let mut resp = [0; 64];
+    dap::start_transfer_request();
+    dap.process_command(&command[..8 + n], &mut resp, DapVersion::V2);
+
+    // Response: [id, transfers done, status, data[4] * reads]
+    let done = (resp[1] as usize).saturating_sub(1).min(n);
+    let mut status = resp[2];
+    let remaining = remaining - done as u32;
+
+    if status != STATUS_OK || done < n || remaining == 0 {
+        status |= LAST_FRAME;
+        stop();
+    }
+
+    let seq = SEQ.load(Ordering::Relaxed);
+    SEQ.store(seq.wrapping_add(1), Ordering::Relaxed);
+    ADDRESS.store(address.wrapping_add(done as u32 * 4), Ordering::Relaxed);
+    REMAINING.store(remaining, Ordering::Relaxed);
+
+    frame[..FRAME_HEADER].copy_from_slice(&[ID_READ_STREAM, seq, done as u8, status]);
+    frame[FRAME_HEADER..FRAME_HEADER + done * 4].copy_from_slice(&resp[3..3 + done * 4]);
+    FRAME_HEADER + done * 4
+}
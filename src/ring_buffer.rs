@@ -0,0 +1,169 @@
+//! Lock free single producer, single consumer byte ring buffer.
+//!
+//! The producer and consumer may run in different interrupt priorities (or on different cores),
+//! the indices are only ever written by their owning side and published with release/acquire
+//! ordering, so no CAS is needed (which the Cortex-M0+ lacks).
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// `N` must be a power of two.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    /// Free running write index, only written by the producer.
+    head: AtomicUsize,
+    /// Free running read index, only written by the consumer.
+    tail: AtomicUsize,
+}
+
+// NOTE(unsafe) the producer only writes free slots and the consumer only reads filled slots,
+// the slot ownership is handed over through the atomic indices.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two());
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of bytes available to the consumer.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Producer side, returns the number of bytes written. Bytes that do not fit are dropped.
+    pub fn push_slice(&self, data: &[u8]) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let free = N - head.wrapping_sub(tail);
+        let n = core::cmp::min(free, data.len());
+
+        let buf = self.buf.get() as *mut u8;
+        for (i, b) in data[..n].iter().enumerate() {
+            // NOTE(unsafe) slots between head and tail + N are owned by the producer
+            unsafe {
+                buf.add(head.wrapping_add(i) & Self::MASK)
+                    .write_volatile(*b)
+            };
+        }
+
+        self.head.store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    /// Producer side, returns `false` if the buffer is full.
+    pub fn push(&self, byte: u8) -> bool {
+        self.push_slice(&[byte]) == 1
+    }
+
+    /// Consumer side, copies out (and consumes) up to `out.len()` bytes.
+    pub fn pop_slice(&self, out: &mut [u8]) -> usize {
+        let n = self.peek_slice(out);
+        self.consume(n);
+        n
+    }
+
+    /// Consumer side, copies out up to `out.len()` bytes without consuming them.
+    pub fn peek_slice(&self, out: &mut [u8]) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let n = core::cmp::min(head.wrapping_sub(tail), out.len());
+
+        let buf = self.buf.get() as *const u8;
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            // NOTE(unsafe) slots between tail and head are owned by the consumer
+            *b = unsafe { buf.add(tail.wrapping_add(i) & Self::MASK).read_volatile() };
+        }
+
+        n
+    }
+
+    /// Consumer side, drops `n` bytes (at most the available bytes).
+    pub fn consume(&self, n: usize) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let n = core::cmp::min(n, self.len());
+        self.tail.store(tail.wrapping_add(n), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+
+    #[test]
+    fn push_then_pop() {
+        let buffer = RingBuffer::<8>::new();
+        assert!(buffer.is_empty());
+
+        assert_eq!(buffer.push_slice(&[1, 2, 3]), 3);
+        assert_eq!(buffer.len(), 3);
+
+        let mut out = [0; 8];
+        assert_eq!(buffer.pop_slice(&mut out), 3);
+        assert_eq!(out[..3], [1, 2, 3]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn full_buffer_drops_the_rest() {
+        let buffer = RingBuffer::<4>::new();
+        assert_eq!(buffer.push_slice(&[1, 2, 3, 4, 5, 6]), 4);
+        assert!(!buffer.push(7));
+
+        let mut out = [0; 8];
+        assert_eq!(buffer.pop_slice(&mut out), 4);
+        assert_eq!(out[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn wraps_around_the_end() {
+        let buffer = RingBuffer::<4>::new();
+        let mut out = [0; 4];
+
+        // Move the indices to the last slot, the next push straddles the end
+        buffer.push_slice(&[0, 0, 0]);
+        buffer.pop_slice(&mut out[..3]);
+
+        assert_eq!(buffer.push_slice(&[1, 2, 3, 4]), 4);
+        assert_eq!(buffer.pop_slice(&mut out), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn peek_keeps_the_bytes() {
+        let buffer = RingBuffer::<4>::new();
+        buffer.push_slice(&[1, 2, 3]);
+
+        let mut out = [0; 2];
+        assert_eq!(buffer.peek_slice(&mut out), 2);
+        assert_eq!(buffer.len(), 3);
+
+        buffer.consume(2);
+        assert_eq!(buffer.pop_slice(&mut out), 1);
+        assert_eq!(out[0], 3);
+    }
+}
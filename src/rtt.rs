@@ -0,0 +1,234 @@
+//! Forwarding of the target's RTT up-channel 0 to a second CDC-ACM port.
+//!
+//! While that port is open (DTR set) and no debugger uses the probe, the `rtt_poll` task
+//! connects to the target on its own, scans RAM from [`RTT_SCAN_START`] for the `SEGGER RTT`
+//! control block and then moves the bytes of up-channel 0 into [`FROM_TARGET`], which the USB
+//! task writes to the port. It pauses as soon as the host sends a DAP command or reports a
+//! connected debugger, and only connects and scans again once the host was quiet for
+//! [`HOST_QUIET_US`], as the host may have changed the target state in between. Down-channels
+//! are not supported.
+//!
+//! [`RTT_SCAN_START`]: crate::setup::RTT_SCAN_START
+
+use crate::{
+    dap,
+    ring_buffer::RingBuffer,
+    setup::{self, DapHandler},
+    target::{self, ConnectError},
+    timer_delay,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use defmt::*;
+
+/// Size of the buffer towards the host in bytes.
+pub const BUFFER_SIZE: usize = 512;
+
+/// Bytes from up-channel 0 waiting for the host.
+pub static FROM_TARGET: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+
+/// Time between polls of an idle channel, or while paused.
+pub const POLL_INTERVAL_MS: u32 = 10;
+
+/// Time before connecting or scanning again after a failure.
+const RETRY_INTERVAL_MS: u32 = 1000;
+
+/// How long the host has to be quiet before RTT takes over the bus again.
+pub const HOST_QUIET_US: u32 = 1_000_000;
+
+/// The control block ID, "SEGGER RTT" padded with zeros, as little endian words.
+const ID: [u32; 4] = [0x4747_4553, 0x5220_5245, 0x0000_5454, 0];
+
+/// Offset of the number of up-channels in the control block.
+const UP_CHANNELS_OFFSET: u32 = 16;
+/// Offset of the descriptor of up-channel 0 in the control block.
+const FIRST_UP_CHANNEL_OFFSET: u32 = 24;
+
+/// Channel descriptor: name, buffer, size, write offset, read offset and flags.
+const DESCRIPTOR_WORDS: usize = 6;
+const WRITE_OFFSET: u32 = 12;
+const READ_OFFSET: u32 = 16;
+
+/// Words read per scan step.
+const SCAN_CHUNK_WORDS: usize = 64;
+
+/// Words read per drain step, the bytes are at most 3 bytes off the word alignment.
+const READ_WORDS: usize = 15;
+const MAX_READ_BYTES: u32 = READ_WORDS as u32 * 4 - 3;
+
+static PORT_OPEN: AtomicBool = AtomicBool::new(false);
+static LAST_HOST_COMMAND_US: AtomicU32 = AtomicU32::new(0);
+
+/// Records whether the host has the RTT port open, call when the DTR state may have changed.
+pub fn set_port_open(open: bool) {
+    PORT_OPEN.store(open, Ordering::Relaxed);
+}
+
+/// Records a DAP command from the host, which pauses RTT.
+pub fn host_activity() {
+    LAST_HOST_COMMAND_US.store(timer_delay::now_us(), Ordering::Relaxed);
+}
+
+fn may_run() -> bool {
+    let quiet_us = timer_delay::now_us().wrapping_sub(LAST_HOST_COMMAND_US.load(Ordering::Relaxed));
+    PORT_OPEN.load(Ordering::Relaxed) && !dap::host_connected() && quiet_us >= HOST_QUIET_US
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+struct Channel {
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+enum State {
+    /// Not connected to the target.
+    Idle,
+    /// Looking for the control block from this address on.
+    Scanning(u32),
+    Attached(Channel),
+}
+
+pub struct Rtt {
+    state: State,
+}
+
+impl Default for Rtt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rtt {
+    pub const fn new() -> Self {
+        Rtt { state: State::Idle }
+    }
+
+    /// Runs one step, returns the time until the next one in milliseconds.
+    pub fn poll(&mut self, dap: &mut DapHandler) -> u32 {
+        if !may_run() {
+            if self.state != State::Idle {
+                info!("RTT paused");
+                self.state = State::Idle;
+            }
+            return POLL_INTERVAL_MS;
+        }
+
+        let result = match self.state {
+            State::Idle => self.connect(dap),
+            State::Scanning(address) => self.scan(dap, address),
+            State::Attached(channel) => self.drain(dap, channel),
+        };
+
+        match result {
+            Ok(next_ms) => next_ms,
+            Err(e) => {
+                debug!("RTT transfer failed: {}", e);
+                self.state = State::Idle;
+                RETRY_INTERVAL_MS
+            }
+        }
+    }
+
+    fn connect(&mut self, dap: &mut DapHandler) -> Result<u32, ConnectError> {
+        target::connect(dap)?;
+        target::select_mem_ap(dap)?;
+        self.state = State::Scanning(setup::RTT_SCAN_START);
+        Ok(0)
+    }
+
+    fn scan(&mut self, dap: &mut DapHandler, address: u32) -> Result<u32, ConnectError> {
+        let end = setup::RTT_SCAN_START + setup::RTT_SCAN_SIZE;
+        if address >= end {
+            // Not found, the target may not have set it up yet
+            self.state = State::Scanning(setup::RTT_SCAN_START);
+            return Ok(RETRY_INTERVAL_MS);
+        }
+
+        let mut words = [0; SCAN_CHUNK_WORDS];
+        let n = (((end - address) / 4) as usize).min(SCAN_CHUNK_WORDS);
+        target::read_memory(dap, address, &mut words[..n])?;
+
+        match words[..n].windows(ID.len()).position(|w| w == ID) {
+            Some(index) => self.attach(dap, address + 4 * index as u32),
+            None => {
+                // Overlap the chunks, so an ID across two of them is still found
+                let advance = n.saturating_sub(ID.len() - 1).max(1);
+                self.state = State::Scanning(address + 4 * advance as u32);
+                Ok(0)
+            }
+        }
+    }
+
+    fn attach(&mut self, dap: &mut DapHandler, control_block: u32) -> Result<u32, ConnectError> {
+        let mut up_channels = [0];
+        target::read_memory(dap, control_block + UP_CHANNELS_OFFSET, &mut up_channels)?;
+
+        let descriptor = control_block + FIRST_UP_CHANNEL_OFFSET;
+        let mut words = [0; DESCRIPTOR_WORDS];
+        target::read_memory(dap, descriptor, &mut words)?;
+
+        let channel = Channel {
+            descriptor,
+            buffer: words[1],
+            size: words[2],
+        };
+        if up_channels[0] == 0 || channel.size == 0 {
+            warn!(
+                "RTT control block at 0x{:x} has no usable up-channel",
+                control_block
+            );
+            self.state = State::Scanning(control_block + 4);
+            return Ok(0);
+        }
+
+        info!(
+            "RTT control block at 0x{:x}, up-channel 0: {}",
+            control_block, channel
+        );
+        self.state = State::Attached(channel);
+        Ok(0)
+    }
+
+    fn drain(&mut self, dap: &mut DapHandler, channel: Channel) -> Result<u32, ConnectError> {
+        let mut offsets = [0; 2];
+        target::read_memory(dap, channel.descriptor + WRITE_OFFSET, &mut offsets)?;
+        let (write, read) = (offsets[0], offsets[1]);
+
+        if write >= channel.size || read >= channel.size {
+            warn!("RTT offsets out of range, scanning again");
+            self.state = State::Scanning(setup::RTT_SCAN_START);
+            return Ok(RETRY_INTERVAL_MS);
+        }
+
+        let contiguous = if write >= read {
+            write - read
+        } else {
+            channel.size - read
+        };
+        // Nothing new, or the host is not reading the port
+        let free = (BUFFER_SIZE - FROM_TARGET.len()) as u32;
+        let n = contiguous.min(free).min(MAX_READ_BYTES);
+        if n == 0 {
+            return Ok(POLL_INTERVAL_MS);
+        }
+
+        // Read the word aligned window around the new bytes
+        let start = channel.buffer + read;
+        let skip = (start & 3) as usize;
+        let word_count = (skip + n as usize + 3) / 4;
+        let mut words = [0; READ_WORDS];
+        target::read_memory(dap, start & !3, &mut words[..word_count])?;
+
+        let mut bytes = [0; READ_WORDS * 4];
+        for (out, word) in bytes.chunks_exact_mut(4).zip(words.iter()) {
+            out.copy_from_slice(&word.to_le_bytes());
+        }
+        FROM_TARGET.push_slice(&bytes[skip..skip + n as usize]);
+
+        let read = (read + n) % channel.size;
+        target::write_word(dap, channel.descriptor + READ_OFFSET, read)?;
+
+        Ok(if read == write { POLL_INTERVAL_MS } else { 0 })
+    }
+}
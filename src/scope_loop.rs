@@ -0,0 +1,76 @@
+//! Continuous SWD read loop for looking at the signals with a scope.
+//!
+//! While active the loop keeps issuing the same single register read, as a standard
+//! `DAP_Transfer`, at the configured SWD clock. It stops on the stop command or when the safety
+//! timeout expires, so a forgotten loop does not keep hammering the target.
+
+use crate::{dap, setup::DapHandler, timer_delay};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use dap_rs::dap::DapVersion;
+use defmt::*;
+
+const ID_DAP_TRANSFER: u8 = 0x05;
+
+/// Upper limit for the safety timeout, in seconds.
+pub const MAX_TIMEOUT_S: u8 = 60;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static REQUEST: AtomicU8 = AtomicU8::new(0);
+static STARTED_US: AtomicU32 = AtomicU32::new(0);
+static TIMEOUT_US: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the loop with `request` in the `DAP_Transfer` request format, e.g. `0x02` for a DP
+/// read of DPIDR. Write requests are refused as a loop of them could change the target state.
+pub fn start(request: u8, timeout_s: u8) -> bool {
+    const RNW: u8 = 1 << 1;
+
+    if request & RNW == 0 {
+        warn!("Scope loop only supports reads, request: 0x{:x}", request);
+        return false;
+    }
+
+    let timeout_s = core::cmp::min(timeout_s, MAX_TIMEOUT_S).max(1);
+    REQUEST.store(request, Ordering::Relaxed);
+    TIMEOUT_US.store(timeout_s as u32 * 1_000_000, Ordering::Relaxed);
+    STARTED_US.store(timer_delay::now_us(), Ordering::Relaxed);
+    ACTIVE.store(true, Ordering::Release);
+
+    info!(
+        "Scope loop started, request: 0x{:x}, timeout: {} s",
+        request, timeout_s
+    );
+    true
+}
+
+pub fn stop() {
+    // NOTE(no-CAS) only called from the `on_usb` and `scope_loop` tasks, which share a priority
+    if ACTIVE.load(Ordering::Acquire) {
+        ACTIVE.store(false, Ordering::Release);
+        info!("Scope loop stopped");
+    }
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Runs one iteration of the loop, returns `true` if the loop should keep going.
+pub fn step(dap: &mut DapHandler, scratch: &mut [u8]) -> bool {
+    if !is_active() {
+        return false;
+    }
+
+    let elapsed = timer_delay::now_us().wrapping_sub(STARTED_US.load(Ordering::Relaxed));
+    if elapsed >= TIMEOUT_US.load(Ordering::Relaxed) {
+        info!("Scope loop timed out");
+        stop();
+        return false;
+    }
+
+    // DAP index 0, one transfer
+    let command = [ID_DAP_TRANSFER, 0, 1, REQUEST.load(Ordering::Relaxed)];
+    dap::start_transfer_request();
+    dap.process_command(&command, scratch, DapVersion::V2);
+
+    true
+}
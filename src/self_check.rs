@@ -0,0 +1,216 @@
+//! Power-on self-check of the debug outputs.
+//!
+//! Before the probe enumerates, SWDIO and SWCLK are driven high and low in turn and read back
+//! through their input buffers, which catches a pin shorted to a rail or stuck behind a broken
+//! buffer. nRESET is left alone, driving it low would reset an attached target.
+//!
+//! A stuck pin blinks its code on the LED, then the probe boots as usual:
+//!
+//! | Blinks | Fault              |
+//! | ------ | ------------------ |
+//! | 1      | SWDIO stuck low    |
+//! | 2      | SWDIO stuck high   |
+//! | 3      | SWCLK stuck low    |
+//! | 4      | SWCLK stuck high   |
+//!
+//! With SWDIO and SWCLK jumpered together and no target attached, [`loopback`] drives each pin
+//! in turn and reads it back on the other, which also checks the input paths. It switches the
+//! pins with raw SIO and pad register accesses, as the pin drivers are owned by the DAP handler,
+//! and restores them afterwards.
+
+use crate::{bus_park, dap, setup::LedPin};
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::*;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rp_pico::hal::{gpio::DynPin, pac};
+
+/// Settle time between driving a pin and reading it back, in microseconds.
+const SETTLE_US: u32 = 10;
+
+/// GPIO numbers of the direct SWDIO and SWCLK, for [`loopback`].
+static SWDIO: AtomicU8 = AtomicU8::new(NO_PIN);
+static SWCLK: AtomicU8 = AtomicU8::new(NO_PIN);
+
+const NO_PIN: u8 = 0xff;
+
+/// SIO function of the `GPIOx_CTRL` function select.
+const FUNCSEL_SIO: u32 = 5;
+
+/// [`loopback`] result bit of SWCLK driving SWDIO, the `DAP_SWJ_Pins` bit of SWCLK.
+pub const LOOPBACK_SWCLK: u8 = 1 << 0;
+/// [`loopback`] result bit of SWDIO driving SWCLK, the `DAP_SWJ_Pins` bit of SWDIO.
+pub const LOOPBACK_SWDIO: u8 = 1 << 1;
+
+/// Number of times the code of a stuck pin is blinked.
+const CODE_ROUNDS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Fault {
+    StuckLow = 0,
+    StuckHigh = 1,
+}
+
+/// Evaluates the levels read back while driving a pin high and low.
+pub fn evaluate(read_while_high: bool, read_while_low: bool) -> Option<Fault> {
+    match (read_while_high, read_while_low) {
+        (true, false) => None,
+        (false, _) => Some(Fault::StuckLow),
+        (true, true) => Some(Fault::StuckHigh),
+    }
+}
+
+/// Drives the pin in both directions and reads it back, leaving it disabled afterwards.
+fn check_pin(pin: &mut DynPin) -> Option<Fault> {
+    pin.into_push_pull_output();
+
+    let mut read_back = |high: bool| {
+        if high {
+            pin.set_high().ok();
+        } else {
+            pin.set_low().ok();
+        }
+        cortex_m::asm::delay(SETTLE_US * dap::cycles_per_ms() / 1000);
+        matches!(pin.is_high(), Ok(true))
+    };
+
+    let while_high = read_back(true);
+    let while_low = read_back(false);
+    pin.into_pull_down_disabled();
+
+    evaluate(while_high, while_low)
+}
+
+/// Checks SWDIO and SWCLK, blinking the code of every stuck pin. Returns `true` if all pins
+/// passed.
+pub fn run(swdio: &mut DynPin, swclk: &mut DynPin, led: &mut LedPin) -> bool {
+    let mut passed = true;
+
+    for (index, (name, pin)) in [("SWDIO", swdio), ("SWCLK", swclk)].into_iter().enumerate() {
+        if let Some(fault) = check_pin(pin) {
+            error!("Self-check: {} is {}", name, fault);
+            blink_code(led, 2 * index as u32 + fault as u32 + 1);
+            passed = false;
+        }
+    }
+
+    passed
+}
+
+fn blink_code(led: &mut LedPin, blinks: u32) {
+    let cycles_per_ms = dap::cycles_per_ms();
+    for _ in 0..CODE_ROUNDS {
+        for _ in 0..blinks {
+            led.set_high().ok();
+            cortex_m::asm::delay(200 * cycles_per_ms);
+            led.set_low().ok();
+            cortex_m::asm::delay(200 * cycles_per_ms);
+        }
+
+        // Gap between the rounds
+        cortex_m::asm::delay(1000 * cycles_per_ms);
+    }
+}
+
+/// Record the direct SWDIO and SWCLK for [`loopback`], call from setup.
+pub fn set_loopback_pins(swdio: u8, swclk: u8) {
+    SWDIO.store(swdio, Ordering::Relaxed);
+    SWCLK.store(swclk, Ordering::Relaxed);
+}
+
+/// Drives SWCLK high and low and reads it back on SWDIO, then the other way round, with SWDIO
+/// and SWCLK jumpered together. Returns the [`LOOPBACK_SWCLK`] and [`LOOPBACK_SWDIO`] bits of
+/// the pins that drove the other one correctly, `None` while SWD is connected.
+pub fn loopback() -> Option<u8> {
+    let swdio = SWDIO.load(Ordering::Relaxed);
+    let swclk = SWCLK.load(Ordering::Relaxed);
+    if swdio == NO_PIN || bus_park::connected() {
+        return None;
+    }
+
+    let mut passed = 0;
+    if drives(swclk, swdio) {
+        passed |= LOOPBACK_SWCLK;
+    }
+    if drives(swdio, swclk) {
+        passed |= LOOPBACK_SWDIO;
+    }
+    info!("Loopback: passed 0x{:x}", passed);
+    Some(passed)
+}
+
+/// Whether `input` follows `output` driven high and low. `input` is pulled the other way each
+/// time, so an open jumper fails too.
+fn drives(output: u8, input: u8) -> bool {
+    // NOTE(unsafe) the pins are not used by the DAP handler while SWD is not connected, the
+    // USB task is the only one touching them
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+
+    let (out_mask, in_mask) = (1 << output, 1 << input);
+    let (output, input) = (output as usize, input as usize);
+
+    let ctrl = [
+        io.gpio[output].gpio_ctrl.read().bits(),
+        io.gpio[input].gpio_ctrl.read().bits(),
+    ];
+    let pad = [
+        pads.gpio[output].read().bits(),
+        pads.gpio[input].read().bits(),
+    ];
+    let oe = sio.gpio_oe.read().bits() & (out_mask | in_mask);
+    let out = sio.gpio_out.read().bits() & (out_mask | in_mask);
+
+    for &pin in &[output, input] {
+        io.gpio[pin]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(FUNCSEL_SIO) });
+        pads.gpio[pin].modify(|_, w| w.ie().set_bit().od().clear_bit());
+    }
+    sio.gpio_oe_clr.write(|w| unsafe { w.bits(in_mask) });
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(out_mask) });
+
+    let settle_cycles = SETTLE_US * dap::cycles_per_ms() / 1000;
+    let mut follows = true;
+    for &high in &[true, false] {
+        pads.gpio[input].modify(|_, w| w.pue().bit(!high).pde().bit(high));
+        if high {
+            sio.gpio_out_set.write(|w| unsafe { w.bits(out_mask) });
+        } else {
+            sio.gpio_out_clr.write(|w| unsafe { w.bits(out_mask) });
+        }
+        cortex_m::asm::delay(settle_cycles);
+        follows &= (sio.gpio_in.read().bits() & in_mask != 0) == high;
+    }
+
+    sio.gpio_oe_clr
+        .write(|w| unsafe { w.bits((out_mask | in_mask) & !oe) });
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(oe) });
+    sio.gpio_out_clr
+        .write(|w| unsafe { w.bits((out_mask | in_mask) & !out) });
+    sio.gpio_out_set.write(|w| unsafe { w.bits(out) });
+    pads.gpio[output].write(|w| unsafe { w.bits(pad[0]) });
+    pads.gpio[input].write(|w| unsafe { w.bits(pad[1]) });
+    io.gpio[output]
+        .gpio_ctrl
+        .write(|w| unsafe { w.bits(ctrl[0]) });
+    io.gpio[input]
+        .gpio_ctrl
+        .write(|w| unsafe { w.bits(ctrl[1]) });
+
+    follows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_evaluation() {
+        assert_eq!(evaluate(true, false), None);
+        assert_eq!(evaluate(false, false), Some(Fault::StuckLow));
+        assert_eq!(evaluate(true, true), Some(Fault::StuckHigh));
+        // Reading the opposite level both times is taken as stuck low, the first read failed
+        assert_eq!(evaluate(false, true), Some(Fault::StuckLow));
+    }
+}
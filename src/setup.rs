@@ -1,12 +1,30 @@
-use crate::dap::{Context, Jtag, Leds, Swd, Swo, Wait};
+use crate::adc::{AdcPins, AdcReader, CurrentSense, VtrefChannel, VtrefScale};
+use crate::dap::{
+    ConnectProfile, ConnectSequence, Context, Jtag, JtagPins, Leds, PinSet, PinSetTiming, Swd, Swo,
+    Wait,
+};
+use crate::flash_config::{self, PinAssignment};
+use crate::liveness::Liveness;
+use crate::pio_swd::PioSwd;
+use crate::status_led::StatusLed;
+use crate::status_timer::StatusTimer;
 use crate::systick_delay::Delay;
-use crate::{dap, usb::ProbeUsb};
+#[cfg(feature = "cdc")]
+use crate::uart_bridge::UartBridge;
+use crate::{
+    bootloader, build_info, bus_park, clock_gating, dap, self_check, target_power,
+    usb::{ProbeUsb, UsbIdentity},
+};
 use core::mem::MaybeUninit;
+use defmt::*;
 use rp2040_monotonic::Rp2040Monotonic;
 use rp_pico::{
     hal::{
+        adc::Adc,
         clocks::init_clocks_and_plls,
-        gpio::{pin::bank0::*, OutputDriveStrength, OutputSlewRate, Pin, Pins, PushPullOutput},
+        gpio::{
+            pin::bank0::*, DynPin, OutputDriveStrength, OutputSlewRate, Pin, Pins, PushPullOutput,
+        },
         pac,
         usb::UsbBus,
         watchdog::Watchdog,
@@ -19,13 +37,190 @@ use usb_device::class_prelude::UsbBusAllocator;
 pub type DapHandler = dap_rs::dap::Dap<'static, Context, Leds, Wait, Jtag, Swd, Swo>;
 pub type LedPin = Pin<Gpio25, PushPullOutput>;
 
+/// The connect profile used for the SWD timing defaults.
+pub const CONNECT_PROFILE: ConnectProfile = ConnectProfile::Standard;
+
+/// Order of the line reset, JTAG-to-SWD switch and dormant wakeup on connect, `None` uses the
+/// sequence of [`CONNECT_PROFILE`].
+pub const CONNECT_SEQUENCE: Option<ConnectSequence> = None;
+
+/// Send the connect sequence and read DPIDR on `DAP_Connect`, so the first transfer of the host
+/// finds the line out of reset. Hosts can turn it off at runtime with `ID_AUTO_SWITCH`.
+pub const CONNECT_LINE_RESET: bool = true;
+
+/// Answer `DAP_Connect` with the failed port (`0`) when the DPIDR read after the connect sequence
+/// gets no OK ACK, instead of leaving it to the first transfer of the host. Needs
+/// [`CONNECT_LINE_RESET`].
+pub const CONNECT_REQUIRE_DPIDR: bool = false;
+
+/// Assert nRESET around the connect sequence on `DAP_Connect` (connect under reset). Off, the
+/// probe attaches to the running target and only drives nRESET on a `DAP_SWJ_Pins` request of
+/// the host. Hosts can change it at runtime with `ID_CONNECT_UNDER_RESET`.
+pub const CONNECT_UNDER_RESET: bool = false;
+
+/// Keep SWDIO, SWCLK and nRESET high-impedance after boot until the first `DAP_Connect`, for
+/// hot-plugging onto a running target.
+pub const HIGH_Z_UNTIL_CONNECT: bool = false;
+
+/// Idle period after which SWDIO and SWCLK are parked on the pad pulls, in microseconds, `None`
+/// keeps driving them.
+pub const PARK_AFTER_US: Option<u32> = None;
+
+/// Check SWDIO and SWCLK for stuck pins at boot, before enumerating. Skipped when
+/// [`HIGH_Z_UNTIL_CONNECT`] is set, as it drives the pins.
+pub const SELF_CHECK: bool = true;
+
+/// Drive GPIO16 high while a host is connected and GPIO17 high while nRESET is asserted, for
+/// other equipment in test rigs to read.
+pub const STATUS_OUTPUTS: bool = false;
+
+/// GPIOs of the [`STATUS_OUTPUTS`], connected and reset-active, as taken in [`setup`].
+pub const STATUS_OUTPUT_GPIOS: [u8; 2] = [16, 17];
+
+/// A second, buffered, pin set on GPIO2 (SWCLK), GPIO3 (SWDIO) and GPIO4 (nRESET), selectable at
+/// runtime for isolated front-ends.
+pub const BUFFERED_PINS: bool = false;
+
+/// GPIOs of the [`BUFFERED_PINS`] set, SWCLK, SWDIO and nRESET, as taken in [`setup`].
+pub const BUFFERED_GPIOS: [u8; 3] = [2, 3, 4];
+
+/// GPIOs of JTAG TDI and TDO with the `jtag` feature, as taken in [`setup`].
+pub const JTAG_GPIOS: [u8; 2] = [10, 11];
+
+/// Timing for the buffered pin set.
+pub const BUFFERED_TIMING: PinSetTiming = PinSetTiming {
+    max_frequency: 1_000_000,
+    turnaround: None,
+    pull_up: true,
+};
+
+/// A jumper from GPIO22 to GND at boot enters the UF2 bootloader, as a recovery path when USB
+/// commands can't reach the probe.
+pub const BOOT_JUMPER: bool = true;
+
+/// Number of SOS rounds blinked on a panic or HardFault before rebooting into the UF2 bootloader.
+pub const PANIC_SOS_ROUNDS: u32 = 3;
+
+/// Reboot into the UF2 bootloader after a panic or HardFault, instead of blinking SOS forever.
+pub const PANIC_REBOOT_TO_BOOTLOADER: bool = false;
+
+/// Drive the enable of a target power switch on GPIO19, high powers the target. The target
+/// stays unpowered until the host switches it on.
+pub const TARGET_POWER_SWITCH: bool = false;
+
+/// Toggle GPIO18 on every watchdog feed, for an external monitor to detect a hung firmware.
+pub const LIVENESS_OUTPUT: bool = false;
+
+/// Watchdog timeout in microseconds (at most 8.3 s), `None` leaves the watchdog disabled.
+pub const WATCHDOG_TIMEOUT_US: Option<u32> = None;
+
+/// Measure Vtref on the ADC, the status task then tracks the target being powered.
+pub const VTREF_SENSE: bool = false;
+
+/// ADC input Vtref is wired to.
+pub const VTREF_CHANNEL: VtrefChannel = VtrefChannel::Adc0;
+
+/// ADC reference, divider ratio and calibration offset of the Vtref input.
+pub const VTREF_SCALE: VtrefScale = VtrefScale::DEFAULT;
+
+/// Sample the target current from a shunt amplifier on a second ADC input, `None` without one.
+/// Needs [`VTREF_SENSE`].
+pub const CURRENT_SENSE: Option<CurrentSense> = None;
+
+/// Vtref above which the target counts as powered, in millivolts.
+pub const VTREF_THRESHOLD_MV: u32 = 1_500;
+
+/// Answer `DAP_Transfer` and `DAP_TransferBlock` right away with a no ACK status while Vtref is
+/// below [`VTREF_THRESHOLD_MV`], instead of clocking an unpowered target. Any other SWD transfer,
+/// such as the DPIDR read on connect, fails the same way and the last error reads as unpowered.
+/// Needs [`VTREF_SENSE`].
+pub const REJECT_WITHOUT_VTREF: bool = false;
+
+/// Longest run of consecutive WAIT responses, in milliseconds, before the transfer fails with a
+/// FAULT status instead. The number of WAITs is limited by the `DAP_TransferConfigure` wait
+/// retry count as well.
+pub const WAIT_TIMEOUT_MS: u32 = 1_000;
+
+/// Highest SWCLK frequency the host can select, lower it for long or marginal wiring.
+///
+/// The bit-banged driver waits whole SysTick ticks per half period, so at 125 MHz the rates
+/// step through 62.5 MHz / n (4.17 MHz, 3.91 MHz, ... 2.08 MHz, 1.95 MHz, ...). The overshoot of
+/// the wait loop is measured at startup and left out of the waits; only once the pin accesses
+/// take longer than a half period the real clock drops below the requested one. The PIO engine
+/// does not have that overhead.
+pub const MAX_SWCLK_FREQUENCY: u32 = 4_000_000;
+
+/// Pad drive strength of SWCLK and SWDIO, on both pin sets.
+pub const SWD_DRIVE_STRENGTH: OutputDriveStrength = OutputDriveStrength::EightMilliAmps;
+
+/// Pad slew rate of SWCLK and SWDIO. The slow edges ring less on long jumper wires and are
+/// still sharp enough for the highest SWCLK frequencies, switch to `Fast` for short traces
+/// with a matched target.
+pub const SWD_SLEW_RATE: OutputSlewRate = OutputSlewRate::Slow;
+
+/// Run SWD transfers on a PIO0 state machine instead of bit-banging them, for higher clock
+/// rates. Sequences and pin control are bit-banged either way.
+pub const PIO_SWD: bool = false;
+
+/// Gate the clocks of the peripherals the configuration does not use, see [`clock_gating`].
+pub const GATE_UNUSED_CLOCKS: bool = true;
+
+/// VID/PID and strings the probe enumerates with, see [`UsbIdentity`] for what host tools
+/// depend on.
+pub const USB_IDENTITY: UsbIdentity = UsbIdentity::DEFAULT;
+
+/// Advertise remote wakeup, and wake a suspended host that enabled it when the target sends
+/// UART, RTT or streamed SWO data.
+pub const USB_REMOTE_WAKEUP: bool = true;
+
+/// WebUSB landing page the browser offers when the probe is plugged in, an `https://` URL
+/// without the scheme, with the `webusb` feature.
+pub const WEBUSB_LANDING_PAGE: Option<&str> = None;
+
+/// Start of the target RAM scanned for the RTT control block, with the `rtt` feature.
+pub const RTT_SCAN_START: u32 = 0x2000_0000;
+
+/// Size of the target RAM scanned for the RTT control block, in bytes.
+pub const RTT_SCAN_SIZE: u32 = 64 * 1024;
+
+/// Period of the status task, in microseconds.
+pub const STATUS_PERIOD_US: u32 = 500_000;
+
+/// The [`PinAssignment::ASSIGNABLE`] pins as `DynPin`s, with the SWD pad settings.
+macro_rules! assignable_pins {
+    ($pins:ident, $($gpio:ident),+) => {
+        [$({
+            let mut pin = $pins.$gpio;
+            pin.set_drive_strength(SWD_DRIVE_STRENGTH);
+            pin.set_slew_rate(SWD_SLEW_RATE);
+            Some(DynPin::from(pin))
+        }),+]
+    };
+}
+
+/// Takes GPIO `num` out of the assignable pins.
+fn take_pin(assignable: &mut [Option<DynPin>], num: u8) -> DynPin {
+    let slot = assignable
+        .iter_mut()
+        .find(|pin| matches!(pin, Some(pin) if pin.id().num == num));
+    defmt::unwrap!(slot.and_then(Option::take))
+}
+
 #[inline(always)]
 pub fn setup(
     pac: pac::Peripherals,
     core: cortex_m::Peripherals,
     usb_bus: &'static mut MaybeUninit<UsbBusAllocator<UsbBus>>,
     delay: &'static mut MaybeUninit<Delay>,
-) -> (Rp2040Monotonic, LedPin, ProbeUsb, DapHandler) {
+) -> (
+    Rp2040Monotonic,
+    StatusTimer,
+    Option<AdcReader>,
+    Liveness,
+    StatusLed,
+    ProbeUsb,
+    DapHandler,
+) {
     let mut resets = pac.RESETS;
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let clocks = defmt::unwrap!(init_clocks_and_plls(
@@ -38,6 +233,8 @@ pub fn setup(
         &mut watchdog,
     )
     .ok());
+    let system_clock = clocks.system_clock.freq().0;
+    dap::set_system_clock(system_clock);
 
     let usb_bus: &'static _ = usb_bus.write(UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
@@ -47,36 +244,176 @@ pub fn setup(
         &mut resets,
     )));
 
-    let probe_usb = ProbeUsb::new(&usb_bus);
-
     let sio = Sio::new(pac.SIO);
     let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut resets);
 
-    let led = pins.gpio25.into_push_pull_output();
-    let mut io = pins.gpio14;
-    let mut ck = pins.gpio15;
-    let reset = pins.gpio13;
+    let boot_jumper = pins.gpio22.into_pull_up_input();
+    if BOOT_JUMPER && bootloader::jumper_set(&boot_jumper, system_clock / 1000) {
+        bootloader::enter();
+    }
+
+    let mut led = pins.gpio25.into_push_pull_output();
+
+    let flash_config = flash_config::read();
+    let assignment = match flash_config.pins {
+        Some(assignment) => {
+            info!("Debug pins from flash: {}", assignment);
+            assignment
+        }
+        None => PinAssignment::DEFAULT,
+    };
+    let mut assignable = assignable_pins!(
+        pins, gpio6, gpio7, gpio8, gpio9, gpio12, gpio13, gpio14, gpio15, gpio20, gpio21
+    );
+    let mut swdio = take_pin(&mut assignable, assignment.swdio);
+    let mut swclk = take_pin(&mut assignable, assignment.swclk);
+    let reset = take_pin(&mut assignable, assignment.nreset);
+
+    let buffered_pins = if BUFFERED_PINS {
+        let mut io = pins.gpio3;
+        let mut ck = pins.gpio2;
+        io.set_drive_strength(SWD_DRIVE_STRENGTH);
+        io.set_slew_rate(SWD_SLEW_RATE);
+        ck.set_drive_strength(SWD_DRIVE_STRENGTH);
+        ck.set_slew_rate(SWD_SLEW_RATE);
+        Some(PinSet {
+            swdio: io.into(),
+            swclk: ck.into(),
+            nreset: pins.gpio4.into(),
+            timing: BUFFERED_TIMING,
+        })
+    } else {
+        None
+    };
+
+    let jtag_pins = if cfg!(feature = "jtag") {
+        Some(JtagPins {
+            tdi: pins.gpio10.into(),
+            tdo: pins.gpio11.into(),
+        })
+    } else {
+        None
+    };
+
+    let (connected, reset_active) = if STATUS_OUTPUTS {
+        (Some(pins.gpio16.into()), Some(pins.gpio17.into()))
+    } else {
+        (None, None)
+    };
+
+    let vtref = if VTREF_SENSE {
+        let mut adc_pins = AdcPins {
+            adc0: Some(pins.gpio26),
+            adc1: Some(pins.gpio27),
+            adc2: Some(pins.gpio28),
+            adc3: Some(pins.gpio29),
+        };
+        let pin = defmt::unwrap!(adc_pins.take(VTREF_CHANNEL));
+        let current = CURRENT_SENSE.map(|sense| {
+            let pin = defmt::unwrap!(adc_pins.take(sense.channel), "Current sense on Vtref");
+            (pin, sense)
+        });
+        Some(AdcReader::new(
+            Adc::new(pac.ADC, &mut resets),
+            pin,
+            VTREF_SCALE,
+            VTREF_THRESHOLD_MV,
+            current,
+        ))
+    } else {
+        None
+    };
+
+    #[cfg(feature = "swo")]
+    let swo = Some(Swo::new(
+        pac.UART1,
+        pac.PIO1,
+        pins.gpio5.into_mode(),
+        &mut resets,
+        clocks.peripheral_clock.freq().0,
+        system_clock,
+    ));
+    #[cfg(not(feature = "swo"))]
+    let swo = None;
+
+    let pio_swd = if PIO_SWD {
+        Some(PioSwd::new(pac.PIO0, &mut resets, system_clock))
+    } else {
+        None
+    };
+
+    if TARGET_POWER_SWITCH {
+        target_power::init(pins.gpio19.into());
+    }
 
-    // High speed IO
-    io.set_drive_strength(OutputDriveStrength::TwelveMilliAmps);
-    io.set_slew_rate(OutputSlewRate::Fast);
-    ck.set_drive_strength(OutputDriveStrength::TwelveMilliAmps);
-    ck.set_slew_rate(OutputSlewRate::Fast);
+    let liveness_output = if LIVENESS_OUTPUT {
+        Some(pins.gpio18.into())
+    } else {
+        None
+    };
 
-    let delay = delay.write(Delay::new(core.SYST, clocks.system_clock.freq().0));
+    self_check::set_loopback_pins(swdio.id().num, swclk.id().num);
+    if SELF_CHECK && !HIGH_Z_UNTIL_CONNECT {
+        self_check::run(&mut swdio, &mut swclk, &mut led);
+    }
 
-    const GIT_VERSION: &'static str = git_version::git_version!();
+    #[cfg(feature = "cdc")]
+    let bridge = UartBridge::new(
+        pac.UART0,
+        pins.gpio0.into_mode(),
+        pins.gpio1.into_mode(),
+        &mut resets,
+        clocks.peripheral_clock.freq().0,
+    );
+
+    // Only enumerate after the self-check
+    let probe_usb = ProbeUsb::new(
+        &usb_bus,
+        USB_IDENTITY,
+        #[cfg(feature = "cdc")]
+        bridge,
+    );
+
+    let delay = delay.write(Delay::new(core.SYST, system_clock));
 
     let dap_hander = dap::create_dap(
-        GIT_VERSION,
-        io.into(),
-        ck.into(),
-        reset.into(),
-        clocks.system_clock.freq().0,
+        build_info::GIT_VERSION,
+        swdio,
+        swclk,
+        reset,
+        system_clock,
+        flash_config.frequency.unwrap_or(dap::DEFAULT_FREQUENCY),
+        MAX_SWCLK_FREQUENCY,
+        buffered_pins,
+        jtag_pins,
+        pio_swd,
+        reset_active,
+        Leds::new(connected),
+        swo,
+        CONNECT_PROFILE,
+        CONNECT_SEQUENCE,
+        HIGH_Z_UNTIL_CONNECT,
         delay,
     );
 
+    bus_park::set_park_after(PARK_AFTER_US);
+
+    if GATE_UNUSED_CLOCKS {
+        clock_gating::gate_unused(VTREF_SENSE, PIO_SWD);
+    }
+
+    let led = StatusLed::new(led, pac.PWM, &mut resets, system_clock);
     let mono = Rp2040Monotonic::new(pac.TIMER);
+    let status_timer = StatusTimer::new(STATUS_PERIOD_US);
+    let liveness = Liveness::new(watchdog, WATCHDOG_TIMEOUT_US, liveness_output);
 
-    (mono, led, probe_usb, dap_hander)
+    (
+        mono,
+        status_timer,
+        vtref,
+        liveness,
+        led,
+        probe_usb,
+        dap_hander,
+    )
 }
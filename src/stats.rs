@@ -0,0 +1,163 @@
+//! Signal integrity statistics, error and retry counters, the ACK latency and the host command
+//! statistics.
+//!
+//! Every counter keeps a session and a lifetime total. The session totals are zeroed on
+//! `DAP_Connect` when [`reset_on_connect`] is enabled, the lifetime totals count since boot.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub struct Counter {
+    session: AtomicU32,
+    lifetime: AtomicU32,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Counter {
+            session: AtomicU32::new(0),
+            lifetime: AtomicU32::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u32) {
+        // NOTE(no-CAS) counters are only updated from the DAP command path, which runs at one
+        // priority
+        let session = self.session.load(Ordering::Relaxed);
+        self.session
+            .store(session.wrapping_add(n), Ordering::Relaxed);
+        let lifetime = self.lifetime.load(Ordering::Relaxed);
+        self.lifetime
+            .store(lifetime.wrapping_add(n), Ordering::Relaxed);
+    }
+
+    pub fn session(&self) -> u32 {
+        self.session.load(Ordering::Relaxed)
+    }
+
+    pub fn lifetime(&self) -> u32 {
+        self.lifetime.load(Ordering::Relaxed)
+    }
+
+    fn reset_session(&self) {
+        self.session.store(0, Ordering::Relaxed);
+    }
+}
+
+pub static WAIT: Counter = Counter::new();
+pub static FAULT: Counter = Counter::new();
+pub static NO_ACK: Counter = Counter::new();
+pub static PARITY: Counter = Counter::new();
+pub static PARITY_RETRIES: Counter = Counter::new();
+
+/// All counters, in the order they are reported by the diagnostic command.
+pub static COUNTERS: [&Counter; 5] = [&WAIT, &FAULT, &NO_ACK, &PARITY, &PARITY_RETRIES];
+
+/// Number of commands of one type issued by the host and the transfers they requested.
+pub struct CommandCounter {
+    pub commands: Counter,
+    pub transfers: Counter,
+}
+
+impl CommandCounter {
+    const fn new() -> Self {
+        CommandCounter {
+            commands: Counter::new(),
+            transfers: Counter::new(),
+        }
+    }
+
+    pub fn record(&self, transfers: u32) {
+        self.commands.increment();
+        self.transfers.add(transfers);
+    }
+
+    /// Average number of transfers per command in this session, `0` without commands.
+    pub fn session_average(&self) -> u32 {
+        match self.commands.session() {
+            0 => 0,
+            commands => self.transfers.session() / commands,
+        }
+    }
+
+    fn reset_session(&self) {
+        self.commands.reset_session();
+        self.transfers.reset_session();
+    }
+}
+
+pub static DAP_TRANSFER: CommandCounter = CommandCounter::new();
+pub static DAP_TRANSFER_BLOCK: CommandCounter = CommandCounter::new();
+
+/// All command counters, in the order they are reported by the diagnostic command.
+pub static COMMANDS: [&CommandCounter; 2] = [&DAP_TRANSFER, &DAP_TRANSFER_BLOCK];
+
+static ACK_LATENCY_NS: AtomicU32 = AtomicU32::new(0);
+
+/// Time from the end of the request to sampling the first bit of a valid ACK, in the last
+/// transfer that got one.
+pub fn ack_latency_ns() -> u32 {
+    ACK_LATENCY_NS.load(Ordering::Relaxed)
+}
+
+pub fn record_ack_latency(ns: u32) {
+    ACK_LATENCY_NS.store(ns, Ordering::Relaxed);
+}
+
+static RESET_ON_CONNECT: AtomicBool = AtomicBool::new(true);
+
+/// Whether `DAP_Connect` zeroes the session totals, enabled by default.
+pub fn reset_on_connect() -> bool {
+    RESET_ON_CONNECT.load(Ordering::Relaxed)
+}
+
+pub fn set_reset_on_connect(enabled: bool) {
+    RESET_ON_CONNECT.store(enabled, Ordering::Relaxed);
+}
+
+/// Start of a new session, zeroes the session totals if configured to.
+pub fn on_connect() {
+    if reset_on_connect() {
+        for counter in COUNTERS.iter() {
+            counter.reset_session();
+        }
+        for counter in COMMANDS.iter() {
+            counter.reset_session();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_reset_keeps_the_lifetime_total() {
+        let counter = Counter::new();
+        counter.add(3);
+        counter.increment();
+        assert_eq!((counter.session(), counter.lifetime()), (4, 4));
+
+        counter.reset_session();
+        counter.increment();
+        assert_eq!((counter.session(), counter.lifetime()), (1, 5));
+    }
+
+    #[test]
+    fn connect_zeroes_the_sessions_when_enabled() {
+        WAIT.add(2);
+        let lifetime = WAIT.lifetime();
+
+        set_reset_on_connect(false);
+        on_connect();
+        assert_eq!(WAIT.session(), 2);
+
+        set_reset_on_connect(true);
+        on_connect();
+        assert_eq!(WAIT.session(), 0);
+        assert_eq!(WAIT.lifetime(), lifetime);
+    }
+}
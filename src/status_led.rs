@@ -0,0 +1,121 @@
+//! The on-board LED, driven by PWM slice 4 (GPIO25 is its channel B).
+//!
+//! The status task sets the LED off, on or breathing. Off and on are a 0 % and 100 % duty cycle,
+//! breathing ramps the duty cycle up and down from the PWM wrap interrupt, which is only enabled
+//! while breathing. The slice is accessed with raw registers from both, like the status timer
+//! alarm.
+
+use crate::setup::LedPin;
+use core::sync::atomic::{AtomicU16, Ordering};
+use rp_pico::hal::{
+    gpio::{bank0::Gpio25, FunctionPwm, Pin},
+    pac,
+};
+
+const SLICE: usize = 4;
+
+/// PWM frequency, fast enough to not flicker.
+const PWM_HZ: u32 = 250;
+
+/// Counter wrap value, a duty cycle of `TOP + 1` keeps the output high.
+const TOP: u16 = 9_999;
+
+/// Wraps per ramp, a breath is a ramp up and a ramp down (2 s at 250 Hz).
+const RAMP_WRAPS: u16 = 250;
+
+/// Position of the breath, in wraps.
+static PHASE: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LedState {
+    Off,
+    On,
+    Breathing,
+}
+
+pub struct StatusLed {
+    _pin: Pin<Gpio25, FunctionPwm>,
+    _pwm: pac::PWM,
+    state: LedState,
+}
+
+impl StatusLed {
+    /// Takes the PWM out of reset and runs slice 4 at [`PWM_HZ`] with the LED off.
+    pub fn new(pin: LedPin, pwm: pac::PWM, resets: &mut pac::RESETS, system_clock: u32) -> Self {
+        resets.reset.modify(|_, w| w.pwm().clear_bit());
+        while resets.reset_done.read().pwm().bit_is_clear() {}
+
+        let ch = &pwm.ch[SLICE];
+        let div = (system_clock / (PWM_HZ * (TOP as u32 + 1))).clamp(1, 255);
+        ch.div.write(|w| unsafe { w.int().bits(div as u8) });
+        ch.top.write(|w| unsafe { w.top().bits(TOP) });
+        ch.cc.write(|w| unsafe { w.b().bits(0) });
+        ch.csr.write(|w| w.en().set_bit());
+
+        StatusLed {
+            _pin: pin.into_mode(),
+            _pwm: pwm,
+            state: LedState::Off,
+        }
+    }
+
+    pub fn set(&mut self, state: LedState) {
+        if state == self.state {
+            return;
+        }
+        self.state = state;
+
+        let pwm = Self::pwm();
+        let breathing = state == LedState::Breathing;
+        // NOTE(unsafe) only the bit of this slice is touched
+        pwm.inte.modify(|r, w| unsafe {
+            w.bits(if breathing {
+                r.bits() | (1 << SLICE)
+            } else {
+                r.bits() & !(1 << SLICE)
+            })
+        });
+
+        match state {
+            LedState::Off => set_duty(0),
+            LedState::On => set_duty(TOP + 1),
+            LedState::Breathing => PHASE.store(0, Ordering::Relaxed),
+        }
+    }
+
+    #[inline(always)]
+    fn pwm() -> &'static pac::pwm::RegisterBlock {
+        // NOTE(unsafe) the slice is owned by `StatusLed`, `on_wrap_interrupt` only runs while
+        // it enabled the wrap interrupt
+        unsafe { &*pac::PWM::ptr() }
+    }
+}
+
+fn set_duty(duty: u16) {
+    StatusLed::pwm().ch[SLICE]
+        .cc
+        .modify(|_, w| unsafe { w.b().bits(duty) });
+}
+
+/// Acknowledges the wrap and steps the breath, call from the `PWM_IRQ_WRAP` handler.
+pub fn on_wrap_interrupt() {
+    let pwm = StatusLed::pwm();
+    pwm.intr.write(|w| unsafe { w.bits(1 << SLICE) });
+
+    // A wrap still pending after `set` left breathing must not touch the duty cycle
+    if pwm.inte.read().bits() & (1 << SLICE) == 0 {
+        return;
+    }
+
+    let phase = (PHASE.load(Ordering::Relaxed) + 1) % (2 * RAMP_WRAPS);
+    PHASE.store(phase, Ordering::Relaxed);
+
+    // Squared, so the brightness looks like it changes evenly
+    let ramp = if phase < RAMP_WRAPS {
+        phase
+    } else {
+        2 * RAMP_WRAPS - phase
+    } as u32;
+    let duty = (TOP as u32 + 1) * ramp * ramp / (RAMP_WRAPS as u32 * RAMP_WRAPS as u32);
+    set_duty(duty as u16);
+}
@@ -0,0 +1,55 @@
+//! Periodic tick for the status (LED) task on a dedicated TIMER alarm.
+//!
+//! The RP2040 TIMER has four alarms, allocated as follows:
+//!
+//! | Alarm | IRQ         | User                                      |
+//! | ----- | ----------- | ----------------------------------------- |
+//! | 0     | TIMER_IRQ_0 | `Rp2040Monotonic`, RTIC `spawn_after` etc |
+//! | 1     | TIMER_IRQ_1 | `StatusTimer`, the status task            |
+//! | 2, 3  | -           | Free                                      |
+//!
+//! As the status task is bound directly to its own alarm it never queues on the monotonic, so
+//! anything else scheduled on the monotonic does not contend with the periodic status work.
+
+use rp_pico::hal::pac;
+
+const ALARM: u32 = 1;
+
+pub struct StatusTimer {
+    period_us: u32,
+    target: u32,
+}
+
+impl StatusTimer {
+    /// Starts a periodic alarm firing `TIMER_IRQ_1` every `period_us`.
+    ///
+    /// The TIMER itself is owned by the monotonic, this only touches the alarm 1 registers.
+    pub fn new(period_us: u32) -> Self {
+        let timer = Self::timer();
+        let target = timer.timerawl.read().bits().wrapping_add(period_us);
+
+        // NOTE(unsafe) the alarm registers take any 32 bit value
+        timer.alarm1.write(|w| unsafe { w.bits(target) });
+        timer
+            .inte
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << ALARM)) });
+
+        StatusTimer { period_us, target }
+    }
+
+    /// Acknowledges the interrupt and re-arms the alarm, call from the `TIMER_IRQ_1` handler.
+    pub fn on_interrupt(&mut self) {
+        let timer = Self::timer();
+        timer.intr.write(|w| unsafe { w.bits(1 << ALARM) });
+
+        // Schedule from the last target rather than from now to not accumulate drift
+        self.target = self.target.wrapping_add(self.period_us);
+        timer.alarm1.write(|w| unsafe { w.bits(self.target) });
+    }
+
+    #[inline(always)]
+    fn timer() -> &'static pac::timer::RegisterBlock {
+        // NOTE(unsafe) only the alarm 1 registers and its interrupt bits are accessed
+        unsafe { &*pac::TIMER::ptr() }
+    }
+}
@@ -0,0 +1,205 @@
+//! `DAP_SWD_Sequence`, SWDIO bit sequences clocked in either direction, as used for custom
+//! resets and the multi-drop target selection.
+//!
+//! The DAP handler has no SWD sequences of its own, so they are clocked here on the pins SWD
+//! connected on, with raw SIO accesses like the bus parking. The pins are switched to SIO for
+//! the command and restored afterwards, so the pin drivers of the handler (or the PIO) find them
+//! as they left them. SWCLK runs at the achieved SWCLK frequency. Input bits are sampled before
+//! the rising edge, like the data phase of a read.
+
+use crate::{bus_park, dap};
+use defmt::*;
+use rp_pico::hal::pac;
+
+pub const ID_DAP_SWD_SEQUENCE: u8 = 0x1d;
+
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xff;
+
+/// Sequence info byte, clock cycles in bits 5:0 with 0 meaning 64, SWDIO input in bit 7.
+const INPUT: u8 = 1 << 7;
+
+const FUNCSEL_SIO: u32 = 5;
+
+/// Clock cycles of a sequence and the bytes its data takes.
+fn length(info: u8) -> (usize, usize) {
+    let cycles = match info & 0x3f {
+        0 => 64,
+        n => n as usize,
+    };
+    (cycles, (cycles + 7) / 8)
+}
+
+/// Request: `[0x1d, count, (info, swdio[n] if output) * count]`, response:
+/// `[0x1d, status, (swdio[n] if input) * count]`.
+pub fn process(report: &[u8], resp: &mut [u8]) -> usize {
+    resp[0] = ID_DAP_SWD_SEQUENCE;
+    resp[1] = DAP_ERROR;
+
+    let (swdio, swclk) = match bus_park::pins() {
+        Some(pins) => pins,
+        None => {
+            warn!("SWD sequence while SWD is not connected");
+            return 2;
+        }
+    };
+    if !fits(report, resp.len()) {
+        warn!("SWD sequence request malformed");
+        return 2;
+    }
+
+    bus_park::activity();
+    let len = 2 + run(&report[2..], report[1], swdio, swclk, &mut resp[2..]);
+    resp[1] = DAP_OK;
+    len
+}
+
+/// Whether the request carries the data of all its output sequences and the input data fits
+/// into a response of `resp_len` bytes.
+fn fits(report: &[u8], resp_len: usize) -> bool {
+    let mut data = &report[2..];
+    let mut input = 2;
+    for _ in 0..report[1] {
+        let (&info, rest) = match data.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+        let (_, bytes) = length(info);
+        if info & INPUT != 0 {
+            input += bytes;
+            data = rest;
+        } else if rest.len() >= bytes {
+            data = &rest[bytes..];
+        } else {
+            return false;
+        }
+    }
+    input <= resp_len
+}
+
+/// Clocks `count` sequences, returns the number of SWDIO bytes captured into `rx`.
+fn run(mut data: &[u8], count: u8, swdio: u8, swclk: u8, rx: &mut [u8]) -> usize {
+    // NOTE(unsafe) the pins are owned by the DAP handler, which is locked by the USB task
+    // running this, and restored before returning
+    let sio = unsafe { &*pac::SIO::ptr() };
+    let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+
+    let (dio_mask, clk_mask) = (1 << swdio, 1 << swclk);
+    let (swdio, swclk) = (swdio as usize, swclk as usize);
+    let mask = dio_mask | clk_mask;
+
+    let ctrl = [
+        io.gpio[swdio].gpio_ctrl.read().bits(),
+        io.gpio[swclk].gpio_ctrl.read().bits(),
+    ];
+    let pad = pads.gpio[swdio].read().bits();
+    let oe = sio.gpio_oe.read().bits() & mask;
+    let out = sio.gpio_out.read().bits() & mask;
+
+    // A driven SWCLK keeps its level, so no extra edge is clocked, an undriven one starts high
+    if oe & clk_mask == 0 {
+        sio.gpio_out_set.write(|w| unsafe { w.bits(clk_mask) });
+    }
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(clk_mask) });
+    for &pin in &[swdio, swclk] {
+        io.gpio[pin]
+            .gpio_ctrl
+            .write(|w| unsafe { w.bits(FUNCSEL_SIO) });
+    }
+    pads.gpio[swdio].modify(|_, w| w.ie().set_bit().od().clear_bit());
+
+    let frequency = match dap::achieved_frequency() {
+        0 => dap::DEFAULT_FREQUENCY,
+        frequency => frequency,
+    };
+    let half_period = (dap::cycles_per_ms() * 1000 / (2 * frequency)).max(1);
+    let clock = |high: bool| {
+        if high {
+            sio.gpio_out_set.write(|w| unsafe { w.bits(clk_mask) });
+        } else {
+            sio.gpio_out_clr.write(|w| unsafe { w.bits(clk_mask) });
+        }
+        cortex_m::asm::delay(half_period);
+    };
+
+    let mut rx_len = 0;
+    for _ in 0..count {
+        // Checked by `fits`
+        let (&info, rest) = match data.split_first() {
+            Some(split) => split,
+            None => break,
+        };
+        let (cycles, bytes) = length(info);
+
+        if info & INPUT != 0 {
+            sio.gpio_oe_clr.write(|w| unsafe { w.bits(dio_mask) });
+            let captured = &mut rx[rx_len..rx_len + bytes];
+            captured.fill(0);
+            for bit in 0..cycles {
+                clock(false);
+                if sio.gpio_in.read().bits() & dio_mask != 0 {
+                    captured[bit / 8] |= 1 << (bit % 8);
+                }
+                clock(true);
+            }
+            rx_len += bytes;
+            data = rest;
+        } else {
+            sio.gpio_oe_set.write(|w| unsafe { w.bits(dio_mask) });
+            for bit in 0..cycles {
+                if rest[bit / 8] & (1 << (bit % 8)) != 0 {
+                    sio.gpio_out_set.write(|w| unsafe { w.bits(dio_mask) });
+                } else {
+                    sio.gpio_out_clr.write(|w| unsafe { w.bits(dio_mask) });
+                }
+                clock(false);
+                clock(true);
+            }
+            data = &rest[bytes..];
+        }
+    }
+
+    sio.gpio_oe_clr.write(|w| unsafe { w.bits(mask & !oe) });
+    sio.gpio_oe_set.write(|w| unsafe { w.bits(oe) });
+    sio.gpio_out_clr.write(|w| unsafe { w.bits(mask & !out) });
+    sio.gpio_out_set.write(|w| unsafe { w.bits(out) });
+    pads.gpio[swdio].write(|w| unsafe { w.bits(pad) });
+    io.gpio[swdio]
+        .gpio_ctrl
+        .write(|w| unsafe { w.bits(ctrl[0]) });
+    io.gpio[swclk]
+        .gpio_ctrl
+        .write(|w| unsafe { w.bits(ctrl[1]) });
+
+    rx_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_length() {
+        assert_eq!(length(1), (1, 1));
+        assert_eq!(length(8), (8, 1));
+        assert_eq!(length(INPUT | 9), (9, 2));
+        assert_eq!(length(0), (64, 8));
+        assert_eq!(length(INPUT), (64, 8));
+    }
+
+    #[test]
+    fn mixed_sequences_fit() {
+        // 12 bits out, 33 bits in, 8 bits out
+        let report = [ID_DAP_SWD_SEQUENCE, 3, 12, 0xff, 0x0f, INPUT | 33, 8, 0xa5];
+        assert!(fits(&report, 2 + 5));
+        assert!(!fits(&report, 2 + 4));
+    }
+
+    #[test]
+    fn missing_output_data() {
+        assert!(!fits(&[ID_DAP_SWD_SEQUENCE, 1, 12, 0xff], 64));
+        assert!(!fits(&[ID_DAP_SWD_SEQUENCE, 2, INPUT | 8], 64));
+        assert!(fits(&[ID_DAP_SWD_SEQUENCE, 0], 2));
+    }
+}
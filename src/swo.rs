@@ -0,0 +1,603 @@
+//! SWO trace capture and buffering.
+//!
+//! In UART (NRZ) mode the trace is received by UART1 on GPIO5. The UART interrupt drains the
+//! receive FIFO into [`BUFFER`], which is drained by the DAP `DAP_SWO_Data` path or the snapshot
+//! vendor command, or with the endpoint transport by the USB task, which streams it out the
+//! DAPv2 trace endpoint. Only one of them sees each byte: `DAP_SWO_Data` returns nothing while
+//! streaming.
+//!
+//! In Manchester mode PIO1 state machine 0 decodes the same pin and its interrupt feeds the
+//! same buffer. The pin stays in its UART function, the PIO reads the input synchronizer
+//! whatever the function.
+//!
+//! A baud rate of 0 auto-detects the baud rate: PIO1 state machine 1 times the high and low runs
+//! of the line for a while and the shortest one is taken as a bit (half a bit for Manchester).
+//! Without trace activity the previous baud rate is kept.
+//!
+//! The UART and PIO are driven through their registers rather than the HAL drivers, which have
+//! no receive only UART, and can not change the divisor of a running state machine.
+
+use crate::{
+    ring_buffer::RingBuffer,
+    timer_delay,
+    uart::{ALL_INTERRUPTS, DR_ERRORS, DR_OE, OEIM, RTIM, RXE, RXFE, RXIM, UARTEN},
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use dap_rs::swo;
+use defmt::*;
+use rp_pico::hal::{
+    gpio::{bank0::Gpio5, FunctionUart, Pin},
+    pac::{self, Interrupt, NVIC},
+};
+
+/// Size of the trace buffer in bytes.
+pub const BUFFER_SIZE: usize = 4096;
+
+/// Trace bytes captured and not yet handed to the host.
+pub static BUFFER: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+
+/// A framing, parity or break error was received.
+static TRACE_ERROR: AtomicBool = AtomicBool::new(false);
+/// Trace bytes were lost, in the UART FIFO or because [`BUFFER`] was full.
+static OVERRUN: AtomicBool = AtomicBool::new(false);
+/// Capture is running with the trace endpoint as transport.
+static STREAMING: AtomicBool = AtomicBool::new(false);
+
+/// `DAP_SWO_Transport` value of the separate trace endpoint.
+const TRANSPORT_ENDPOINT: u8 = 2;
+
+/// PIO cycles per Manchester bit.
+const MANCHESTER_OVERSAMPLING: u32 = 16;
+
+/// SWO pin, read by the Manchester decoder.
+const SWO_PIN: u32 = 5;
+
+// PIO `CTRL`, `FSTAT` and `FDEBUG` bits of state machine 0
+const SM0_ENABLE: u32 = 1 << 0;
+const SM0_RESTART: u32 = 1 << 4;
+const SM0_CLKDIV_RESTART: u32 = 1 << 8;
+const SM0_RXEMPTY: u32 = 1 << 8;
+const SM0_RXSTALL: u32 = 1 << 0;
+
+// PIO `CTRL` and `FSTAT` bits of state machine 1, the edge timer
+const SM1_ENABLE: u32 = 1 << 1;
+const SM1_RESTART: u32 = 1 << 5;
+const SM1_CLKDIV_RESTART: u32 = 1 << 9;
+const SM1_RXEMPTY: u32 = 1 << 9;
+
+/// PIO cycles per count of the edge timer loops.
+const DETECT_LOOP_CYCLES: u32 = 2;
+/// PIO cycles between two runs that the edge timer does not count.
+const DETECT_OVERHEAD_CYCLES: u32 = 3;
+/// Runs timed before the shortest is taken as the bit period.
+const DETECT_RUNS: u32 = 256;
+/// How long auto-detection listens at most, it blocks the DAP command meanwhile.
+const DETECT_TIMEOUT_US: u32 = 100_000;
+
+// PIO `SM0_SHIFTCTRL`: shift right, autopush at 8 bits, joined RX FIFO
+const FJOIN_RX: u32 = 1 << 31;
+const PUSH_THRESH_8: u32 = 8 << 20;
+const IN_SHIFTDIR_RIGHT: u32 = 1 << 18;
+const AUTOPUSH: u32 = 1 << 16;
+
+// PIO `IRQ0_INTE` bits
+const SM0_RXNEMPTY: u32 = 1 << 0;
+
+/// Copies up to `out.len()` buffered bytes into `out`, consuming them if `consume` is set.
+/// Returns the number of bytes copied, 0 when the buffer is empty.
+///
+/// While the USB task streams the buffer out the trace endpoint it is the only consumer, like
+/// for `DAP_SWO_Data` the bytes are then only peeked at.
+pub fn snapshot(out: &mut [u8], consume: bool) -> usize {
+    if consume && !streaming() {
+        BUFFER.pop_slice(out)
+    } else {
+        BUFFER.peek_slice(out)
+    }
+}
+
+/// Whether the USB task should stream [`BUFFER`] out the trace endpoint.
+pub fn streaming() -> bool {
+    STREAMING.load(Ordering::Relaxed)
+}
+
+/// Lets the USB task stream what was captured.
+fn kick_stream() {
+    if streaming() && !BUFFER.is_empty() {
+        NVIC::pend(Interrupt::USBCTRL_IRQ);
+    }
+}
+
+/// Drains the UART receive FIFO into [`BUFFER`], call from the `UART1_IRQ` handler.
+pub fn on_uart_interrupt() {
+    let uart = uart();
+
+    while uart.uartfr.read().bits() & RXFE == 0 {
+        let dr = uart.uartdr.read().bits();
+        if dr & DR_OE != 0 {
+            OVERRUN.store(true, Ordering::Relaxed);
+        }
+        if dr & DR_ERRORS != 0 {
+            TRACE_ERROR.store(true, Ordering::Relaxed);
+        }
+        if !BUFFER.push(dr as u8) {
+            OVERRUN.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // NOTE(unsafe) write one to clear register
+    uart.uarticr.write(|w| unsafe { w.bits(ALL_INTERRUPTS) });
+    kick_stream();
+}
+
+/// Drains the Manchester decoder RX FIFO into [`BUFFER`], call from the `PIO1_IRQ_0` handler.
+pub fn on_pio_interrupt() {
+    let pio = pio();
+
+    while pio.fstat.read().bits() & SM0_RXEMPTY == 0 {
+        // Shifted in from the top, the byte is in bits 31:24
+        let byte = (pio.rxf[0].read().bits() >> 24) as u8;
+        if !BUFFER.push(byte) {
+            OVERRUN.store(true, Ordering::Relaxed);
+        }
+    }
+
+    if pio.fdebug.read().bits() & SM0_RXSTALL != 0 {
+        OVERRUN.store(true, Ordering::Relaxed);
+        // NOTE(unsafe) write one to clear register
+        pio.fdebug.write(|w| unsafe { w.bits(SM0_RXSTALL) });
+    }
+    kick_stream();
+}
+
+#[inline(always)]
+fn uart() -> &'static pac::uart0::RegisterBlock {
+    // NOTE(unsafe) owned by `Swo`, the interrupt handler only reads the FIFO and clears flags
+    unsafe { &*pac::UART1::ptr() }
+}
+
+#[inline(always)]
+fn pio() -> &'static pac::pio0::RegisterBlock {
+    // NOTE(unsafe) owned by `Swo`, the interrupt handler only reads the RX FIFO of SM0
+    unsafe { &*pac::PIO1::ptr() }
+}
+
+/// The 16.8 fixed point PIO clock divisor for a Manchester `baudrate`, clamped to at least 1.
+pub fn manchester_divisor(system_frequency: u32, baudrate: u32) -> u32 {
+    let div = 256 * system_frequency as u64 / (MANCHESTER_OVERSAMPLING as u64 * baudrate as u64);
+    div.clamp(0x100, 0xffff_ff) as u32
+}
+
+/// The baud rate the PIO clock divisor actually produces.
+pub fn manchester_achieved_baudrate(system_frequency: u32, divisor: u32) -> u32 {
+    (256 * system_frequency as u64 / (MANCHESTER_OVERSAMPLING as u64 * divisor as u64)) as u32
+}
+
+/// Moves an instruction assembled at 0 to `offset`, only jumps hold an address.
+fn relocate(instruction: u16, offset: u8) -> u16 {
+    if instruction & 0xe000 == 0 {
+        instruction + offset as u16
+    } else {
+        instruction
+    }
+}
+
+pub struct Swo {
+    _uart: pac::UART1,
+    _pio: pac::PIO1,
+    _pin: Pin<Gpio5, FunctionUart>,
+    peripheral_frequency: u32,
+    system_frequency: u32,
+    /// Program address of the edge timer.
+    edge_timer: u8,
+    mode: swo::SwoMode,
+    baudrate: u32,
+    active: bool,
+    endpoint_transport: bool,
+}
+
+impl defmt::Format for Swo {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Swo {{ uart_mode: {}, manchester_mode: {}, baudrate: {}, active: {}, endpoint_transport: {} }}",
+            self.uart_mode(),
+            self.manchester_mode(),
+            self.baudrate,
+            self.active,
+            self.endpoint_transport,
+        )
+    }
+}
+
+impl core::fmt::Debug for Swo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Swo")
+            .field("uart_mode", &self.uart_mode())
+            .field("manchester_mode", &self.manchester_mode())
+            .field("baudrate", &self.baudrate)
+            .field("active", &self.active)
+            .field("endpoint_transport", &self.endpoint_transport)
+            .finish()
+    }
+}
+
+impl Swo {
+    /// Takes UART1 and PIO1 out of reset and loads the Manchester decoder and the edge timer,
+    /// all stay disabled until capture is started or a baud rate detected.
+    pub fn new(
+        uart: pac::UART1,
+        pio: pac::PIO1,
+        pin: Pin<Gpio5, FunctionUart>,
+        resets: &mut pac::RESETS,
+        peripheral_frequency: u32,
+        system_frequency: u32,
+    ) -> Self {
+        resets
+            .reset
+            .modify(|_, w| w.uart1().clear_bit().pio1().clear_bit());
+        while resets.reset_done.read().uart1().bit_is_clear()
+            || resets.reset_done.read().pio1().bit_is_clear()
+        {}
+
+        // NOTE(unsafe) any value is valid for these registers
+        uart.uartcr.write(|w| unsafe { w.bits(0) });
+        uart.uartimsc
+            .write(|w| unsafe { w.bits(RXIM | RTIM | OEIM) });
+
+        let program = pio_proc::pio!(
+            32,
+            "
+; Manchester decoder, 16 cycles per bit
+;
+; - a bit is the level of its first half, the line idles low
+; - a frame starts with a 1 start bit, followed by whole bytes LSB first
+; - the bit clock is recovered from the mid-bit edge of every bit, the next bit is sampled 12
+;   cycles after it, both edge directions run the same `nop` to get there
+; - uses auto push with 8 bits limit, shifting right
+;
+; When no mid-bit edge follows a low first half the line went idle, the partial byte (the idle
+; level sampled as a 0) is dropped and the decoder resynchronizes on the next start bit.
+
+idle:
+    mov isr, null               ; drop the bits of an incomplete byte
+    wait 1 pin 0                ; rising edge of the start bit
+high:
+    wait 0 pin 0                ; falling mid-bit edge, the bit clock is synchronized from here
+bit:
+    nop [10]
+    in pins, 1                  ; 12 cycles after the edge, middle of the first half of the next bit
+    jmp pin high
+    set x, 4
+low:
+    jmp pin bit                 ; rising mid-bit edge, found within a cycle of the falling one
+    jmp x-- low
+    jmp idle                    ; no edge up to the end of the bit, the line is idle
+            "
+        )
+        .program;
+
+        // NOTE(unsafe) PIO1 is only used here, the program is loaded at 0 as assembled
+        for (slot, instruction) in pio.instr_mem.iter().zip(program.code.iter()) {
+            slot.write(|w| unsafe { w.bits(*instruction as u32) });
+        }
+        let sm = &pio.sm[0];
+        sm.sm_execctrl.write(|w| unsafe {
+            w.bits(
+                SWO_PIN << 24
+                    | (program.wrap.source as u32) << 12
+                    | (program.wrap.target as u32) << 7,
+            )
+        });
+        sm.sm_shiftctrl
+            .write(|w| unsafe { w.bits(FJOIN_RX | PUSH_THRESH_8 | IN_SHIFTDIR_RIGHT | AUTOPUSH) });
+        sm.sm_pinctrl.write(|w| unsafe { w.bits(SWO_PIN << 15) });
+        pio.sm_irq[0]
+            .irq_inte
+            .write(|w| unsafe { w.bits(SM0_RXNEMPTY) });
+
+        let edge_timer = pio_proc::pio!(
+            32,
+            "
+; Edge timer, pushes the length of every high and low run of the line
+;
+; - counts down from all ones in 2 cycle loops, the complement is the run length
+; - starts on a rising edge so the first run is whole
+; - runs at the system clock, 3 cycles between two runs are not counted
+
+    wait 0 pin 0
+    wait 1 pin 0
+.wrap_target
+    mov x, ~null
+high:
+    jmp x-- still_high
+still_high:
+    jmp pin high
+    mov isr, ~x
+    push noblock
+    mov x, ~null
+low:
+    jmp pin rising
+    jmp x-- low
+rising:
+    mov isr, ~x
+    push noblock
+.wrap
+            "
+        )
+        .program;
+
+        // NOTE(unsafe) loaded after the decoder, state machine 1 only runs while detecting
+        let offset = program.code.len() as u8;
+        for (slot, instruction) in pio.instr_mem[offset as usize..]
+            .iter()
+            .zip(edge_timer.code.iter())
+        {
+            slot.write(|w| unsafe { w.bits(relocate(*instruction, offset) as u32) });
+        }
+        let sm = &pio.sm[1];
+        sm.sm_clkdiv.write(|w| unsafe { w.bits(1 << 16) });
+        sm.sm_execctrl.write(|w| unsafe {
+            w.bits(
+                SWO_PIN << 24
+                    | (edge_timer.wrap.source as u32 + offset as u32) << 12
+                    | (edge_timer.wrap.target as u32 + offset as u32) << 7,
+            )
+        });
+        sm.sm_shiftctrl.write(|w| unsafe { w.bits(0) });
+        sm.sm_pinctrl.write(|w| unsafe { w.bits(SWO_PIN << 15) });
+
+        Swo {
+            _uart: uart,
+            _pio: pio,
+            _pin: pin,
+            peripheral_frequency,
+            system_frequency,
+            edge_timer: offset,
+            mode: swo::SwoMode::Off,
+            baudrate: 0,
+            active: false,
+            endpoint_transport: false,
+        }
+    }
+
+    fn uart_mode(&self) -> bool {
+        matches!(self.mode, swo::SwoMode::UART)
+    }
+
+    fn manchester_mode(&self) -> bool {
+        matches!(self.mode, swo::SwoMode::Manchester)
+    }
+
+    /// Starts the Manchester decoder from the top of the program, waiting for a start bit.
+    fn start_manchester(&mut self) {
+        let pio = pio();
+        // NOTE(unsafe) `jmp 0` encodes as 0, drop what the FIFO holds from an earlier capture
+        pio.ctrl
+            .write(|w| unsafe { w.bits(SM0_RESTART | SM0_CLKDIV_RESTART) });
+        pio.sm[0].sm_instr.write(|w| unsafe { w.bits(0) });
+        while pio.fstat.read().bits() & SM0_RXEMPTY == 0 {
+            let _ = pio.rxf[0].read();
+        }
+        pio.ctrl.write(|w| unsafe { w.bits(SM0_ENABLE) });
+    }
+
+    /// Times the runs of the SWO line until [`DETECT_RUNS`] are seen or [`DETECT_TIMEOUT_US`]
+    /// passed, and returns the baud rate of the shortest. `None` if the line did not toggle.
+    fn detect_baudrate(&mut self) -> Option<u32> {
+        let pio = pio();
+        // NOTE(unsafe) only the bits of state machine 1 are changed, `jmp` to the edge timer
+        pio.ctrl.modify(|r, w| unsafe {
+            w.bits(r.bits() & !SM1_ENABLE | SM1_RESTART | SM1_CLKDIV_RESTART)
+        });
+        pio.sm[1]
+            .sm_instr
+            .write(|w| unsafe { w.bits(self.edge_timer as u32) });
+        while pio.fstat.read().bits() & SM1_RXEMPTY == 0 {
+            let _ = pio.rxf[1].read();
+        }
+        pio.ctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() | SM1_ENABLE) });
+
+        let start = timer_delay::now_us();
+        let mut runs = 0;
+        let mut shortest = u32::MAX;
+        while runs < DETECT_RUNS && timer_delay::now_us().wrapping_sub(start) < DETECT_TIMEOUT_US {
+            if pio.fstat.read().bits() & SM1_RXEMPTY == 0 {
+                let count = pio.rxf[1].read().bits();
+                let cycles = count
+                    .saturating_mul(DETECT_LOOP_CYCLES)
+                    .saturating_add(DETECT_OVERHEAD_CYCLES);
+                shortest = shortest.min(cycles);
+                runs += 1;
+            }
+        }
+
+        // NOTE(unsafe) only the bit of state machine 1 is changed
+        pio.ctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() & !SM1_ENABLE) });
+
+        if runs == 0 {
+            return None;
+        }
+
+        // The shortest Manchester run is half a bit
+        let bit_cycles = if self.manchester_mode() {
+            2 * shortest
+        } else {
+            shortest
+        };
+        trace!("SWO runs timed: {}, shortest: {} cycles", runs, shortest);
+        Some(self.system_frequency / bit_cycles)
+    }
+
+    fn stop(&mut self) {
+        // NOTE(unsafe) disabling the UART and the state machine is always valid
+        uart().uartcr.write(|w| unsafe { w.bits(0) });
+        pio().ctrl.write(|w| unsafe { w.bits(0) });
+        self.active = false;
+        STREAMING.store(false, Ordering::Relaxed);
+    }
+}
+
+impl swo::Swo for Swo {
+    fn set_transport(&mut self, transport: swo::SwoTransport) {
+        // Applies from the next start, like the mode
+        self.endpoint_transport = transport as u8 == TRANSPORT_ENDPOINT;
+    }
+
+    fn set_mode(&mut self, mode: swo::SwoMode) {
+        // The decoder is only switched on the next start
+        self.stop();
+        self.mode = mode;
+    }
+
+    /// A `baudrate` of 0 detects the baud rate of the running trace, keeping the previous one
+    /// (0 if none was set) if the line is idle.
+    fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+        let baudrate = match baudrate {
+            0 => match self.detect_baudrate() {
+                Some(detected) => {
+                    debug!("SWO baudrate detected: {}", detected);
+                    detected
+                }
+                None => {
+                    warn!(
+                        "No SWO activity to detect the baudrate, keeping {}",
+                        self.baudrate
+                    );
+                    return self.baudrate;
+                }
+            },
+            baudrate => baudrate,
+        };
+
+        self.baudrate = if self.manchester_mode() {
+            let divisor = manchester_divisor(self.system_frequency, baudrate);
+            // NOTE(unsafe) the divisor is in range
+            pio().sm[0]
+                .sm_clkdiv
+                .write(|w| unsafe { w.bits(divisor << 8) });
+            manchester_achieved_baudrate(self.system_frequency, divisor)
+        } else {
+            crate::uart::configure(uart(), self.peripheral_frequency, baudrate)
+        };
+        debug!(
+            "SWO baudrate requested: {}, achieved: {}",
+            baudrate, self.baudrate
+        );
+        self.baudrate
+    }
+
+    fn set_control(&mut self, control: swo::SwoControl) {
+        match control {
+            swo::SwoControl::Start
+                if (self.uart_mode() || self.manchester_mode()) && self.baudrate > 0 =>
+            {
+                TRACE_ERROR.store(false, Ordering::Relaxed);
+                OVERRUN.store(false, Ordering::Relaxed);
+                if self.manchester_mode() {
+                    self.start_manchester();
+                } else {
+                    // NOTE(unsafe) receive only, TX stays disabled
+                    uart().uartcr.write(|w| unsafe { w.bits(UARTEN | RXE) });
+                }
+                self.active = true;
+                STREAMING.store(self.endpoint_transport, Ordering::Relaxed);
+            }
+            swo::SwoControl::Start => {
+                warn!("SWO start without a capture mode and baudrate");
+            }
+            swo::SwoControl::Stop => self.stop(),
+        }
+    }
+
+    fn polling_data(&mut self, buf: &mut [u8]) -> u32 {
+        if streaming() {
+            return 0;
+        }
+        BUFFER.pop_slice(buf) as u32
+    }
+
+    /// The USB task owns the trace endpoint, this only wakes it up.
+    fn streaming_data(&mut self) {
+        kick_stream();
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn bytes_available(&self) -> u32 {
+        BUFFER.len() as u32
+    }
+
+    fn buffer_size(&self) -> u32 {
+        BUFFER_SIZE as u32
+    }
+
+    fn support(&self) -> swo::SwoSupport {
+        swo::SwoSupport {
+            uart: true,
+            manchester: true,
+        }
+    }
+
+    fn status(&mut self) -> swo::SwoStatus {
+        swo::SwoStatus {
+            active: self.active,
+            trace_error: TRACE_ERROR.load(Ordering::Relaxed),
+            trace_overrun: OVERRUN.load(Ordering::Relaxed),
+            bytes_available: BUFFER.len() as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisor_of_an_exact_rate() {
+        let divisor = manchester_divisor(125_000_000, 1_000_000);
+        assert_eq!(divisor, 2000);
+        assert_eq!(
+            manchester_achieved_baudrate(125_000_000, divisor),
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn divisor_is_clamped() {
+        // 16 times the rate does not fit into u32
+        assert_eq!(manchester_divisor(125_000_000, 300_000_000), 0x100);
+        assert_eq!(manchester_divisor(125_000_000, 1), 0xffff_ff);
+        assert_eq!(manchester_achieved_baudrate(125_000_000, 0x100), 7_812_500);
+    }
+
+    #[test]
+    fn snapshot_consumes_only_when_asked() {
+        BUFFER.push_slice(b"trace");
+        let mut out = [0; 8];
+
+        assert_eq!(snapshot(&mut out, false), 5);
+        assert_eq!(&out[..5], b"trace");
+        assert_eq!(BUFFER.len(), 5);
+
+        out.fill(0);
+        assert_eq!(snapshot(&mut out[..3], true), 3);
+        assert_eq!(&out[..3], b"tra");
+        assert_eq!(snapshot(&mut out, true), 2);
+        assert_eq!(&out[..2], b"ce");
+        assert_eq!(snapshot(&mut out, true), 0);
+
+        // The USB task owns the buffer while it streams
+        BUFFER.push_slice(b"trace");
+        STREAMING.store(true, Ordering::Relaxed);
+        assert_eq!(snapshot(&mut out, true), 5);
+        assert_eq!(BUFFER.len(), 5);
+        STREAMING.store(false, Ordering::Relaxed);
+        assert_eq!(snapshot(&mut out, true), 5);
+        assert_eq!(BUFFER.len(), 0);
+    }
+}
@@ -0,0 +1,320 @@
+//! Higher level target connect for standalone use of the DAP handler.
+//!
+//! [`connect`] runs the whole sequence a debugger would otherwise orchestrate: `DAP_Connect`
+//! (which emits the switch sequence, line reset and DPIDR read), the debug power-up, an AP scan
+//! and the ROM table base read. Everything goes through standard DAP commands, so the handler
+//! state stays consistent with what a host would see.
+//!
+//! After a connect, [`select_mem_ap`] sets AP 0 up for word accesses, which [`read_memory`] and
+//! [`write_word`] rely on.
+
+use crate::{dap, setup::DapHandler, transfer_block::CommandProcessor};
+use dap_rs::dap::DapVersion;
+use defmt::*;
+
+const ID_DAP_CONNECT: u8 = 0x02;
+const ID_DAP_TRANSFER: u8 = 0x05;
+const PORT_SWD: u8 = 1;
+const STATUS_OK: u8 = 0x01;
+
+/// `DAP_Transfer` requests.
+const REQUEST_DP_READ_DPIDR: u8 = 0x02;
+const REQUEST_DP_WRITE_CTRL_STAT: u8 = 0x04;
+const REQUEST_DP_READ_CTRL_STAT: u8 = 0x06;
+const REQUEST_DP_WRITE_SELECT: u8 = 0x08;
+const REQUEST_AP_READ_BASE: u8 = 0x0b;
+const REQUEST_AP_READ_IDR: u8 = 0x0f;
+const REQUEST_AP_WRITE_CSW: u8 = 0x01;
+const REQUEST_AP_WRITE_TAR: u8 = 0x05;
+const REQUEST_AP_WRITE_DRW: u8 = 0x0d;
+const REQUEST_AP_READ_DRW: u8 = 0x0f;
+
+/// CSW for 32 bit privileged data accesses with TAR auto-increment.
+const CSW_WORD_INCREMENT: u32 = 0x2300_0012;
+
+/// Reads per `DAP_Transfer`, limited by the 64 byte response.
+const READS_PER_TRANSFER: usize = 15;
+
+/// TAR auto-increment is only guaranteed within 1 KiB.
+const AUTO_INCREMENT_RANGE: u32 = 0x400;
+
+const CSYSPWRUPREQ: u32 = 1 << 30;
+const CDBGPWRUPREQ: u32 = 1 << 28;
+const CSYSPWRUPACK: u32 = 1 << 31;
+const CDBGPWRUPACK: u32 = 1 << 29;
+
+/// SELECT bank holding IDR and BASE.
+const BANK_ID: u32 = 0xf << 4;
+
+/// IDR class of a MEM-AP.
+const CLASS_MEM_AP: u32 = 0x8;
+
+/// Number of CTRL/STAT polls for the power-up acknowledge.
+const POWER_UP_POLLS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct TargetInfo {
+    pub dpidr: u32,
+    /// DP architecture version from DPIDR, 1 for DPv1, 2 for DPv2 etc.
+    pub dp_version: u8,
+    /// Number of consecutive APs with a non-zero IDR, starting at AP 0.
+    pub ap_count: u16,
+    /// The BASE register of AP 0 if it is a MEM-AP, with the ROM table address in bits 31:12.
+    pub rom_base: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectError {
+    /// `DAP_Connect` did not select SWD.
+    Connect,
+    /// A transfer failed with this `DAP_Transfer` response status.
+    Transfer(u8),
+    /// The debug power domains did not acknowledge the power-up request.
+    PowerUp,
+}
+
+/// Connects over SWD, powers up the debug domain and reads the target identification.
+pub fn connect(dap: &mut DapHandler) -> Result<TargetInfo, ConnectError> {
+    let info = identify(dap)?;
+    info!("Target: {}", info);
+    Ok(info)
+}
+
+fn identify(dap: &mut impl CommandProcessor) -> Result<TargetInfo, ConnectError> {
+    let mut resp = [0; 64];
+    dap.process_command(&[ID_DAP_CONNECT, PORT_SWD], &mut resp, DapVersion::V2);
+    if resp[1] != PORT_SWD {
+        return Err(ConnectError::Connect);
+    }
+
+    let dpidr = transfer(dap, REQUEST_DP_READ_DPIDR, None)?;
+
+    transfer(
+        dap,
+        REQUEST_DP_WRITE_CTRL_STAT,
+        Some(CSYSPWRUPREQ | CDBGPWRUPREQ),
+    )?;
+    let mut powered = false;
+    for _ in 0..POWER_UP_POLLS {
+        let ctrl_stat = transfer(dap, REQUEST_DP_READ_CTRL_STAT, None)?;
+        if ctrl_stat & (CSYSPWRUPACK | CDBGPWRUPACK) == CSYSPWRUPACK | CDBGPWRUPACK {
+            powered = true;
+            break;
+        }
+    }
+    if !powered {
+        return Err(ConnectError::PowerUp);
+    }
+
+    let mut ap_count = 0;
+    let mut rom_base = None;
+    for ap in 0..=255u32 {
+        transfer(dap, REQUEST_DP_WRITE_SELECT, Some(ap << 24 | BANK_ID))?;
+        let idr = transfer(dap, REQUEST_AP_READ_IDR, None)?;
+        if idr == 0 {
+            break;
+        }
+
+        if ap == 0 && (idr >> 13) & 0xf == CLASS_MEM_AP {
+            rom_base = Some(transfer(dap, REQUEST_AP_READ_BASE, None)?);
+        }
+        ap_count += 1;
+    }
+
+    Ok(TargetInfo {
+        dpidr,
+        dp_version: ((dpidr >> 12) & 0xf) as u8,
+        ap_count,
+        rom_base,
+    })
+}
+
+/// Selects bank 0 of AP 0 and sets it up for word accesses with auto-increment.
+pub fn select_mem_ap(dap: &mut DapHandler) -> Result<(), ConnectError> {
+    transfer(dap, REQUEST_DP_WRITE_SELECT, Some(0))?;
+    transfer(dap, REQUEST_AP_WRITE_CSW, Some(CSW_WORD_INCREMENT))?;
+    Ok(())
+}
+
+/// Reads `words.len()` words from the word aligned `address`, through AP 0.
+pub fn read_memory(
+    dap: &mut DapHandler,
+    mut address: u32,
+    words: &mut [u32],
+) -> Result<(), ConnectError> {
+    let mut done = 0;
+    while done < words.len() {
+        let to_boundary =
+            ((AUTO_INCREMENT_RANGE - (address & (AUTO_INCREMENT_RANGE - 1))) / 4) as usize;
+        let n = (words.len() - done)
+            .min(READS_PER_TRANSFER)
+            .min(to_boundary);
+
+        // Request: [id, index, count, TAR write, address[4], DRW read * n]
+        let mut command = [REQUEST_AP_READ_DRW; 8 + READS_PER_TRANSFER];
+        command[..4].copy_from_slice(&[ID_DAP_TRANSFER, 0, 1 + n as u8, REQUEST_AP_WRITE_TAR]);
+        command[4..8].copy_from_slice(&address.to_le_bytes());
+
+        // Response: [id, transfers done, status, data[4] * n]
+        let mut resp = [0; 64];
+        dap::start_transfer_request();
+        dap.process_command(&command[..8 + n], &mut resp, DapVersion::V2);
+        if resp[1] != 1 + n as u8 || resp[2] != STATUS_OK {
+            return Err(ConnectError::Transfer(resp[2]));
+        }
+
+        for (word, data) in words[done..done + n]
+            .iter_mut()
+            .zip(resp[3..].chunks_exact(4))
+        {
+            *word = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        }
+        done += n;
+        address = address.wrapping_add(4 * n as u32);
+    }
+
+    Ok(())
+}
+
+/// Writes one word to the word aligned `address`, through AP 0.
+pub fn write_word(dap: &mut DapHandler, address: u32, value: u32) -> Result<(), ConnectError> {
+    transfer(dap, REQUEST_AP_WRITE_TAR, Some(address))?;
+    transfer(dap, REQUEST_AP_WRITE_DRW, Some(value))?;
+    Ok(())
+}
+
+/// One `DAP_Transfer`, a write if `data` is given, returning the read data.
+fn transfer(
+    dap: &mut impl CommandProcessor,
+    request: u8,
+    data: Option<u32>,
+) -> Result<u32, ConnectError> {
+    let mut command = [ID_DAP_TRANSFER, 0, 1, request, 0, 0, 0, 0];
+    let len = match data {
+        Some(data) => {
+            command[4..].copy_from_slice(&data.to_le_bytes());
+            8
+        }
+        None => 4,
+    };
+
+    // Response: [id, transfers done, status, data[4]]
+    let mut resp = [0; 64];
+    dap::start_transfer_request();
+    dap.process_command(&command[..len], &mut resp, DapVersion::V2);
+    if resp[1] != 1 || resp[2] != STATUS_OK {
+        return Err(ConnectError::Transfer(resp[2]));
+    }
+
+    Ok(u32::from_le_bytes([resp[3], resp[4], resp[5], resp[6]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DPIDR: u32 = 0x0bc1_2477;
+    const IDR_AHB_AP: u32 = 0x0477_0031;
+    const IDR_APB_AP: u32 = 0x0477_0002;
+    const ROM_BASE: u32 = 0xe00f_f003;
+
+    /// A DPv2 target with an AHB-AP and an APB-AP, powering up after a few CTRL/STAT polls.
+    struct Target {
+        power_up_polls: usize,
+        ctrl_stat: u32,
+        select: u32,
+        requests: Vec<u8>,
+    }
+
+    impl Target {
+        fn new(power_up_polls: usize) -> Self {
+            Target {
+                power_up_polls,
+                ctrl_stat: 0,
+                select: 0,
+                requests: Vec::new(),
+            }
+        }
+
+        fn read(&mut self, request: u8) -> u32 {
+            match request {
+                REQUEST_DP_READ_DPIDR => DPIDR,
+                REQUEST_DP_READ_CTRL_STAT if self.power_up_polls > 0 => {
+                    self.power_up_polls -= 1;
+                    self.ctrl_stat
+                }
+                // The ACKs follow the requests
+                REQUEST_DP_READ_CTRL_STAT => self.ctrl_stat | self.ctrl_stat << 1,
+                REQUEST_AP_READ_IDR => match self.select {
+                    0x0000_00f0 => IDR_AHB_AP,
+                    0x0100_00f0 => IDR_APB_AP,
+                    _ => 0,
+                },
+                REQUEST_AP_READ_BASE => {
+                    assert_eq!(self.select, BANK_ID);
+                    ROM_BASE
+                }
+                _ => panic!("unexpected read 0x{:02x}", request),
+            }
+        }
+    }
+
+    impl CommandProcessor for Target {
+        fn process_command(&mut self, report: &[u8], resp: &mut [u8], _: DapVersion) -> usize {
+            resp[0] = report[0];
+            match *report {
+                [ID_DAP_CONNECT, port] => {
+                    resp[1] = port;
+                    2
+                }
+                [ID_DAP_TRANSFER, 0, 1, request, ref data @ ..] => {
+                    self.requests.push(request);
+                    resp[1..3].copy_from_slice(&[1, STATUS_OK]);
+                    if let Ok(data) = <[u8; 4]>::try_from(data) {
+                        let data = u32::from_le_bytes(data);
+                        match request {
+                            REQUEST_DP_WRITE_CTRL_STAT => self.ctrl_stat = data,
+                            REQUEST_DP_WRITE_SELECT => self.select = data,
+                            _ => panic!("unexpected write 0x{:02x}", request),
+                        }
+                        return 3;
+                    }
+                    let data = self.read(request);
+                    resp[3..7].copy_from_slice(&data.to_le_bytes());
+                    7
+                }
+                _ => panic!("unexpected command {:02x?}", report),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_reads_the_target_info() {
+        let mut target = Target::new(3);
+        assert_eq!(
+            identify(&mut target),
+            Ok(TargetInfo {
+                dpidr: DPIDR,
+                dp_version: 2,
+                ap_count: 2,
+                rom_base: Some(ROM_BASE),
+            })
+        );
+        assert_eq!(target.ctrl_stat, CSYSPWRUPREQ | CDBGPWRUPREQ);
+
+        let polls = target
+            .requests
+            .iter()
+            .filter(|&&request| request == REQUEST_DP_READ_CTRL_STAT)
+            .count();
+        assert_eq!(polls, 4);
+        // The scan stops at the first AP without an IDR
+        assert_eq!(target.select, 0x0200_00f0);
+    }
+
+    #[test]
+    fn connect_fails_without_the_power_up_acknowledge() {
+        let mut target = Target::new(POWER_UP_POLLS);
+        assert_eq!(identify(&mut target), Err(ConnectError::PowerUp));
+    }
+}
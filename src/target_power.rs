@@ -0,0 +1,60 @@
+//! Switch for the target power supply, on carrier boards that can power the target.
+//!
+//! The enable output starts off, the target is only powered once the host asks for it with the
+//! `ID_TARGET_POWER` vendor command. With [`VTREF_SENSE`](crate::setup::VTREF_SENSE) SWD transfers
+//! are refused while the switch is on and Vtref has not come up yet, so a host power-cycling the
+//! target between flash attempts does not clock a target that is still starting up.
+//!
+//! The pin is configured once at init, afterwards its level is switched with raw SIO accesses
+//! like the other outputs the USB task drives.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::*;
+use embedded_hal::digital::v2::OutputPin;
+use rp_pico::hal::{gpio::DynPin, pac};
+
+/// GPIO number of the enable output, `NO_PIN` without a switch.
+static PIN: AtomicU8 = AtomicU8::new(NO_PIN);
+
+const NO_PIN: u8 = 0xff;
+
+/// Drives the enable output low (target unpowered) and takes it over.
+pub fn init(mut pin: DynPin) {
+    pin.into_push_pull_output();
+    pin.set_low().ok();
+    PIN.store(pin.id().num, Ordering::Relaxed);
+}
+
+/// Whether a power switch is fitted.
+pub fn available() -> bool {
+    PIN.load(Ordering::Relaxed) != NO_PIN
+}
+
+/// Switches the target power on or off, does nothing without a switch.
+pub fn set(on: bool) {
+    let pin = PIN.load(Ordering::Relaxed);
+    if pin == NO_PIN {
+        return;
+    }
+
+    // NOTE(unsafe) the SIO set/clear registers are atomic, the pin is only switched from here
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if on {
+        sio.gpio_out_set.write(|w| unsafe { w.bits(1 << pin) });
+    } else {
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << pin) });
+    }
+    info!("Target power switched {}", if on { "on" } else { "off" });
+}
+
+/// Whether the target power is switched on.
+pub fn is_on() -> bool {
+    let pin = PIN.load(Ordering::Relaxed);
+    if pin == NO_PIN {
+        return false;
+    }
+
+    // NOTE(unsafe) read only access
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_out.read().bits() & (1 << pin) != 0
+}
@@ -0,0 +1,54 @@
+//! Blocking microsecond delays on the 1 MHz TIMER.
+//!
+//! The TIMER counts microseconds from the reference tick, independent of the system clock and of
+//! how the waiting loop is compiled, so long waits do not drift like a cycle counted loop. The
+//! TIMER is owned by `Rp2040Monotonic`, only its free running counter is read here and no alarm
+//! is used. Sub-microsecond timing, such as the SWCLK half periods, stays on SysTick.
+
+use rp_pico::hal::pac;
+
+/// The free running microsecond counter, wrapping after about 71 minutes.
+pub fn now_us() -> u32 {
+    // NOTE(unsafe) read only access to the raw counter, which has no side effects
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    timer.timerawl.read().bits()
+}
+
+/// Blocks for at least `us` microseconds.
+pub fn delay_us(us: u32) {
+    let start = now_us();
+    while !elapsed(start, now_us(), us) {}
+}
+
+/// Whether at least `us` microseconds passed between the counter values `start` and `now`. The
+/// start may be read at the very end of a microsecond, so it takes one more tick.
+fn elapsed(start: u32, now: u32, us: u32) -> bool {
+    now.wrapping_sub(start) > us
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first counter value a delay of `us` from `start` returns at.
+    fn end(start: u32, us: u32) -> u32 {
+        (0..=u32::MAX)
+            .map(|tick| start.wrapping_add(tick))
+            .find(|&now| elapsed(start, now, us))
+            .unwrap()
+    }
+
+    #[test]
+    fn delay_takes_one_more_tick() {
+        assert_eq!(end(1000, 0), 1001);
+        assert_eq!(end(1000, 1), 1002);
+        // The longest `DAP_Delay`
+        assert_eq!(end(1000, 0xffff), 1000 + 0x1_0000);
+    }
+
+    #[test]
+    fn delay_across_the_counter_wrap() {
+        assert_eq!(end(u32::MAX - 4, 10), 6);
+        assert!(!elapsed(u32::MAX - 4, 5, 10));
+    }
+}
@@ -0,0 +1,290 @@
+//! Chunking of `DAP_TransferBlock` requests.
+//!
+//! Blocks larger than [`MAX_BLOCK_WORDS`] are split up before being handed to the DAP handler,
+//! so its buffers are never asked to hold more than that. The completed transfer count in the
+//! response is summed over the chunks, processing stops at the first chunk that does not
+//! complete or when the response buffer is full.
+//!
+//! A `DAP_TransferBlock` carries a single request, so all its transfers go in one direction;
+//! mixed reads and writes come as `DAP_Transfer`, which is forwarded unchanged.
+//!
+//! The read data of a completed block is handed to [`block_crc`].
+
+use crate::{block_crc, setup::DapHandler};
+use dap_rs::dap::DapVersion;
+
+pub const ID_DAP_TRANSFER_BLOCK: u8 = 0x06;
+
+/// Largest block, in words, handed to the DAP handler at once.
+pub const MAX_BLOCK_WORDS: usize = 8;
+
+/// `DAP_TransferBlock` response status for an OK ACK.
+const STATUS_OK: u8 = 0x01;
+
+const RNW: u8 = 1 << 1;
+
+/// Request header: `[id, index, count[2], request]`, response header: `[id, count[2], status]`.
+const REQUEST_HEADER: usize = 5;
+const RESPONSE_HEADER: usize = 4;
+
+/// Runs a single command, the chunks and other commands built by the firmware are handed to it.
+/// The DAP handler, or a stand-in in tests.
+pub(crate) trait CommandProcessor {
+    fn process_command(&mut self, report: &[u8], resp: &mut [u8], version: DapVersion) -> usize;
+}
+
+impl CommandProcessor for DapHandler {
+    fn process_command(&mut self, report: &[u8], resp: &mut [u8], version: DapVersion) -> usize {
+        DapHandler::process_command(self, report, resp, version)
+    }
+}
+
+pub fn process(dap: &mut DapHandler, report: &[u8], resp: &mut [u8], version: DapVersion) -> usize {
+    let len = process_chunked(dap, report, resp, version);
+
+    let read = matches!(report.get(4), Some(request) if request & RNW != 0);
+    if read && block_crc::enabled() && len >= RESPONSE_HEADER {
+        block_crc::record(&resp[RESPONSE_HEADER..len]);
+    }
+    len
+}
+
+fn process_chunked(
+    dap: &mut impl CommandProcessor,
+    report: &[u8],
+    resp: &mut [u8],
+    version: DapVersion,
+) -> usize {
+    let (index, count, request) = match report {
+        &[_, index, c0, c1, request, ..] => (index, u16::from_le_bytes([c0, c1]) as usize, request),
+        _ => return dap.process_command(report, resp, version),
+    };
+
+    if count <= MAX_BLOCK_WORDS {
+        return dap.process_command(report, resp, version);
+    }
+
+    let read = request & RNW != 0;
+    let mut write_data = report[REQUEST_HEADER..].chunks_exact(4);
+
+    let mut done = 0;
+    let mut status = STATUS_OK;
+    let mut chunk_req = [0; REQUEST_HEADER + MAX_BLOCK_WORDS * 4];
+    let mut chunk_resp = [0; RESPONSE_HEADER + MAX_BLOCK_WORDS * 4];
+
+    while done < count && status == STATUS_OK {
+        let mut n = core::cmp::min(count - done, MAX_BLOCK_WORDS);
+        let mut len = REQUEST_HEADER;
+        if read {
+            // Only ask for what still fits in the response
+            let space = resp.len().saturating_sub(RESPONSE_HEADER + done * 4) / 4;
+            n = core::cmp::min(n, space);
+        } else {
+            // A malformed request may carry less data than its count claims
+            for word in write_data.by_ref().take(n) {
+                chunk_req[len..len + 4].copy_from_slice(word);
+                len += 4;
+            }
+            n = (len - REQUEST_HEADER) / 4;
+        }
+        if n == 0 {
+            break;
+        }
+
+        chunk_req[..REQUEST_HEADER].copy_from_slice(&[
+            ID_DAP_TRANSFER_BLOCK,
+            index,
+            n as u8,
+            0,
+            request,
+        ]);
+
+        dap.process_command(&chunk_req[..len], &mut chunk_resp, version);
+
+        let chunk_done = u16::from_le_bytes([chunk_resp[1], chunk_resp[2]]) as usize;
+        status = chunk_resp[3];
+
+        if read {
+            let offset = RESPONSE_HEADER + done * 4;
+            resp[offset..offset + chunk_done * 4]
+                .copy_from_slice(&chunk_resp[RESPONSE_HEADER..RESPONSE_HEADER + chunk_done * 4]);
+        }
+
+        done += chunk_done;
+        if chunk_done < n {
+            break;
+        }
+    }
+
+    resp[0] = ID_DAP_TRANSFER_BLOCK;
+    resp[1..3].copy_from_slice(&(done as u16).to_le_bytes());
+    resp[3] = status;
+
+    if read {
+        RESPONSE_HEADER + done * 4
+    } else {
+        RESPONSE_HEADER
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS_WAIT: u8 = 0x02;
+
+    /// Answers every transfer with OK until `ok_words` ran out, reads return a running count.
+    struct Handler {
+        ok_words: usize,
+        chunks: Vec<usize>,
+        read: Vec<u32>,
+        written: Vec<u32>,
+    }
+
+    impl Handler {
+        fn new(ok_words: usize) -> Self {
+            Handler {
+                ok_words,
+                chunks: Vec::new(),
+                read: Vec::new(),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl CommandProcessor for Handler {
+        fn process_command(&mut self, report: &[u8], resp: &mut [u8], _: DapVersion) -> usize {
+            let count = u16::from_le_bytes([report[2], report[3]]) as usize;
+            let read = report[4] & RNW != 0;
+            self.chunks.push(count);
+
+            let done = count.min(self.ok_words);
+            self.ok_words -= done;
+            for i in 0..done {
+                if read {
+                    let word = self.read.len() as u32;
+                    let offset = RESPONSE_HEADER + i * 4;
+                    resp[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+                    self.read.push(word);
+                } else {
+                    let offset = REQUEST_HEADER + i * 4;
+                    let word = &report[offset..offset + 4];
+                    self.written
+                        .push(u32::from_le_bytes(word.try_into().unwrap()));
+                }
+            }
+
+            resp[0] = report[0];
+            resp[1..3].copy_from_slice(&(done as u16).to_le_bytes());
+            resp[3] = if done < count { STATUS_WAIT } else { STATUS_OK };
+            RESPONSE_HEADER + if read { done * 4 } else { 0 }
+        }
+    }
+
+    fn request(count: u16, request: u8, words: impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut report = vec![ID_DAP_TRANSFER_BLOCK, 0];
+        report.extend_from_slice(&count.to_le_bytes());
+        report.push(request);
+        for word in words {
+            report.extend_from_slice(&word.to_le_bytes());
+        }
+        report
+    }
+
+    fn words(data: &[u8]) -> Vec<u32> {
+        data.chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn small_block_is_forwarded() {
+        let mut dap = Handler::new(usize::MAX);
+        let mut resp = [0; 64];
+        let len = process_chunked(&mut dap, &request(5, RNW, 0..0), &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [5]);
+        assert_eq!(len, RESPONSE_HEADER + 5 * 4);
+    }
+
+    #[test]
+    fn read_is_chunked() {
+        let mut dap = Handler::new(usize::MAX);
+        let mut resp = [0; 512];
+        let len = process_chunked(&mut dap, &request(20, RNW, 0..0), &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [8, 8, 4]);
+        assert_eq!(len, RESPONSE_HEADER + 20 * 4);
+        assert_eq!(
+            resp[..RESPONSE_HEADER],
+            [ID_DAP_TRANSFER_BLOCK, 20, 0, STATUS_OK]
+        );
+        assert_eq!(
+            words(&resp[RESPONSE_HEADER..len]),
+            (0..20).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn read_stops_when_the_response_is_full() {
+        let mut dap = Handler::new(usize::MAX);
+        let mut resp = [0; RESPONSE_HEADER + 10 * 4];
+        let len = process_chunked(&mut dap, &request(20, RNW, 0..0), &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [8, 2]);
+        assert_eq!(len, resp.len());
+        assert_eq!(
+            resp[..RESPONSE_HEADER],
+            [ID_DAP_TRANSFER_BLOCK, 10, 0, STATUS_OK]
+        );
+    }
+
+    #[test]
+    fn read_stops_at_the_first_failed_chunk() {
+        let mut dap = Handler::new(10);
+        let mut resp = [0; 512];
+        let len = process_chunked(&mut dap, &request(20, RNW, 0..0), &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [8, 8]);
+        assert_eq!(len, RESPONSE_HEADER + 10 * 4);
+        assert_eq!(
+            resp[..RESPONSE_HEADER],
+            [ID_DAP_TRANSFER_BLOCK, 10, 0, STATUS_WAIT]
+        );
+        assert_eq!(
+            words(&resp[RESPONSE_HEADER..len]),
+            (0..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn write_is_chunked() {
+        let mut dap = Handler::new(usize::MAX);
+        let mut resp = [0; 64];
+        let report = request(12, 0, 100..112);
+        let len = process_chunked(&mut dap, &report, &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [8, 4]);
+        assert_eq!(len, RESPONSE_HEADER);
+        assert_eq!(
+            resp[..RESPONSE_HEADER],
+            [ID_DAP_TRANSFER_BLOCK, 12, 0, STATUS_OK]
+        );
+        assert_eq!(dap.written, (100..112).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_with_missing_data_stops_short() {
+        let mut dap = Handler::new(usize::MAX);
+        let mut resp = [0; 64];
+        let report = request(12, 0, 100..109);
+        process_chunked(&mut dap, &report, &mut resp, DapVersion::V2);
+
+        assert_eq!(dap.chunks, [8, 1]);
+        assert_eq!(
+            resp[..RESPONSE_HEADER],
+            [ID_DAP_TRANSFER_BLOCK, 9, 0, STATUS_OK]
+        );
+        assert_eq!(dap.written, (100..109).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,78 @@
+//! Register level helpers shared by the PL011 UART users, the SWO capture on UART1 and the
+//! target serial bridge on UART0.
+
+use rp_pico::hal::pac;
+
+// `UARTCR` bits
+pub const UARTEN: u32 = 1 << 0;
+pub const TXE: u32 = 1 << 8;
+pub const RXE: u32 = 1 << 9;
+
+// `UARTLCR_H` bits, 8 data bits with the FIFOs enabled
+const WLEN_8: u32 = 0b11 << 5;
+const FEN: u32 = 1 << 4;
+
+// `UARTIMSC`/`UARTICR` bits
+pub const RXIM: u32 = 1 << 4;
+pub const TXIM: u32 = 1 << 5;
+pub const RTIM: u32 = 1 << 6;
+pub const OEIM: u32 = 1 << 10;
+pub const ALL_INTERRUPTS: u32 = 0x7ff;
+
+// `UARTDR` bits
+pub const DR_OE: u32 = 1 << 11;
+pub const DR_ERRORS: u32 = 0b111 << 8;
+
+// `UARTFR` bits
+pub const RXFE: u32 = 1 << 4;
+pub const TXFF: u32 = 1 << 5;
+
+/// Divisors for `baudrate`, as the 16.6 fixed point `(UARTIBRD, UARTFBRD)` pair.
+pub fn divisors(peripheral_frequency: u32, baudrate: u32) -> (u32, u32) {
+    let div = (8 * peripheral_frequency as u64 / baudrate as u64) as u32;
+    match div >> 7 {
+        0 => (1, 0),
+        ibrd if ibrd >= 0xffff => (0xffff, 0),
+        ibrd => (ibrd, ((div & 0x7f) + 1) / 2),
+    }
+}
+
+/// The baud rate the divisors actually produce.
+pub fn achieved_baudrate(peripheral_frequency: u32, (ibrd, fbrd): (u32, u32)) -> u32 {
+    (4 * peripheral_frequency as u64 / (64 * ibrd + fbrd) as u64) as u32
+}
+
+/// Sets the baud rate and 8N1 framing, returning the baud rate actually achieved. `baudrate`
+/// must not be 0, and the UART should be disabled while its framing changes.
+pub fn configure(
+    uart: &pac::uart0::RegisterBlock,
+    peripheral_frequency: u32,
+    baudrate: u32,
+) -> u32 {
+    let divisors = divisors(peripheral_frequency, baudrate);
+    // NOTE(unsafe) the divisors are in range, the `UARTLCR_H` write latches them
+    uart.uartibrd.write(|w| unsafe { w.bits(divisors.0) });
+    uart.uartfbrd.write(|w| unsafe { w.bits(divisors.1) });
+    uart.uartlcr_h.write(|w| unsafe { w.bits(WLEN_8 | FEN) });
+
+    achieved_baudrate(peripheral_frequency, divisors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisors_of_common_rates() {
+        assert_eq!(divisors(125_000_000, 115_200), (67, 52));
+        assert_eq!(achieved_baudrate(125_000_000, (67, 52)), 115_207);
+        assert_eq!(divisors(125_000_000, 1_000_000), (7, 52));
+        assert_eq!(achieved_baudrate(125_000_000, (7, 52)), 1_000_000);
+    }
+
+    #[test]
+    fn divisors_are_clamped() {
+        assert_eq!(divisors(125_000_000, 20_000_000), (1, 0));
+        assert_eq!(divisors(125_000_000, 100), (0xffff, 0));
+    }
+}
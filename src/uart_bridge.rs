@@ -0,0 +1,138 @@
+//! Bridge between the CDC-ACM interface and the target UART.
+//!
+//! UART0 talks to the target on GPIO0 (probe TX, target RX) and GPIO1 (probe RX, target TX).
+//! Both directions go through a ring buffer: the USB task fills [`TO_TARGET`] with what the host
+//! sends and drains [`FROM_TARGET`] to the host, while the UART interrupt moves bytes between the
+//! buffers and the UART FIFOs. The host sets the baud rate with the CDC line coding, the framing
+//! is always 8N1.
+
+use crate::{
+    ring_buffer::RingBuffer,
+    uart::{ALL_INTERRUPTS, OEIM, RTIM, RXE, RXFE, RXIM, TXE, TXFF, TXIM, UARTEN},
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::*;
+use rp_pico::hal::{
+    gpio::{
+        bank0::{Gpio0, Gpio1},
+        FunctionUart, Pin,
+    },
+    pac,
+};
+
+/// Size of each direction's buffer in bytes.
+pub const BUFFER_SIZE: usize = 512;
+
+/// Bytes from the host waiting for the UART.
+pub static TO_TARGET: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+/// Bytes from the target waiting for the host.
+pub static FROM_TARGET: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+
+/// Baud rate used until the host sets the line coding.
+pub const DEFAULT_BAUDRATE: u32 = 115_200;
+
+static BAUDRATE: AtomicU32 = AtomicU32::new(0);
+
+pub struct UartBridge {
+    _uart: pac::UART0,
+    _pins: (Pin<Gpio0, FunctionUart>, Pin<Gpio1, FunctionUart>),
+    peripheral_frequency: u32,
+}
+
+impl UartBridge {
+    /// Takes UART0 out of reset and enables it at [`DEFAULT_BAUDRATE`].
+    pub fn new(
+        uart: pac::UART0,
+        tx: Pin<Gpio0, FunctionUart>,
+        rx: Pin<Gpio1, FunctionUart>,
+        resets: &mut pac::RESETS,
+        peripheral_frequency: u32,
+    ) -> Self {
+        resets.reset.modify(|_, w| w.uart0().clear_bit());
+        while resets.reset_done.read().uart0().bit_is_clear() {}
+
+        // NOTE(unsafe) any value is valid for this register
+        uart.uartimsc
+            .write(|w| unsafe { w.bits(RXIM | RTIM | OEIM) });
+
+        let mut bridge = UartBridge {
+            _uart: uart,
+            _pins: (tx, rx),
+            peripheral_frequency,
+        };
+        bridge.set_baudrate(DEFAULT_BAUDRATE);
+        bridge
+    }
+
+    /// Applies the baud rate of the CDC line coding, if it changed.
+    pub fn set_baudrate(&mut self, baudrate: u32) {
+        if baudrate == 0 || baudrate == BAUDRATE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let uart = uart();
+        // NOTE(unsafe) the framing may only change while the UART is disabled
+        uart.uartcr.write(|w| unsafe { w.bits(0) });
+        let achieved = crate::uart::configure(uart, self.peripheral_frequency, baudrate);
+        uart.uartcr.write(|w| unsafe { w.bits(UARTEN | TXE | RXE) });
+
+        BAUDRATE.store(baudrate, Ordering::Relaxed);
+        debug!(
+            "Target UART baudrate requested: {}, achieved: {}",
+            baudrate, achieved
+        );
+    }
+
+    /// Starts transmitting what the host sent, call after filling [`TO_TARGET`].
+    pub fn kick(&mut self) {
+        fill_tx_fifo();
+    }
+}
+
+/// Moves received bytes into [`FROM_TARGET`] and refills the transmit FIFO, call from the
+/// `UART0_IRQ` handler. Returns `true` if bytes for the host arrived.
+pub fn on_interrupt() -> bool {
+    let uart = uart();
+    let mut received = false;
+
+    while uart.uartfr.read().bits() & RXFE == 0 {
+        // Bytes received while the host is not reading are dropped
+        let byte = uart.uartdr.read().bits() as u8;
+        FROM_TARGET.push(byte);
+        received = true;
+    }
+
+    // NOTE(unsafe) write one to clear register, the TX interrupt clears by refilling the FIFO
+    uart.uarticr
+        .write(|w| unsafe { w.bits(ALL_INTERRUPTS & !TXIM) });
+    fill_tx_fifo();
+
+    received
+}
+
+/// Fills the transmit FIFO from [`TO_TARGET`], the TX interrupt is only enabled while bytes
+/// are left over.
+fn fill_tx_fifo() {
+    let uart = uart();
+
+    let mut byte = [0];
+    while uart.uartfr.read().bits() & TXFF == 0 && TO_TARGET.pop_slice(&mut byte) == 1 {
+        // NOTE(unsafe) any byte can be sent
+        uart.uartdr.write(|w| unsafe { w.bits(byte[0] as u32) });
+    }
+
+    // NOTE(no-CAS) the USB task and the UART interrupt run at the same priority
+    let mask = if TO_TARGET.is_empty() {
+        RXIM | RTIM | OEIM
+    } else {
+        RXIM | RTIM | OEIM | TXIM
+    };
+    uart.uartimsc.write(|w| unsafe { w.bits(mask) });
+}
+
+#[inline(always)]
+fn uart() -> &'static pac::uart0::RegisterBlock {
+    // NOTE(unsafe) owned by `UartBridge`, the interrupt handler only touches the FIFOs and the
+    // interrupt registers
+    unsafe { &*pac::UART0::ptr() }
+}
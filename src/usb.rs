@@ -1,54 +1,194 @@
+#[cfg(feature = "defmt-usb")]
+use crate::defmt_usb;
+#[cfg(feature = "rtt")]
+use crate::rtt;
+#[cfg(feature = "swo")]
+use crate::swo;
+#[cfg(feature = "cdc")]
+use crate::uart_bridge::{self, UartBridge};
+#[cfg(feature = "webusb")]
+use crate::webusb::WebUsbDescriptors;
+use crate::{setup, timer_delay};
 use dap_rs::usb::{dap_v1::CmsisDapV1, dap_v2::CmsisDapV2, Request};
 use defmt::*;
-use rp_pico::hal::usb::UsbBus;
+use rp_pico::hal::{pac, usb::UsbBus};
 use usb_device::{class_prelude::*, prelude::*};
+#[cfg(any(feature = "cdc", feature = "rtt", feature = "defmt-usb"))]
 use usbd_serial::SerialPort;
 
+/// Size of the DAPv1 HID reports, in both directions.
+const DAP_V1_REPORT_SIZE: usize = 64;
+
+/// Size of the DAPv2 bulk packets.
+const DAP_V2_PACKET_SIZE: usize = 64;
+
+/// The bus has to be idle this long before the device may signal remote wakeup, 5 ms in the USB
+/// 2.0 specification (7.1.7.7), counted from when the device noticed the suspend.
+const REMOTE_WAKEUP_IDLE_US: u32 = 5_000;
+
+/// The USB identity of the probe.
+///
+/// Host tools find CMSIS-DAP probes by the interface and product strings, not by the VID/PID:
+/// the product string has to contain "CMSIS-DAP" for probe-rs, pyOCD and OpenOCD to pick up the
+/// v1 (HID) interface at all. The manufacturer string is only shown to the user, the serial
+/// number always comes from the flash unique ID so several probes can be told apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+}
+
+impl UsbIdentity {
+    /// The pid.codes VID/PID allocated to the probe.
+    pub const DEFAULT: UsbIdentity = UsbIdentity {
+        vid: 0x1209,
+        pid: 0x4853,
+        manufacturer: "Probe-rs development team",
+        product: "Pico-Probe with CMSIS-DAP v1/v2 Support",
+    };
+}
+
 /// Implements the CMSIS DAP descriptors.
 pub struct ProbeUsb {
     device: UsbDevice<'static, UsbBus>,
     device_state: UsbDeviceState,
     // winusb: MicrosoftDescriptors,
+    #[cfg(feature = "webusb")]
+    webusb: WebUsbDescriptors,
     dap_v1: CmsisDapV1<'static, UsbBus>,
     dap_v2: CmsisDapV2<'static, UsbBus>,
+    /// A DAPv2 reply waiting for the bulk IN endpoint to send the previous one.
+    dap2_pending: [u8; DAP_V2_PACKET_SIZE],
+    dap2_pending_len: usize,
+    #[cfg(feature = "cdc")]
     serial: SerialPort<'static, UsbBus>,
+    #[cfg(feature = "cdc")]
+    bridge: UartBridge,
+    #[cfg(feature = "rtt")]
+    rtt_serial: SerialPort<'static, UsbBus>,
+    #[cfg(feature = "defmt-usb")]
+    log_serial: SerialPort<'static, UsbBus>,
+    /// When the device was found suspended, `None` while the bus is active.
+    suspended_at: Option<u32>,
+    /// Remote wakeup was signalled during this suspend.
+    wakeup_signalled: bool,
     // dfu: DfuRuntime,
 }
 
 impl ProbeUsb {
     #[inline(always)]
-    pub fn new(usb_bus: &'static UsbBusAllocator<UsbBus>) -> Self {
+    pub fn new(
+        usb_bus: &'static UsbBusAllocator<UsbBus>,
+        identity: UsbIdentity,
+        #[cfg(feature = "cdc")] bridge: UartBridge,
+    ) -> Self {
         let dap_v1 = CmsisDapV1::new(64, usb_bus);
         let dap_v2 = CmsisDapV2::new(64, usb_bus);
+        #[cfg(feature = "webusb")]
+        let webusb = WebUsbDescriptors::new(setup::WEBUSB_LANDING_PAGE);
+        #[cfg(feature = "cdc")]
         let serial = SerialPort::new(&usb_bus);
+        #[cfg(feature = "rtt")]
+        let rtt_serial = SerialPort::new(&usb_bus);
+        #[cfg(feature = "defmt-usb")]
+        let log_serial = SerialPort::new(&usb_bus);
 
         let id = crate::device_signature::device_id_hex();
         info!("Device ID: {}", id);
-        let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x4853))
-            .manufacturer("Probe-rs development team")
-            .product("Pico-Probe with CMSIS-DAP v1/v2 Support")
+        if !identity.product.contains("CMSIS-DAP") {
+            warn!("USB product string without \"CMSIS-DAP\", host tools will not detect the probe");
+        }
+        let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(identity.vid, identity.pid))
+            .manufacturer(identity.manufacturer)
+            .product(identity.product)
             .serial_number(id)
             .device_class(0)
             .max_packet_size_0(64)
             .max_power(500)
+            .supports_remote_wakeup(setup::USB_REMOTE_WAKEUP)
             .build();
         let device_state = device.state();
 
         ProbeUsb {
             device,
             device_state,
+            #[cfg(feature = "webusb")]
+            webusb,
             dap_v1,
             dap_v2,
+            dap2_pending: [0; DAP_V2_PACKET_SIZE],
+            dap2_pending_len: 0,
+            #[cfg(feature = "cdc")]
             serial,
+            #[cfg(feature = "cdc")]
+            bridge,
+            #[cfg(feature = "rtt")]
+            rtt_serial,
+            #[cfg(feature = "defmt-usb")]
+            log_serial,
+            suspended_at: None,
+            wakeup_signalled: false,
         }
     }
 
     pub fn interrupt(&mut self) -> Option<Request> {
+        let request = self.poll();
+
+        // Also runs when only the target UART has data for the host
+        #[cfg(feature = "cdc")]
+        self.bridge_serial();
+        #[cfg(feature = "rtt")]
+        self.forward_rtt();
+        #[cfg(feature = "swo")]
+        self.stream_swo();
+        #[cfg(feature = "defmt-usb")]
+        self.forward_log();
+        self.wake_host();
+
+        request
+    }
+
+    /// Signals remote wakeup to a suspended host that enabled it, once per suspend, when the
+    /// target has data for the host. The data sources pend the USB interrupt, which runs this.
+    fn wake_host(&mut self) {
+        if self.device.state() != UsbDeviceState::Suspend {
+            self.suspended_at = None;
+            self.wakeup_signalled = false;
+            return;
+        }
+        let suspended_at = *self.suspended_at.get_or_insert_with(timer_delay::now_us);
+
+        if !setup::USB_REMOTE_WAKEUP
+            || self.wakeup_signalled
+            || !self.device.remote_wakeup_enabled()
+            || !host_data_pending()
+            || timer_delay::now_us().wrapping_sub(suspended_at) < REMOTE_WAKEUP_IDLE_US
+        {
+            return;
+        }
+
+        info!("Signalling USB remote wakeup");
+        // NOTE(unsafe) the resume bit is self-clearing and the bus is only used from this task
+        let regs = unsafe { &*pac::USBCTRL_REGS::ptr() };
+        regs.sie_ctrl.modify(|_, w| w.resume().set_bit());
+        self.wakeup_signalled = true;
+    }
+
+    fn poll(&mut self) -> Option<Request> {
         if self.device.poll(&mut [
             // &mut usb.winusb,
+            #[cfg(feature = "webusb")]
+            &mut self.webusb,
             &mut self.dap_v1,
             &mut self.dap_v2,
+            #[cfg(feature = "cdc")]
             &mut self.serial,
+            #[cfg(feature = "rtt")]
+            &mut self.rtt_serial,
+            #[cfg(feature = "defmt-usb")]
+            &mut self.log_serial,
             // &mut usb.dfu,
         ]) {
             let old_state = self.device_state;
@@ -63,29 +203,195 @@ impl ProbeUsb {
                 return r;
             }
 
-            let r = self.dap_v2.process();
-            if r.is_some() {
-                return r;
+            // The next command is only taken once its reply has a buffer
+            if self.flush_dap2() {
+                let r = self.dap_v2.process();
+                if r.is_some() {
+                    return r;
+                }
             }
-
-            // Discard data from the serial interface
-            let mut buf = [0; 64 as usize];
-            let _ = self.serial.read(&mut buf);
         }
         None
     }
 
-    /// Transmit a DAP report back over the DAPv1 HID interface
+    /// Moves data between the CDC-ACM interface and the target UART.
+    #[cfg(feature = "cdc")]
+    fn bridge_serial(&mut self) {
+        if self.device_state != UsbDeviceState::Configured {
+            return;
+        }
+        self.bridge
+            .set_baudrate(self.serial.line_coding().data_rate());
+
+        // Only take what fits, the rest stays with the host until the UART caught up
+        let mut buf = [0; 64];
+        let free = uart_bridge::BUFFER_SIZE - uart_bridge::TO_TARGET.len();
+        let n = free.min(buf.len());
+        if n > 0 {
+            if let Ok(n) = self.serial.read(&mut buf[..n]) {
+                uart_bridge::TO_TARGET.push_slice(&buf[..n]);
+                self.bridge.kick();
+            }
+        }
+
+        let n = uart_bridge::FROM_TARGET.peek_slice(&mut buf);
+        if n > 0 {
+            if let Ok(written) = self.serial.write(&buf[..n]) {
+                uart_bridge::FROM_TARGET.consume(written);
+            }
+        }
+    }
+
+    /// Writes the RTT output to its CDC-ACM interface, and tells the RTT task whether the port
+    /// is open.
+    #[cfg(feature = "rtt")]
+    fn forward_rtt(&mut self) {
+        if self.device_state != UsbDeviceState::Configured {
+            rtt::set_port_open(false);
+            return;
+        }
+        rtt::set_port_open(self.rtt_serial.dtr());
+
+        // There is no down-channel, drop what the host sends
+        let mut buf = [0; 64];
+        while matches!(self.rtt_serial.read(&mut buf), Ok(n) if n > 0) {}
+
+        let n = rtt::FROM_TARGET.peek_slice(&mut buf);
+        if n > 0 {
+            if let Ok(written) = self.rtt_serial.write(&buf[..n]) {
+                rtt::FROM_TARGET.consume(written);
+            }
+        }
+    }
+
+    /// Writes the probe's own defmt log to its CDC-ACM interface while the port is open.
+    #[cfg(feature = "defmt-usb")]
+    fn forward_log(&mut self) {
+        if self.device_state != UsbDeviceState::Configured {
+            return;
+        }
+
+        // There is nothing to receive, drop what the host sends
+        let mut buf = [0; 64];
+        while matches!(self.log_serial.read(&mut buf), Ok(n) if n > 0) {}
+
+        if !self.log_serial.dtr() {
+            return;
+        }
+        let n = defmt_usb::LOG.peek_slice(&mut buf);
+        if n > 0 {
+            if let Ok(written) = self.log_serial.write(&buf[..n]) {
+                defmt_usb::LOG.consume(written);
+            }
+        }
+    }
+
+    /// Writes the captured SWO trace out the DAPv2 trace endpoint while the host streams it.
+    /// Runs on every USB interrupt, the completion of the previous packet sends the next one.
+    #[cfg(feature = "swo")]
+    fn stream_swo(&mut self) {
+        if self.device_state != UsbDeviceState::Configured
+            || !swo::streaming()
+            || self.dap_v2.trace_busy()
+        {
+            return;
+        }
+
+        let mut buf = [0; DAP_V2_PACKET_SIZE];
+        let n = swo::BUFFER.peek_slice(&mut buf);
+        if n > 0 {
+            if let Ok(written) = self.dap_v2.trace_write(&buf[..n]) {
+                swo::BUFFER.consume(written);
+            }
+        }
+    }
+
+    /// Transmit a DAP report back over the DAPv1 HID interface, zero padded (or truncated) to
+    /// the fixed report size, as HID hosts expect every report to be complete.
     pub fn dap1_reply(&mut self, data: &[u8]) {
         self.dap_v1
-            .write_packet(data)
+            .write_packet(&dap1_report(data))
             .expect("DAPv1 EP write failed");
     }
 
-    /// Transmit a DAP report back over the DAPv2 bulk interface
+    /// Transmit a DAP report back over the DAPv2 bulk interface. While the endpoint is still
+    /// busy with the previous reply the report is kept back and sent from a later interrupt, so
+    /// the next command can be received and processed in the meantime.
     pub fn dap2_reply(&mut self, data: &[u8]) {
-        self.dap_v2
-            .write_packet(data)
-            .expect("DAPv2 EP write failed");
+        if self.dap2_pending_len == 0 {
+            match self.dap_v2.write_packet(data) {
+                Ok(_) => return,
+                Err(UsbError::WouldBlock) => {}
+                Err(_) => panic!("DAPv2 EP write failed"),
+            }
+        }
+
+        let len = data.len().min(DAP_V2_PACKET_SIZE);
+        self.dap2_pending[..len].copy_from_slice(&data[..len]);
+        self.dap2_pending_len = len;
+    }
+
+    /// Sends the reply kept back by [`Self::dap2_reply`], returns `true` if none is left.
+    fn flush_dap2(&mut self) -> bool {
+        if self.dap2_pending_len > 0
+            && self
+                .dap_v2
+                .write_packet(&self.dap2_pending[..self.dap2_pending_len])
+                .is_ok()
+        {
+            self.dap2_pending_len = 0;
+        }
+
+        self.dap2_pending_len == 0
+    }
+
+    /// Transmit a DAP report over the DAPv2 bulk interface if the endpoint is free, returns
+    /// `false` if it is still busy with the previous one.
+    pub fn try_dap2_reply(&mut self, data: &[u8]) -> bool {
+        self.flush_dap2() && self.dap_v2.write_packet(data).is_ok()
+    }
+}
+
+/// Whether the target sent something the host has not read yet.
+fn host_data_pending() -> bool {
+    #[cfg(feature = "cdc")]
+    if !uart_bridge::FROM_TARGET.is_empty() {
+        return true;
+    }
+    #[cfg(feature = "rtt")]
+    if !rtt::FROM_TARGET.is_empty() {
+        return true;
+    }
+    #[cfg(feature = "swo")]
+    if swo::streaming() && !swo::BUFFER.is_empty() {
+        return true;
+    }
+    false
+}
+
+/// `data` zero padded, or truncated, to a whole DAPv1 report.
+fn dap1_report(data: &[u8]) -> [u8; DAP_V1_REPORT_SIZE] {
+    let mut report = [0; DAP_V1_REPORT_SIZE];
+    let len = data.len().min(DAP_V1_REPORT_SIZE);
+    report[..len].copy_from_slice(&data[..len]);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dap1_reports_are_padded() {
+        let report = dap1_report(&[0x00, 0x04, 0xaa]);
+        assert_eq!(report[..3], [0x00, 0x04, 0xaa]);
+        assert!(report[3..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn dap1_reports_are_truncated() {
+        let data = [0x55; DAP_V1_REPORT_SIZE + 8];
+        assert_eq!(dap1_report(&data), [0x55; DAP_V1_REPORT_SIZE]);
+        assert_eq!(dap1_report(&[]), [0; DAP_V1_REPORT_SIZE]);
     }
 }
@@ -0,0 +1,1133 @@
+//! Vendor specific DAP commands, `ID_DAP_Vendor0` (0x80) up to `ID_DAP_Vendor31` (0x9f).
+//!
+//! `DAP_TransferBlock` is chunked by [`transfer_block`], part of `DAP_Info` is answered by
+//! [`info`], `DAP_JTAG_IDCODE` by [`jtag_idcode`], `DAP_SWD_Sequence` by [`swd_sequence`], every
+//! other command is forwarded to the DAP handler.
+
+#[cfg(feature = "jtag")]
+use crate::jtag_idcode;
+#[cfg(feature = "swo")]
+use crate::swo;
+use crate::{
+    adc, block_crc, bootloader, build_info, dap,
+    flash_config::{self, PinAssignment},
+    info, read_stream, self_check,
+    setup::{self, DapHandler},
+    swd_sequence, target_power, timer_delay,
+    transfer_block::{self, CommandProcessor},
+};
+#[cfg(feature = "diagnostics")]
+use crate::{scope_loop, stats};
+use dap_rs::dap::DapVersion;
+use defmt::*;
+use embedded_hal::digital::v2::PinState;
+
+/// Returns the detail of the last failed transfer.
+///
+/// Response: `[0x80, detail, status]` where `detail` is a [`dap::TransferError`] and `status`
+/// the matching `DAP_Transfer` response status.
+pub const ID_LAST_ERROR: u8 = 0x80;
+
+/// Enables (`1`) or disables (`0`) the automatic switch sequence on `DAP_Connect`.
+///
+/// Request: `[0x81, enable]`, response: `[0x81, enabled]`.
+pub const ID_AUTO_SWITCH: u8 = 0x81;
+
+/// Sets the level SWDIO is left driven at after a FAULT, `1` for high and `0` for low.
+///
+/// Request: `[0x82, high]`, response: `[0x82, high]`.
+pub const ID_FAULT_LEVEL: u8 = 0x82;
+
+/// Starts (`1`) or stops (`0`) the continuous SWD read loop for scope testing.
+///
+/// Request: `[0x83, start, request, timeout_s]` where `request` is a `DAP_Transfer` read
+/// request, response: `[0x83, active]`. The caller must spawn the loop when active.
+pub const ID_SCOPE_LOOP: u8 = 0x83;
+
+/// Reads a block of words from target memory, through the current MEM-AP, continuing past
+/// words that fault instead of aborting the whole block like `DAP_TransferBlock`.
+///
+/// Request: `[0x84, count, address[4]]` with at most [`BLOCK_READ_MAX_WORDS`] words.
+/// Response: `[0x84, count, (status, data[4]) * count]` where `status` is the `DAP_Transfer`
+/// response status of the word, its data is `0xdeadbeef` when the status is not OK.
+pub const ID_BLOCK_READ: u8 = 0x84;
+
+/// Maximum number of words per block read, limited by the 64 byte packet.
+pub const BLOCK_READ_MAX_WORDS: usize = 12;
+
+/// Data reported for a word that failed to read.
+pub const BLOCK_READ_SENTINEL: u32 = 0xdead_beef;
+
+/// Sets the level SWCLK rests at between transfers, `1` for high and `0` for low.
+///
+/// Request: `[0x85, high]`, response: `[0x85, high]`.
+pub const ID_SWCLK_IDLE_LEVEL: u8 = 0x85;
+
+/// Returns the signal integrity statistics.
+///
+/// Response: `[0x86, n, (session[4], lifetime[4]) * n]`, the counters are WAIT, FAULT, no ACK,
+/// parity errors and parity retries in that order.
+pub const ID_STATS: u8 = 0x86;
+
+/// Enables (`1`) or disables (`0`) zeroing the session statistics on `DAP_Connect`.
+///
+/// Request: `[0x87, enable]`, response: `[0x87, enabled]`.
+pub const ID_STATS_RESET_ON_CONNECT: u8 = 0x87;
+
+/// Selects the pin set used from the next `DAP_Connect` on, `0` direct and `1` buffered.
+///
+/// Request: `[0x88, pin_set]`, response: `[0x88, pin_set]`.
+pub const ID_PIN_SET: u8 = 0x88;
+
+/// Sets the number of extra cycles between the request and sampling the ACK, at most
+/// [`dap::MAX_ACK_GAP`].
+///
+/// Request: `[0x89, cycles]`, response: `[0x89, cycles]`.
+pub const ID_ACK_GAP: u8 = 0x89;
+
+/// Returns the host command statistics of this session, to tune the batching of the host.
+///
+/// Response: `[0x8a, n, (commands[4], transfers[4], average[2]) * n]` for `DAP_Transfer` and
+/// `DAP_TransferBlock` in that order, `average` is the number of transfers per command.
+pub const ID_COMMAND_STATS: u8 = 0x8a;
+
+/// Returns the time from the end of the request to sampling the first ACK bit, in the last
+/// transfer with a valid ACK. It is quantized to the SWCLK period, as the ACK is bit-banged.
+///
+/// Response: `[0x8b, latency_ns[4]]`.
+pub const ID_ACK_LATENCY: u8 = 0x8b;
+
+/// Returns the target power state and whether it changed since the last query, which clears the
+/// notification.
+///
+/// Response: `[0x8c, powered, changed, vtref_mv[2]]`.
+pub const ID_VTREF: u8 = 0x8c;
+
+/// Enables (`1`) or disables (`0`) connect-under-reset and sets its timing windows, in
+/// microseconds, each at most [`dap::ResetWindows::MAX_US`]. Without the windows only the
+/// enable is changed, without any arguments the current setting is returned.
+///
+/// Request: `[0x8d, enable, assert_us[4], release_delay_us[4], settle_us[4]]`, response:
+/// `[0x8d, enabled, assert_us[4], release_delay_us[4], settle_us[4]]`.
+pub const ID_CONNECT_UNDER_RESET: u8 = 0x8d;
+
+/// Starts streaming `count` words read from `address` through the current MEM-AP, `count` 0
+/// stops the stream. Only available on DAPv2, the frames are described in [`read_stream`].
+///
+/// Request: `[0x8e, count[4], address[4]]`, response: `[0x8e, streaming]` followed by the
+/// frames.
+pub const ID_READ_STREAM: u8 = read_stream::ID_READ_STREAM;
+
+/// Returns whether the target only answered the last `DAP_Connect` with SWDIO and SWCLK
+/// swapped, in which case the probe keeps the normal assignment and the wiring needs fixing.
+///
+/// Response: `[0x8f, swapped]`.
+pub const ID_WIRING_SWAPPED: u8 = 0x8f;
+
+/// Sets which failures a transfer is retried on, a combination of the `dap::RETRY_*` bits, and
+/// how many times at most, up to [`dap::MAX_RETRIES`]. Without arguments the current policy is
+/// returned.
+///
+/// Request: `[0x90, mask, count]`, response: `[0x90, mask, count]`.
+pub const ID_RETRY_POLICY: u8 = 0x90;
+
+/// Returns the currently buffered SWO bytes, independent of the configured SWO transport. With
+/// `consume` set (`1`) the returned bytes are removed from the buffer, otherwise they stay.
+/// While SWO is streamed out the trace endpoint `consume` is ignored, the stream keeps the bytes.
+///
+/// Request: `[0x91, consume]`, response: `[0x91, n, data[n]]` where `n` is 0 when the buffer is
+/// empty.
+pub const ID_SWO_SNAPSHOT: u8 = 0x91;
+
+/// Selects a preset SWCLK frequency from [`dap::SPEED_PRESETS`] by index, applied through
+/// `DAP_SWJ_Clock`. Without an index only the table is returned, with the frequency each
+/// preset quantizes to within the limits `DAP_SWJ_Clock` applies, and the active index, `0xff`
+/// if the host set the clock itself.
+///
+/// Request: `[0x92, index]`, response: `[0x92, active, n, frequency[4] * n]`.
+pub const ID_SPEED_TABLE: u8 = 0x92;
+
+/// Reboots the probe into the UF2 bootloader, to re-flash it without pressing BOOTSEL. Only
+/// accepted with [`BOOTLOADER_KEY`], so a stray vendor command does not take the probe off the
+/// bus. The reboot follows shortly after the response.
+///
+/// Request: `[0x93, key[4]]`, response: `[0x93, accepted]`.
+pub const ID_BOOTLOADER: u8 = 0x93;
+
+/// Key of the bootloader command, "UF2!" in ASCII.
+pub const BOOTLOADER_KEY: [u8; 4] = *b"UF2!";
+
+/// Pulses nRESET low for `ms` milliseconds, at most [`RESET_PULSE_MAX_MS`], then waits up to
+/// [`RESET_RELEASE_MAX_US`] for the target to release it. Without `ms` the pulse lasts
+/// [`RESET_PULSE_DEFAULT_MS`]. Goes through `DAP_SWJ_Pins`, so nRESET stays open drain.
+///
+/// Request: `[0x94, ms[2]]`, response: `[0x94, released]`.
+pub const ID_RESET_PULSE: u8 = 0x94;
+
+/// Length of a reset pulse without an explicit length.
+pub const RESET_PULSE_DEFAULT_MS: u16 = 100;
+
+/// Longest reset pulse, the USB task is blocked for the whole pulse.
+pub const RESET_PULSE_MAX_MS: u16 = 1000;
+
+/// Longest wait for nRESET to be released after a pulse, below [`dap::MAX_PIN_WAIT_US`] so the
+/// whole command blocks the USB task for at most 2 s.
+pub const RESET_RELEASE_MAX_US: u32 = 1_000_000;
+
+/// Sets the number of idle cycles after every completed transfer, at most
+/// [`dap::MAX_IDLE_CYCLES`], or [`dap::PROFILE_IDLE_CYCLES`] for the value of the connect
+/// profile (the default). Without an argument the current setting is returned.
+///
+/// Request: `[0x95, cycles]`, response: `[0x95, cycles]`.
+pub const ID_IDLE_CYCLES: u8 = 0x95;
+
+/// Switches the target power on (`1`) or off (`0`), without an argument the state is returned.
+///
+/// Request: `[0x96, on]`, response: `[0x96, on, powered]` where `on` is `0xff` without a power
+/// switch and `powered` whether Vtref is above the threshold, which lags the switch by up to a
+/// status period. Transfers are refused while the switch is on and Vtref is not, see
+/// [`target_power`].
+pub const ID_TARGET_POWER: u8 = 0x96;
+
+/// Returns the SWCLK frequency achieved for the last `DAP_SWJ_Clock`, which `DAP_SWJ_Clock`
+/// itself can't report. The bit-banged driver rounds the requested frequency down to a whole
+/// number of SysTick ticks per half period, including its loop overhead as measured at startup
+/// ([`dap::edge_overhead_ticks`]).
+///
+/// Request: `[0x97]`, response: `[0x97, frequency[4]]`, little endian in Hz.
+pub const ID_SWCLK_FREQUENCY: u8 = 0x97;
+
+/// Stores the GPIOs of SWDIO, SWCLK and nRESET in flash, they are used from the next reset on.
+/// Any pin of [`PinAssignment::ASSIGNABLE`] can be assigned, `0xff` for all three restores the
+/// compiled-in pins. Without an argument the stored assignment is returned.
+///
+/// Request: `[0x98, swdio, swclk, nreset]`, response: `[0x98, accepted, swdio, swclk, nreset]`
+/// with the pins used after the next reset.
+pub const ID_PIN_ASSIGNMENT: u8 = 0x98;
+
+/// Stores the SWCLK frequency used until the host sets one in flash, from the next reset on.
+/// `0` restores [`dap::DEFAULT_FREQUENCY`]. Without an argument the stored frequency is returned.
+/// Flash is only written by this command, never by `DAP_SWJ_Clock`.
+///
+/// Request: `[0x99, frequency[4]]`, response: `[0x99, frequency[4]]`, little endian in Hz.
+pub const ID_DEFAULT_FREQUENCY: u8 = 0x99;
+
+/// Loopback test of the probe's own SWDIO and SWCLK, for bring-up. Needs SWDIO and SWCLK of
+/// the direct pin set jumpered together and no target attached; refused while SWD is connected.
+///
+/// Request: `[0x9a]`, response: `[0x9a, passed]` where `passed` has bit 0 set if SWCLK drove
+/// SWDIO and bit 1 if SWDIO drove SWCLK (`0x03` for a good probe), `0xff` when refused.
+pub const ID_LOOPBACK: u8 = 0x9a;
+
+/// Returns the target current measured by the status task, with
+/// [`CURRENT_SENSE`](crate::setup::CURRENT_SENSE).
+///
+/// Request: `[0x9b]`, response: `[0x9b, available, current_ma[2]]`.
+pub const ID_TARGET_CURRENT: u8 = 0x9b;
+
+/// Returns the git version and the build time of the firmware, see [`build_info`].
+///
+/// Request: `[0x9c]`, response: `[0x9c, len, git_version, 0, build_time, 0]` where `len` counts
+/// both NUL terminated strings.
+pub const ID_BUILD_INFO: u8 = 0x9c;
+
+/// Enables (`1`) or disables (`0`) the CRC-32 of block reads, see [`block_crc`], and returns the
+/// CRC of the last `DAP_TransferBlock` read.
+///
+/// Request: `[0x9d]` or `[0x9d, enable]`, response: `[0x9d, enabled, words[2], crc[4]]` where
+/// `words` is the length of the block.
+pub const ID_BLOCK_CRC: u8 = 0x9d;
+
+/// The generic `DAP_Invalid` response for unknown commands.
+const ID_INVALID: u8 = 0xff;
+
+const ID_DAP_CONNECT: u8 = 0x02;
+const ID_DAP_TRANSFER_CONFIGURE: u8 = 0x04;
+const ID_DAP_TRANSFER: u8 = 0x05;
+const ID_DAP_WRITE_ABORT: u8 = 0x08;
+const ID_DAP_SWJ_PINS: u8 = 0x10;
+const ID_DAP_SWJ_CLOCK: u8 = 0x11;
+
+/// Pin number restoring the compiled-in pin assignment.
+const UNSET_PIN: u8 = 0xff;
+
+/// `DAP_Connect` response ports.
+const PORT_FAILED: u8 = 0;
+const PORT_SWD: u8 = 1;
+
+/// `DAP_SWJ_Pins` bit of nRESET.
+const PIN_NRESET: u8 = 1 << 7;
+
+/// `DAP_Transfer` requests for the MEM-AP TAR write and DRW read.
+const REQUEST_AP_WRITE_TAR: u8 = 0x05;
+const REQUEST_AP_READ_DRW: u8 = 0x0f;
+
+/// `DAP_Transfer` response status without an ACK, returned while the target is unpowered.
+const STATUS_NO_ACK: u8 = 0x07;
+
+/// ABORT value clearing all sticky errors.
+const ABORT_CLEAR_ERRORS: u32 = 0x1e;
+
+/// `DAP_Error` response status.
+const DAP_ERROR: u8 = 0xff;
+
+/// Shortest valid request of the standard command `id`, including the ID.
+const fn min_request_len(id: u8) -> usize {
+    match id {
+        // DAP_HostStatus, DAP_Transfer, DAP_Delay, DAP_SWO_Data
+        0x01 | 0x05 | 0x09 | 0x1c => 3,
+        // DAP_TransferBlock
+        0x06 => 5,
+        // DAP_SWJ_Clock, DAP_SWO_Baudrate
+        0x11 | 0x19 => 5,
+        // DAP_TransferConfigure, DAP_WriteABORT
+        0x04 | 0x08 => 6,
+        // DAP_SWJ_Pins
+        0x10 => 7,
+        // DAP_Info, DAP_Connect and the other commands with a single parameter byte
+        0x00 | 0x02 | 0x12..=0x18 | 0x1a | 0x1d | 0x1e | 0x7e | 0x7f => 2,
+        _ => 1,
+    }
+}
+
+/// Process a DAP command, handling the vendor commands locally. Empty packets are ignored and
+/// standard commands too short for their parameters are answered with `DAP_Error`, instead of
+/// being handed to the DAP handler.
+pub fn process_command(
+    dap: &mut DapHandler,
+    report: &[u8],
+    resp: &mut [u8],
+    version: DapVersion,
+) -> usize {
+    let id = match report.first() {
+        Some(&id) => id,
+        None => {
+            warn!("Empty DAP command ignored");
+            return 0;
+        }
+    };
+    if report.len() < min_request_len(id) {
+        warn!("DAP command 0x{:x} truncated to {} bytes", id, report.len());
+        resp[0] = id;
+        resp[1] = DAP_ERROR;
+        return 2;
+    }
+
+    #[cfg(feature = "diagnostics")]
+    count_command(report);
+    #[cfg(feature = "rtt")]
+    crate::rtt::host_activity();
+
+    // Every transfer request counts its WAITs afresh
+    if id == ID_DAP_TRANSFER || id == transfer_block::ID_DAP_TRANSFER_BLOCK {
+        dap::start_transfer_request();
+    }
+
+    match report.first() {
+        Some(&ID_DAP_TRANSFER) | Some(&transfer_block::ID_DAP_TRANSFER_BLOCK)
+            if adc::transfers_blocked() =>
+        {
+            reject_transfer(report[0], resp)
+        }
+        Some(&ID_LAST_ERROR) => last_error(resp),
+        Some(&ID_AUTO_SWITCH) => auto_switch(report, resp),
+        Some(&ID_FAULT_LEVEL) => fault_level(report, resp),
+        #[cfg(feature = "diagnostics")]
+        Some(&ID_SCOPE_LOOP) => scope_loop(report, resp),
+        Some(&ID_BLOCK_READ) => block_read(dap, report, resp),
+        Some(&ID_SWCLK_IDLE_LEVEL) => swclk_idle_level(report, resp),
+        #[cfg(feature = "diagnostics")]
+        Some(&ID_STATS) => statistics(resp),
+        #[cfg(feature = "diagnostics")]
+        Some(&ID_STATS_RESET_ON_CONNECT) => stats_reset_on_connect(report, resp),
+        Some(&ID_PIN_SET) => pin_set(report, resp),
+        Some(&ID_ACK_GAP) => ack_gap(report, resp),
+        #[cfg(feature = "diagnostics")]
+        Some(&ID_COMMAND_STATS) => command_statistics(resp),
+        #[cfg(feature = "diagnostics")]
+        Some(&ID_ACK_LATENCY) => ack_latency(resp),
+        Some(&ID_VTREF) => vtref(resp),
+        Some(&ID_CONNECT_UNDER_RESET) => connect_under_reset(report, resp),
+        Some(&ID_READ_STREAM) => read_stream(report, resp, version),
+        Some(&ID_WIRING_SWAPPED) => wiring_swapped(resp),
+        Some(&ID_RETRY_POLICY) => retry_policy(report, resp),
+        Some(&ID_SPEED_TABLE) => speed_table(dap, report, resp),
+        Some(&ID_BOOTLOADER) => enter_bootloader(report, resp),
+        Some(&ID_RESET_PULSE) => reset_pulse(dap, report, resp, timer_delay::delay_us),
+        Some(&ID_IDLE_CYCLES) => idle_cycles(report, resp),
+        Some(&ID_TARGET_POWER) => target_power(report, resp),
+        Some(&ID_SWCLK_FREQUENCY) => swclk_frequency(resp),
+        Some(&ID_PIN_ASSIGNMENT) => pin_assignment(report, resp),
+        Some(&ID_DEFAULT_FREQUENCY) => default_frequency(report, resp),
+        Some(&ID_LOOPBACK) => loopback(resp),
+        Some(&ID_TARGET_CURRENT) => target_current(resp),
+        Some(&ID_BUILD_INFO) => build_info(resp),
+        Some(&ID_BLOCK_CRC) => block_crc(report, resp),
+        #[cfg(feature = "swo")]
+        Some(&ID_SWO_SNAPSHOT) => swo_snapshot(report, resp),
+        Some(&id) if (0x80..=0x9f).contains(&id) => {
+            warn!("Unknown vendor command: 0x{:x}", id);
+            resp[0] = ID_INVALID;
+            1
+        }
+        Some(&transfer_block::ID_DAP_TRANSFER_BLOCK) => {
+            transfer_block::process(dap, report, resp, version)
+        }
+        Some(&info::ID_DAP_INFO) => info::process(dap, report, resp, version),
+        #[cfg(feature = "jtag")]
+        Some(&jtag_idcode::ID_DAP_JTAG_IDCODE) => jtag_idcode::process(dap, report, resp),
+        Some(&swd_sequence::ID_DAP_SWD_SEQUENCE) => swd_sequence::process(report, resp),
+        Some(&ID_DAP_TRANSFER_CONFIGURE) => {
+            // Request: [id, idle_cycles, wait_retry[2], match_retry[2]]
+            if let Some(&[r0, r1]) = report.get(2..4) {
+                dap::set_wait_retries(u16::from_le_bytes([r0, r1]));
+            }
+            dap.process_command(report, resp, version)
+        }
+        Some(&ID_DAP_CONNECT) => {
+            // Response: [id, port], port 0 is a failed connect
+            let len = dap.process_command(report, resp, version);
+            if setup::CONNECT_REQUIRE_DPIDR && resp[1] == PORT_SWD && !dap::link_up() {
+                warn!("DAP_Connect failed, no DPIDR");
+                resp[1] = PORT_FAILED;
+            }
+            len
+        }
+        Some(&ID_DAP_SWJ_CLOCK) => {
+            dap::set_speed_preset(dap::NO_SPEED_PRESET);
+            dap.process_command(report, resp, version)
+        }
+        _ => dap.process_command(report, resp, version),
+    }
+}
+
+/// Counts the transfer commands issued by the host, before they are chunked.
+#[cfg(feature = "diagnostics")]
+fn count_command(report: &[u8]) {
+    match *report {
+        [ID_DAP_TRANSFER, _, count, ..] => stats::DAP_TRANSFER.record(count as u32),
+        [transfer_block::ID_DAP_TRANSFER_BLOCK, _, low, high, ..] => {
+            stats::DAP_TRANSFER_BLOCK.record(u16::from_le_bytes([low, high]) as u32)
+        }
+        _ => {}
+    }
+}
+
+/// Answers a transfer command as if no target drove the ACK, without any transfer done.
+fn reject_transfer(id: u8, resp: &mut [u8]) -> usize {
+    trace!(
+        "Target unpowered, rejecting transfer, Vtref = {} mV",
+        adc::vtref_mv()
+    );
+
+    resp[0] = id;
+    if id == ID_DAP_TRANSFER {
+        // Response: [id, count, status]
+        resp[1] = 0;
+        resp[2] = STATUS_NO_ACK;
+        3
+    } else {
+        // Response: [id, count[2], status]
+        resp[1..3].copy_from_slice(&0u16.to_le_bytes());
+        resp[3] = STATUS_NO_ACK;
+        4
+    }
+}
+
+fn last_error(resp: &mut [u8]) -> usize {
+    let error = dap::last_transfer_error();
+    trace!("Last transfer error: {}", error);
+
+    resp[0] = ID_LAST_ERROR;
+    resp[1] = error as u8;
+    resp[2] = error.status();
+    3
+}
+
+fn auto_switch(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&enable) = report.get(1) {
+        dap::set_auto_switch(enable != 0);
+    }
+
+    resp[0] = ID_AUTO_SWITCH;
+    resp[1] = dap::auto_switch() as u8;
+    2
+}
+
+fn fault_level(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&high) = report.get(1) {
+        dap::set_fault_level(high != 0);
+    }
+
+    resp[0] = ID_FAULT_LEVEL;
+    resp[1] = (dap::fault_level() == PinState::High) as u8;
+    2
+}
+
+#[cfg(feature = "diagnostics")]
+fn scope_loop(report: &[u8], resp: &mut [u8]) -> usize {
+    match report.get(1..4) {
+        Some(&[1, request, timeout_s]) => {
+            scope_loop::start(request, timeout_s);
+        }
+        _ => scope_loop::stop(),
+    }
+
+    resp[0] = ID_SCOPE_LOOP;
+    resp[1] = scope_loop::is_active() as u8;
+    2
+}
+
+fn swclk_idle_level(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&high) = report.get(1) {
+        dap::set_swclk_idle_level(high != 0);
+    }
+
+    resp[0] = ID_SWCLK_IDLE_LEVEL;
+    resp[1] = (dap::swclk_idle_level() == PinState::High) as u8;
+    2
+}
+
+fn block_read(dap: &mut impl CommandProcessor, report: &[u8], resp: &mut [u8]) -> usize {
+    let (count, mut address) = match report.get(1..6) {
+        Some(&[count, a0, a1, a2, a3]) => (
+            core::cmp::min(count as usize, BLOCK_READ_MAX_WORDS),
+            u32::from_le_bytes([a0, a1, a2, a3]),
+        ),
+        _ => {
+            resp[0] = ID_INVALID;
+            return 1;
+        }
+    };
+
+    resp[0] = ID_BLOCK_READ;
+    resp[1] = count as u8;
+
+    let mut scratch = [0; 16];
+    for word in resp[2..2 + count * 5].chunks_exact_mut(5) {
+        // Program TAR for every word, so a faulting word does not shift the following ones
+        let a = address.to_le_bytes();
+        let command = [
+            ID_DAP_TRANSFER,
+            0,
+            2,
+            REQUEST_AP_WRITE_TAR,
+            a[0],
+            a[1],
+            a[2],
+            a[3],
+            REQUEST_AP_READ_DRW,
+        ];
+        dap::start_transfer_request();
+        dap.process_command(&command, &mut scratch, DapVersion::V2);
+
+        // Response: [id, transfers done, status, data[4]]
+        let status = scratch[2];
+        let data = if scratch[1] == 2 && status == 0x01 {
+            u32::from_le_bytes([scratch[3], scratch[4], scratch[5], scratch[6]])
+        } else {
+            trace!(
+                "Block read of 0x{:x} failed, status: 0x{:x}",
+                address,
+                status
+            );
+
+            let abort = ABORT_CLEAR_ERRORS.to_le_bytes();
+            let command = [
+                ID_DAP_WRITE_ABORT,
+                0,
+                abort[0],
+                abort[1],
+                abort[2],
+                abort[3],
+            ];
+            dap.process_command(&command, &mut scratch, DapVersion::V2);
+
+            BLOCK_READ_SENTINEL
+        };
+
+        word[0] = status;
+        word[1..].copy_from_slice(&data.to_le_bytes());
+        address = address.wrapping_add(4);
+    }
+
+    2 + count * 5
+}
+
+#[cfg(feature = "diagnostics")]
+fn statistics(resp: &mut [u8]) -> usize {
+    resp[0] = ID_STATS;
+    resp[1] = stats::COUNTERS.len() as u8;
+
+    for (counter, out) in stats::COUNTERS.iter().zip(resp[2..].chunks_exact_mut(8)) {
+        out[..4].copy_from_slice(&counter.session().to_le_bytes());
+        out[4..].copy_from_slice(&counter.lifetime().to_le_bytes());
+    }
+
+    2 + stats::COUNTERS.len() * 8
+}
+
+#[cfg(feature = "diagnostics")]
+fn stats_reset_on_connect(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&enable) = report.get(1) {
+        stats::set_reset_on_connect(enable != 0);
+    }
+
+    resp[0] = ID_STATS_RESET_ON_CONNECT;
+    resp[1] = stats::reset_on_connect() as u8;
+    2
+}
+
+#[cfg(feature = "diagnostics")]
+fn command_statistics(resp: &mut [u8]) -> usize {
+    resp[0] = ID_COMMAND_STATS;
+    resp[1] = stats::COMMANDS.len() as u8;
+
+    for (counter, out) in stats::COMMANDS.iter().zip(resp[2..].chunks_exact_mut(10)) {
+        let average = counter.session_average().min(u16::MAX as u32) as u16;
+        out[..4].copy_from_slice(&counter.commands.session().to_le_bytes());
+        out[4..8].copy_from_slice(&counter.transfers.session().to_le_bytes());
+        out[8..].copy_from_slice(&average.to_le_bytes());
+    }
+
+    2 + stats::COMMANDS.len() * 10
+}
+
+#[cfg(feature = "diagnostics")]
+fn ack_latency(resp: &mut [u8]) -> usize {
+    resp[0] = ID_ACK_LATENCY;
+    resp[1..5].copy_from_slice(&stats::ack_latency_ns().to_le_bytes());
+    5
+}
+
+fn vtref(resp: &mut [u8]) -> usize {
+    resp[0] = ID_VTREF;
+    resp[1] = adc::target_powered() as u8;
+    resp[2] = adc::take_changed() as u8;
+    resp[3..5].copy_from_slice(&(adc::vtref_mv() as u16).to_le_bytes());
+    5
+}
+
+fn pin_set(report: &[u8], resp: &mut [u8]) -> usize {
+    match report.get(1) {
+        Some(0) => dap::select_pin_set(dap::PinSetId::Direct),
+        Some(1) => dap::select_pin_set(dap::PinSetId::Buffered),
+        _ => {}
+    }
+
+    resp[0] = ID_PIN_SET;
+    resp[1] = dap::selected_pin_set() as u8;
+    2
+}
+
+fn ack_gap(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&cycles) = report.get(1) {
+        dap::set_ack_gap(cycles);
+    }
+
+    resp[0] = ID_ACK_GAP;
+    resp[1] = dap::ack_gap();
+    2
+}
+
+fn idle_cycles(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&cycles) = report.get(1) {
+        dap::set_idle_cycles(cycles);
+    }
+
+    resp[0] = ID_IDLE_CYCLES;
+    resp[1] = dap::idle_cycles();
+    2
+}
+
+fn connect_under_reset(report: &[u8], resp: &mut [u8]) -> usize {
+    let word =
+        |i: usize| u32::from_le_bytes([report[i], report[i + 1], report[i + 2], report[i + 3]]);
+
+    if report.len() >= 14 {
+        dap::set_reset_windows(dap::ResetWindows {
+            assert_us: word(2),
+            release_delay_us: word(6),
+            settle_us: word(10),
+        });
+    }
+    if let Some(&enable) = report.get(1) {
+        dap::set_connect_under_reset(enable != 0);
+    }
+
+    let windows = dap::reset_windows();
+    resp[0] = ID_CONNECT_UNDER_RESET;
+    resp[1] = dap::connect_under_reset().is_some() as u8;
+    resp[2..6].copy_from_slice(&windows.assert_us.to_le_bytes());
+    resp[6..10].copy_from_slice(&windows.release_delay_us.to_le_bytes());
+    resp[10..14].copy_from_slice(&windows.settle_us.to_le_bytes());
+    14
+}
+
+fn read_stream(report: &[u8], resp: &mut [u8], version: DapVersion) -> usize {
+    match report.get(1..9) {
+        Some(&[c0, c1, c2, c3, a0, a1, a2, a3]) => {
+            let count = u32::from_le_bytes([c0, c1, c2, c3]);
+            let address = u32::from_le_bytes([a0, a1, a2, a3]);
+
+            if count == 0 {
+                read_stream::stop();
+            } else if matches!(version, DapVersion::V2) {
+                read_stream::start(address, count);
+            } else {
+                warn!("Read stream needs the DAPv2 bulk interface");
+            }
+        }
+        _ => read_stream::stop(),
+    }
+
+    resp[0] = ID_READ_STREAM;
+    resp[1] = read_stream::is_active() as u8;
+    2
+}
+
+fn wiring_swapped(resp: &mut [u8]) -> usize {
+    resp[0] = ID_WIRING_SWAPPED;
+    resp[1] = dap::wiring_swapped() as u8;
+    2
+}
+
+fn retry_policy(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&[mask, count]) = report.get(1..3) {
+        dap::set_retry_policy(mask, count);
+    }
+
+    resp[0] = ID_RETRY_POLICY;
+    resp[1] = dap::retry_mask();
+    resp[2] = dap::retry_count();
+    3
+}
+
+#[cfg(feature = "swo")]
+fn swo_snapshot(report: &[u8], resp: &mut [u8]) -> usize {
+    let consume = report.get(1).map_or(false, |&consume| consume != 0);
+    let n = swo::snapshot(&mut resp[2..], consume);
+    trace!("SWO snapshot of {} bytes, consume: {}", n, consume);
+
+    resp[0] = ID_SWO_SNAPSHOT;
+    resp[1] = n as u8;
+    2 + n
+}
+
+fn speed_table(dap: &mut DapHandler, report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&index) = report.get(1) {
+        if let Some(frequency) = dap::preset_frequency(index as usize) {
+            let f = frequency.to_le_bytes();
+            let command = [ID_DAP_SWJ_CLOCK, f[0], f[1], f[2], f[3]];
+            let mut scratch = [0; 64];
+            dap.process_command(&command, &mut scratch, DapVersion::V2);
+
+            // Response: [id, status], 0 is OK
+            if scratch[1] == 0 {
+                dap::set_speed_preset(index);
+            }
+        }
+    }
+
+    resp[0] = ID_SPEED_TABLE;
+    resp[1] = dap::speed_preset();
+    resp[2] = dap::SPEED_PRESETS.len() as u8;
+    for (index, out) in resp[3..]
+        .chunks_exact_mut(4)
+        .take(dap::SPEED_PRESETS.len())
+        .enumerate()
+    {
+        let frequency = dap::preset_effective_frequency(index).unwrap_or(0);
+        out.copy_from_slice(&frequency.to_le_bytes());
+    }
+    3 + dap::SPEED_PRESETS.len() * 4
+}
+
+fn enter_bootloader(report: &[u8], resp: &mut [u8]) -> usize {
+    let accepted = report.get(1..5) == Some(&BOOTLOADER_KEY[..]);
+    if accepted {
+        bootloader::request();
+    } else {
+        warn!("Bootloader command without the key, ignored");
+    }
+
+    resp[0] = ID_BOOTLOADER;
+    resp[1] = accepted as u8;
+    2
+}
+
+fn reset_pulse(
+    dap: &mut impl CommandProcessor,
+    report: &[u8],
+    resp: &mut [u8],
+    delay_us: impl FnOnce(u32),
+) -> usize {
+    let ms = match report.get(1..3) {
+        Some(&[lo, hi]) => u16::from_le_bytes([lo, hi]).min(RESET_PULSE_MAX_MS),
+        _ => RESET_PULSE_DEFAULT_MS,
+    };
+    trace!("Pulsing nRESET for {} ms", ms);
+
+    // Response: [id, pins]
+    let mut scratch = [0; 64];
+    dap.process_command(
+        &[ID_DAP_SWJ_PINS, 0, PIN_NRESET, 0, 0, 0, 0],
+        &mut scratch,
+        DapVersion::V2,
+    );
+    delay_us(ms as u32 * 1000);
+
+    let wait = RESET_RELEASE_MAX_US.to_le_bytes();
+    let command = [
+        ID_DAP_SWJ_PINS,
+        PIN_NRESET,
+        PIN_NRESET,
+        wait[0],
+        wait[1],
+        wait[2],
+        wait[3],
+    ];
+    dap.process_command(&command, &mut scratch, DapVersion::V2);
+
+    resp[0] = ID_RESET_PULSE;
+    resp[1] = (scratch[1] & PIN_NRESET != 0) as u8;
+    2
+}
+
+fn target_power(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&on) = report.get(1) {
+        target_power::set(on != 0);
+    }
+
+    resp[0] = ID_TARGET_POWER;
+    resp[1] = if target_power::available() {
+        target_power::is_on() as u8
+    } else {
+        0xff
+    };
+    resp[2] = adc::target_powered() as u8;
+    3
+}
+
+fn swclk_frequency(resp: &mut [u8]) -> usize {
+    resp[0] = ID_SWCLK_FREQUENCY;
+    resp[1..5].copy_from_slice(&dap::achieved_frequency().to_le_bytes());
+    5
+}
+
+fn pin_assignment(report: &[u8], resp: &mut [u8]) -> usize {
+    let mut config = flash_config::read();
+
+    let accepted = match report.get(1..4) {
+        Some(&[UNSET_PIN, UNSET_PIN, UNSET_PIN]) => {
+            config.pins = None;
+            true
+        }
+        Some(&[swdio, swclk, nreset]) => {
+            let pins = PinAssignment {
+                swdio,
+                swclk,
+                nreset,
+            };
+            if pins.is_valid() {
+                config.pins = Some(pins);
+            } else {
+                warn!("Invalid pin assignment: {}", pins);
+            }
+            pins.is_valid()
+        }
+        _ => true,
+    };
+
+    if accepted && config != flash_config::read() {
+        flash_config::write(&config);
+    }
+
+    let pins = config.pins.unwrap_or(PinAssignment::DEFAULT);
+    resp[0] = ID_PIN_ASSIGNMENT;
+    resp[1] = accepted as u8;
+    resp[2..5].copy_from_slice(&[pins.swdio, pins.swclk, pins.nreset]);
+    5
+}
+
+fn default_frequency(report: &[u8], resp: &mut [u8]) -> usize {
+    let mut config = flash_config::read();
+
+    if let Some(&[f0, f1, f2, f3]) = report.get(1..5) {
+        config.frequency = match u32::from_le_bytes([f0, f1, f2, f3]) {
+            0 => None,
+            frequency => Some(frequency),
+        };
+        if config != flash_config::read() {
+            flash_config::write(&config);
+        }
+    }
+
+    let frequency = config.frequency.unwrap_or(dap::DEFAULT_FREQUENCY);
+    resp[0] = ID_DEFAULT_FREQUENCY;
+    resp[1..5].copy_from_slice(&frequency.to_le_bytes());
+    5
+}
+
+fn loopback(resp: &mut [u8]) -> usize {
+    resp[0] = ID_LOOPBACK;
+    resp[1] = self_check::loopback().unwrap_or(0xff);
+    2
+}
+
+fn target_current(resp: &mut [u8]) -> usize {
+    resp[0] = ID_TARGET_CURRENT;
+    resp[1] = (setup::VTREF_SENSE && setup::CURRENT_SENSE.is_some()) as u8;
+    resp[2..4].copy_from_slice(&(adc::current_ma() as u16).to_le_bytes());
+    4
+}
+
+fn build_info(resp: &mut [u8]) -> usize {
+    // Cut the git version, if it has to be, so the build time always fits
+    let end = resp.len() - (build_info::BUILD_TIME.len() + 1);
+    let mut len = info::string(&mut resp[2..end], build_info::GIT_VERSION);
+    len += info::string(&mut resp[2 + len..], build_info::BUILD_TIME);
+
+    resp[0] = ID_BUILD_INFO;
+    resp[1] = len as u8;
+    2 + len
+}
+
+fn block_crc(report: &[u8], resp: &mut [u8]) -> usize {
+    if let Some(&enable) = report.get(1) {
+        block_crc::set_enabled(enable != 0);
+    }
+
+    let (crc, words) = block_crc::last();
+    resp[0] = ID_BLOCK_CRC;
+    resp[1] = block_crc::enabled() as u8;
+    resp[2..4].copy_from_slice(&(words as u16).to_le_bytes());
+    resp[4..8].copy_from_slice(&crc.to_le_bytes());
+    8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_byte_requests() {
+        // DAP_Info, DAP_Transfer and DAP_SWJ_Pins are truncated without their parameters
+        for id in [0x00, 0x05, 0x10] {
+            assert!(min_request_len(id) > 1, "0x{:02x}", id);
+        }
+        // DAP_Disconnect, DAP_ResetTarget and the vendor commands take none
+        for id in [0x03, 0x0a, ID_LAST_ERROR, ID_BLOCK_CRC] {
+            assert_eq!(min_request_len(id), 1, "0x{:02x}", id);
+        }
+    }
+
+    #[test]
+    fn parameter_lengths() {
+        assert_eq!(min_request_len(0x05), 3);
+        assert_eq!(min_request_len(0x06), 5);
+        assert_eq!(min_request_len(0x11), 5);
+        assert_eq!(min_request_len(0x08), 6);
+        assert_eq!(min_request_len(0x10), 7);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn commands_are_counted_by_type() {
+        // Lifetime totals, other tests may start a new session meanwhile
+        let totals = || {
+            let counters = [&stats::DAP_TRANSFER, &stats::DAP_TRANSFER_BLOCK];
+            counters.map(|c| [c.commands.lifetime(), c.transfers.lifetime()])
+        };
+        let before = totals();
+
+        count_command(&[ID_DAP_TRANSFER, 0, 2, REQUEST_AP_WRITE_TAR]);
+        count_command(&[transfer_block::ID_DAP_TRANSFER_BLOCK, 0, 0x00, 0x01, 0x0f]);
+        count_command(&[ID_DAP_TRANSFER, 0, 3, REQUEST_AP_READ_DRW]);
+        count_command(&[ID_LAST_ERROR]);
+
+        let counted: Vec<_> = totals()
+            .iter()
+            .zip(before)
+            .map(|(after, before)| [after[0] - before[0], after[1] - before[1]])
+            .collect();
+        assert_eq!(counted, [[2, 5], [1, 256]]);
+    }
+
+    #[test]
+    fn swclk_idle_level_is_kept() {
+        let mut resp = [0; 2];
+        assert_eq!(swclk_idle_level(&[ID_SWCLK_IDLE_LEVEL], &mut resp), 2);
+        assert_eq!(resp, [ID_SWCLK_IDLE_LEVEL, 1], "high by default");
+
+        swclk_idle_level(&[ID_SWCLK_IDLE_LEVEL, 0], &mut resp);
+        assert_eq!(resp, [ID_SWCLK_IDLE_LEVEL, 0]);
+        swclk_idle_level(&[ID_SWCLK_IDLE_LEVEL], &mut resp);
+        assert_eq!(resp[1], 0);
+        assert_eq!(dap::swclk_idle_level(), PinState::Low);
+
+        swclk_idle_level(&[ID_SWCLK_IDLE_LEVEL, 1], &mut resp);
+        assert_eq!(dap::swclk_idle_level(), PinState::High);
+    }
+
+    /// A MEM-AP behind a DAP handler, reads of `faulting` addresses answer FAULT.
+    struct MemAp {
+        tar: u32,
+        faulting: u32,
+        aborts: usize,
+    }
+
+    impl CommandProcessor for MemAp {
+        fn process_command(&mut self, report: &[u8], resp: &mut [u8], _: DapVersion) -> usize {
+            resp[0] = report[0];
+            match report {
+                [ID_DAP_TRANSFER, 0, 2, REQUEST_AP_WRITE_TAR, a @ .., REQUEST_AP_READ_DRW] => {
+                    self.tar = u32::from_le_bytes(a.try_into().unwrap());
+                    if self.tar == self.faulting {
+                        // The TAR write went through, the read did not
+                        resp[1..3].copy_from_slice(&[1, 0x04]);
+                        return 3;
+                    }
+                    resp[1..3].copy_from_slice(&[2, 0x01]);
+                    resp[3..7].copy_from_slice(&(!self.tar).to_le_bytes());
+                    7
+                }
+                [ID_DAP_WRITE_ABORT, 0, abort @ ..] => {
+                    assert_eq!(abort, ABORT_CLEAR_ERRORS.to_le_bytes());
+                    self.aborts += 1;
+                    resp[1] = 0;
+                    2
+                }
+                _ => panic!("unexpected command {:02x?}", report),
+            }
+        }
+    }
+
+    #[test]
+    fn block_read_continues_past_a_faulting_word() {
+        let mut ap = MemAp {
+            tar: 0,
+            faulting: 0x2000_0008,
+            aborts: 0,
+        };
+        let mut resp = [0; 64];
+        let len = block_read(
+            &mut ap,
+            &[ID_BLOCK_READ, 4, 0x00, 0x00, 0x00, 0x20],
+            &mut resp,
+        );
+
+        assert_eq!(len, 2 + 4 * 5);
+        assert_eq!(resp[..2], [ID_BLOCK_READ, 4]);
+        let words: Vec<_> = resp[2..len]
+            .chunks_exact(5)
+            .map(|word| (word[0], u32::from_le_bytes(word[1..].try_into().unwrap())))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                (0x01, !0x2000_0000),
+                (0x01, !0x2000_0004),
+                (0x04, BLOCK_READ_SENTINEL),
+                (0x01, !0x2000_000c),
+            ]
+        );
+        // The sticky error is cleared once, and the word after it is read from its own address
+        assert_eq!(ap.aborts, 1);
+        assert_eq!(ap.tar, 0x2000_000c);
+    }
+
+    #[test]
+    fn block_read_is_capped() {
+        let mut ap = MemAp {
+            tar: 0,
+            faulting: 1,
+            aborts: 0,
+        };
+        let mut resp = [0; 64];
+        let len = block_read(&mut ap, &[ID_BLOCK_READ, 0xff, 0, 0, 0, 0], &mut resp);
+
+        assert_eq!(len, 2 + BLOCK_READ_MAX_WORDS * 5);
+        assert_eq!(resp[1] as usize, BLOCK_READ_MAX_WORDS);
+        assert_eq!(block_read(&mut ap, &[ID_BLOCK_READ, 1], &mut resp), 1);
+        assert_eq!(resp[0], ID_INVALID);
+    }
+
+    /// nRESET behind `DAP_SWJ_Pins`, open drain with a pull-up, optionally held low by the
+    /// target.
+    struct ResetPin {
+        held: bool,
+        commands: Vec<[u8; 7]>,
+    }
+
+    impl CommandProcessor for ResetPin {
+        fn process_command(&mut self, report: &[u8], resp: &mut [u8], _: DapVersion) -> usize {
+            let command: [u8; 7] = report.try_into().unwrap();
+            assert_eq!(command[0], ID_DAP_SWJ_PINS);
+            assert_eq!(command[2], PIN_NRESET, "only nRESET is touched");
+            self.commands.push(command);
+
+            let released = command[1] & PIN_NRESET != 0 && !self.held;
+            resp[0] = ID_DAP_SWJ_PINS;
+            resp[1] = if released { PIN_NRESET } else { 0 };
+            2
+        }
+    }
+
+    fn pulse(report: &[u8], held: bool) -> (Vec<[u8; 7]>, Vec<u32>, [u8; 2]) {
+        let mut pin = ResetPin {
+            held,
+            commands: Vec::new(),
+        };
+        let mut delays = Vec::new();
+        let mut resp = [0; 64];
+        let len = reset_pulse(&mut pin, report, &mut resp, |us| delays.push(us));
+        assert_eq!(len, 2);
+        (pin.commands, delays, [resp[0], resp[1]])
+    }
+
+    #[test]
+    fn reset_pulse_asserts_then_releases_nreset() {
+        let (commands, delays, resp) = pulse(&[ID_RESET_PULSE, 20, 0], false);
+
+        let wait = RESET_RELEASE_MAX_US.to_le_bytes();
+        assert_eq!(
+            commands,
+            [
+                [ID_DAP_SWJ_PINS, 0, PIN_NRESET, 0, 0, 0, 0],
+                [
+                    ID_DAP_SWJ_PINS,
+                    PIN_NRESET,
+                    PIN_NRESET,
+                    wait[0],
+                    wait[1],
+                    wait[2],
+                    wait[3]
+                ],
+            ]
+        );
+        assert_eq!(delays, [20_000]);
+        assert_eq!(resp, [ID_RESET_PULSE, 1]);
+    }
+
+    #[test]
+    fn reset_pulse_reports_a_held_nreset() {
+        let (commands, _, resp) = pulse(&[ID_RESET_PULSE, 20, 0], true);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(resp, [ID_RESET_PULSE, 0]);
+    }
+
+    #[test]
+    fn reset_pulse_length() {
+        let ms = |report: &[u8]| pulse(report, false).1;
+        assert_eq!(
+            ms(&[ID_RESET_PULSE]),
+            [RESET_PULSE_DEFAULT_MS as u32 * 1000]
+        );
+        assert_eq!(
+            ms(&[ID_RESET_PULSE, 0xff, 0xff]),
+            [RESET_PULSE_MAX_MS as u32 * 1000]
+        );
+        assert_eq!(ms(&[ID_RESET_PULSE, 0, 0]), [0]);
+    }
+}
@@ -0,0 +1,105 @@
+//! WebUSB descriptors, so browser based CMSIS-DAP clients can use the probe.
+//!
+//! The WebUSB platform capability in the BOS descriptor tells the browser the device supports
+//! WebUSB and which vendor request returns the landing page URL. A browser claims the DAPv2
+//! bulk interface like any other host tool, the class adds no interface of its own. Without a
+//! landing page the capability is still advertised, the browser then just shows no
+//! notification when the probe is plugged in.
+
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, RequestType},
+};
+
+/// `bDevCapabilityType` of a platform capability.
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// WebUSB platform capability UUID {3408b638-09a9-47a0-8bfd-a0768815b665}, in the byte order of
+/// the descriptor.
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+/// WebUSB version 1.0.
+const WEBUSB_VERSION: u16 = 0x0100;
+
+/// `bRequest` of the WebUSB vendor requests, distinct from the one of the MS OS descriptors.
+const VENDOR_CODE: u8 = 0x57;
+
+/// `wIndex` of the GET_URL request.
+const GET_URL: u16 = 0x0002;
+
+/// `bDescriptorType` of a URL descriptor.
+const DESCRIPTOR_URL: u8 = 0x03;
+
+/// URL scheme prefix `https://`.
+const SCHEME_HTTPS: u8 = 0x01;
+
+/// Index of the landing page URL, 0 means none.
+const LANDING_PAGE_INDEX: u8 = 1;
+
+/// Longest URL a descriptor can carry.
+const MAX_URL_LEN: usize = 255 - 3;
+
+pub struct WebUsbDescriptors {
+    landing_page: Option<&'static str>,
+}
+
+impl WebUsbDescriptors {
+    /// `landing_page` is an `https://` URL without the scheme, e.g. `example.com/probe`.
+    pub fn new(landing_page: Option<&'static str>) -> Self {
+        let landing_page = landing_page.filter(|url| {
+            let fits = url.len() <= MAX_URL_LEN;
+            if !fits {
+                defmt::warn!("WebUSB landing page URL too long, not advertised");
+            }
+            fits
+        });
+
+        WebUsbDescriptors { landing_page }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for WebUsbDescriptors {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        let landing_page = if self.landing_page.is_some() {
+            LANDING_PAGE_INDEX
+        } else {
+            0
+        };
+
+        // Capability: [bReserved, PlatformCapabilityUUID[16], bcdVersion[2], bVendorCode,
+        // iLandingPage]
+        let mut capability = [0; 21];
+        capability[1..17].copy_from_slice(&WEBUSB_UUID);
+        capability[17..19].copy_from_slice(&WEBUSB_VERSION.to_le_bytes());
+        capability[19] = VENDOR_CODE;
+        capability[20] = landing_page;
+        writer.capability(CAPABILITY_PLATFORM, &capability)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type != RequestType::Vendor
+            || req.recipient != Recipient::Device
+            || req.request != VENDOR_CODE
+            || req.index != GET_URL
+        {
+            return;
+        }
+
+        match self.landing_page {
+            Some(url) if req.value == LANDING_PAGE_INDEX as u16 => {
+                // Descriptor: [bLength, bDescriptorType, bScheme, URL]
+                let mut descriptor = [0; 3 + MAX_URL_LEN];
+                let len = 3 + url.len();
+                descriptor[..3].copy_from_slice(&[len as u8, DESCRIPTOR_URL, SCHEME_HTTPS]);
+                descriptor[3..len].copy_from_slice(url.as_bytes());
+                xfer.accept_with(&descriptor[..len]).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}